@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use {
-    boot_info::mem::MemoryDescriptor,
+    boot_info::mem::{MemoryDescriptor, MemoryType as BootInfoMemoryType},
     core::{
         convert::TryInto,
         mem::size_of,
@@ -79,6 +79,26 @@ fn uefi_descriptor_to_boot_info(d: &boot::MemoryDescriptor) -> MemoryDescriptor
         MemoryDescriptor::new(
             PhysAddr::new(d.phys_start),
             NumOfPages::new(d.page_count.try_into().unwrap()),
+            uefi_memory_type_to_boot_info(d.ty),
         )
     }
 }
+
+fn uefi_memory_type_to_boot_info(ty: MemoryType) -> BootInfoMemoryType {
+    match ty {
+        MemoryType::CONVENTIONAL => BootInfoMemoryType::Conventional,
+        MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => {
+            BootInfoMemoryType::BootServices
+        }
+        MemoryType::LOADER_CODE | MemoryType::LOADER_DATA => BootInfoMemoryType::Loader,
+        MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => {
+            BootInfoMemoryType::RuntimeServices
+        }
+        MemoryType::ACPI_RECLAIM => BootInfoMemoryType::AcpiReclaim,
+        MemoryType::ACPI_NON_VOLATILE => BootInfoMemoryType::AcpiNvs,
+        MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => BootInfoMemoryType::Mmio,
+        MemoryType::PERSISTENT_MEMORY => BootInfoMemoryType::Persistent,
+        MemoryType::UNUSABLE => BootInfoMemoryType::Unusable,
+        _ => BootInfoMemoryType::Reserved,
+    }
+}
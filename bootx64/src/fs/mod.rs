@@ -5,7 +5,7 @@ mod root_dir;
 use {
     core::{
         convert::{TryFrom, TryInto},
-        slice,
+        ptr, slice,
     },
     elf_rs::Elf,
     file::{FileInfo, FileType},
@@ -23,7 +23,10 @@ use {
         },
         ResultExt,
     },
-    x86_64::{structures::paging::Size4KiB, PhysAddr, VirtAddr},
+    x86_64::{
+        structures::paging::{PageSize, Size2MiB, Size4KiB},
+        PhysAddr, VirtAddr,
+    },
 };
 
 #[must_use]
@@ -74,6 +77,46 @@ pub fn fetch_entry_address_and_memory_size(addr: PhysAddr, bytes: Bytes) -> (Vir
     }
 }
 
+/// Reallocates the memory for the kernel so that it also covers the regions which do not exist in
+/// the file, such as `.bss`.
+///
+/// Otherwise the kernel uses the memory which is regarded as free in the memory map.
+#[must_use]
+pub fn extend_to_memory_size(
+    bs: &boot::BootServices,
+    addr: PhysAddr,
+    file_bytes: Bytes,
+    mem_bytes: Bytes,
+) -> PhysAddr {
+    if mem_bytes <= file_bytes {
+        return addr;
+    }
+
+    let new_addr = allocate(bs, mem_bytes);
+
+    // SAFETY: Both regions are allocated by `allocate`, and they do not overlap.
+    unsafe {
+        ptr::copy_nonoverlapping(
+            addr.as_u64() as *const u8,
+            new_addr.as_u64() as *mut u8,
+            file_bytes.as_usize(),
+        );
+        ptr::write_bytes(
+            (new_addr.as_u64() as *mut u8).add(file_bytes.as_usize()),
+            0,
+            (mem_bytes - file_bytes).as_usize(),
+        );
+    }
+
+    free_pages(
+        bs,
+        addr,
+        file_bytes.as_num_of_pages::<Size4KiB>().as_usize(),
+    );
+
+    new_addr
+}
+
 fn get_handler(root: &mut file::Directory, name: &'static str) -> file::RegularFile {
     let h = root
         .open(name, FileMode::Read, FileAttribute::empty())
@@ -91,18 +134,56 @@ fn get_handler(root: &mut file::Directory, name: &'static str) -> file::RegularF
     }
 }
 
-fn allocate(boot_services: &boot::BootServices, kernel_bytes: Bytes) -> PhysAddr {
+/// Allocates memory for `bytes` bytes.
+///
+/// Memory as large as a huge page or larger starts from a huge page boundary so that it can be
+/// mapped with huge pages.
+fn allocate(boot_services: &boot::BootServices, bytes: Bytes) -> PhysAddr {
+    let num_of_pages = bytes.as_num_of_pages::<Size4KiB>().as_usize();
+    let pages_per_huge_page = usize::try_from(Size2MiB::SIZE / Size4KiB::SIZE).unwrap();
+
+    if num_of_pages < pages_per_huge_page {
+        return allocate_pages(boot_services, num_of_pages);
+    }
+
+    let padded = num_of_pages + pages_per_huge_page - 1;
+
+    let addr = allocate_pages(boot_services, padded);
+    let aligned = addr.align_up(Size2MiB::SIZE);
+
+    let head = usize::try_from((aligned - addr) / Size4KiB::SIZE).unwrap();
+    let tail = padded - head - num_of_pages;
+
+    free_pages(boot_services, addr, head);
+    free_pages(
+        boot_services,
+        aligned + Size4KiB::SIZE * u64::try_from(num_of_pages).unwrap(),
+        tail,
+    );
+
+    aligned
+}
+
+fn allocate_pages(boot_services: &boot::BootServices, num_of_pages: usize) -> PhysAddr {
     PhysAddr::new(
         boot_services
             .allocate_pages(
                 AllocateType::AnyPages,
                 MemoryType::LOADER_DATA,
-                kernel_bytes.as_num_of_pages::<Size4KiB>().as_usize(),
+                num_of_pages,
             )
             .expect_success("Failed to allocate memory for the kernel"),
     )
 }
 
+fn free_pages(boot_services: &boot::BootServices, addr: PhysAddr, num_of_pages: usize) {
+    if num_of_pages > 0 {
+        boot_services
+            .free_pages(addr.as_u64(), num_of_pages)
+            .expect_success("Failed to free memory.");
+    }
+}
+
 fn put_on_memory(handler: &mut file::RegularFile, kernel_addr: PhysAddr, kernel_bytes: Bytes) {
     // Reading should use while statement with the number of bytes which were actually read.
     // However, without while statement previous uefi implementation worked so this uefi
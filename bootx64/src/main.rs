@@ -31,6 +31,12 @@ pub fn efi_main(image: Handle, mut system_table: SystemTable<Boot>) -> ! {
     let (phys_initrd_addr, bytes_initrd) = fs::deploy(system_table.boot_services(), "initrd.cpio");
     let (entry_addr, actual_mem_size) =
         fs::fetch_entry_address_and_memory_size(phys_kernel_addr, bytes_kernel);
+    let phys_kernel_addr = fs::extend_to_memory_size(
+        system_table.boot_services(),
+        phys_kernel_addr,
+        bytes_kernel,
+        actual_mem_size,
+    );
 
     let stack_addr = stack::allocate(system_table.boot_services());
     let rsdp = rsdp::get(&system_table);
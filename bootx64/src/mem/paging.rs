@@ -1,16 +1,16 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use {
-    boot_info::mem::MemoryDescriptor,
+    boot_info::mem::{MemoryDescriptor, MemoryType},
     common::mem::reserved,
-    core::convert::TryFrom,
+    core::{arch::x86_64::__cpuid, convert::TryFrom, fmt},
     predefined_mmap::RECUR_PML4_ADDR,
     x86_64::{
-        addr::PhysAddr,
+        addr::{PhysAddr, VirtAddr},
         registers::control::{Cr0, Cr0Flags, Cr3},
         structures::paging::{
             FrameAllocator, Mapper, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
-            RecursivePageTable, Size4KiB,
+            RecursivePageTable, Size1GiB, Size2MiB, Size4KiB,
         },
     },
 };
@@ -27,7 +27,13 @@ impl<'a> AllocatorWithEfiMemoryMap<'a> {
 
 unsafe impl FrameAllocator<Size4KiB> for AllocatorWithEfiMemoryMap<'_> {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        for descriptor in self.mem_map.iter_mut() {
+        // Other regions may be used by the firmware, or they may even not be RAM.
+        let conventional = self
+            .mem_map
+            .iter_mut()
+            .filter(|d| d.ty == MemoryType::Conventional);
+
+        for descriptor in conventional {
             if descriptor.num_pages.as_usize() > 0 {
                 let addr = descriptor.start;
                 descriptor.start += Size4KiB::SIZE;
@@ -56,9 +62,11 @@ pub fn init(boot_info: &mut boot_info::Info, reserved: &reserved::Map) {
 fn enable_recursive_mapping() {
     let p4: &mut PageTable = unsafe { &mut *(get_pml4_addr().as_u64() as *mut _) };
 
+    // The recursive entry must not be accessible from ring 3. Otherwise any user process can
+    // rewrite its own page tables.
     p4[510].set_addr(
         get_pml4_addr(),
-        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
     );
 }
 
@@ -70,24 +78,73 @@ fn remove_table_protection() {
     }
 }
 
+/// Maps `region` with the largest pages which the alignment of the addresses allows.
+#[allow(clippy::too_many_lines)]
 fn map_virt_to_phys(region: &reserved::Range, allocator: &mut AllocatorWithEfiMemoryMap<'_>) {
     let p4 = unsafe { &mut *(RECUR_PML4_ADDR.as_mut_ptr()) };
     let mut p4 = RecursivePageTable::new(p4).unwrap();
 
-    let num_of_pages = region.bytes().as_num_of_pages::<Size4KiB>().as_usize();
-    for i in 0..num_of_pages {
-        let v = Page::<Size4KiB>::containing_address(
-            region.virt() + usize::try_from(Size4KiB::SIZE).unwrap() * i,
-        );
-        let p = PhysFrame::containing_address(
-            region.phys() + usize::try_from(Size4KiB::SIZE).unwrap() * i,
-        );
-        let f =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
-        unsafe { p4.map_to(v, p, f, allocator) }.unwrap().flush();
+    let supports_1gib = supports_1gib_pages();
+
+    let mut f = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    // The kernel sets the PAT entry which `WRITE_THROUGH` selects to write-combining. Until then
+    // the pages are write-through.
+    if region.is_write_combining() {
+        f |= PageTableFlags::WRITE_THROUGH;
+    }
+
+    let bytes = region.bytes().as_num_of_pages::<Size4KiB>().as_bytes();
+    let bytes = u64::try_from(bytes.as_usize()).unwrap();
+
+    let mut offset = 0;
+
+    while offset < bytes {
+        let v = region.virt() + offset;
+        let p = region.phys() + offset;
+        let rest = bytes - offset;
+
+        offset += if supports_1gib && fits::<Size1GiB>(v, p, rest) {
+            map_one::<Size1GiB>(&mut p4, v, p, f, allocator)
+        } else if fits::<Size2MiB>(v, p, rest) {
+            map_one::<Size2MiB>(&mut p4, v, p, f, allocator)
+        } else {
+            map_one::<Size4KiB>(&mut p4, v, p, f, allocator)
+        };
     }
 }
 
+fn fits<S: PageSize>(v: VirtAddr, p: PhysAddr, rest: u64) -> bool {
+    v.is_aligned(S::SIZE) && p.is_aligned(S::SIZE) && rest >= S::SIZE
+}
+
+#[allow(clippy::too_many_arguments)]
+fn map_one<S: PageSize + fmt::Debug>(
+    p4: &mut RecursivePageTable<'_>,
+    v: VirtAddr,
+    p: PhysAddr,
+    f: PageTableFlags,
+    allocator: &mut AllocatorWithEfiMemoryMap<'_>,
+) -> u64
+where
+    for<'a> RecursivePageTable<'a>: Mapper<S>,
+{
+    let v = Page::<S>::from_start_address(v).unwrap();
+    let p = PhysFrame::<S>::from_start_address(p).unwrap();
+    unsafe { p4.map_to(v, p, f, allocator) }.unwrap().flush();
+
+    S::SIZE
+}
+
+fn supports_1gib_pages() -> bool {
+    const PDPE1GB: u32 = 1 << 26;
+
+    // SAFETY: All x86_64 processors support the CPUID leaf 0x8000_0001.
+    let r = unsafe { __cpuid(0x8000_0001) };
+
+    r.edx & PDPE1GB != 0
+}
+
 fn get_pml4_addr() -> PhysAddr {
     let (frame, _) = Cr3::read();
     frame.start_address()
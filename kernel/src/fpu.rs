@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The x87 FPU and the SIMD registers of user processes.
+//!
+//! The kernel itself never touches these registers. `CR0.TS` is set while the registers hold the
+//! state of a process other than the running one, so the first FPU or SIMD instruction of a
+//! process raises `#NM`, and the handler switches the state.
+
+use {
+    alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error},
+    conquer_once::spin::OnceCell,
+    core::{
+        alloc::Layout,
+        arch::{
+            asm,
+            x86_64::{__cpuid, __cpuid_count},
+        },
+        fmt,
+        ptr::{self, NonNull},
+    },
+    log::info,
+    x86_64::registers::{
+        control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+        xcontrol::{XCr0, XCr0Flags},
+    },
+};
+
+/// The size of the legacy region of the XSAVE area, which is also the size of the FXSAVE area.
+const LEGACY_AREA_SIZE: usize = 512;
+
+/// The x87 FPU control word after `FNINIT`.
+const INITIAL_FCW: u16 = 0x37f;
+
+/// The MXCSR value after reset. All SIMD floating-point exceptions are masked.
+const INITIAL_MXCSR: u32 = 0x1f80;
+
+static SAVE_AREA: OnceCell<SaveArea> = OnceCell::uninit();
+
+#[derive(Copy, Clone, Debug)]
+struct SaveArea {
+    size: usize,
+    xsave: bool,
+}
+impl SaveArea {
+    fn layout(self) -> Layout {
+        Layout::from_size_align(self.size, 64).expect("Invalid layout of the FPU save area.")
+    }
+}
+
+/// Enables the FPU, SSE and, if available, `XSAVE` and AVX, and sets `CR0.TS` so that the first
+/// use of them raises `#NM`.
+pub(crate) fn init() {
+    let area = enable_features();
+
+    SAVE_AREA.init_once(|| area);
+
+    // SAFETY: The kernel is compiled without the FPU and SIMD instructions, so no code of the
+    // kernel raises `#NM`.
+    unsafe {
+        Cr0::update(|f| {
+            f.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            f.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+        });
+    }
+
+    info!("FPU save area: {:?}", area);
+}
+
+/// Clears `CR0.TS` so that the FPU and SIMD instructions do not raise `#NM`.
+pub(crate) fn enable() {
+    // SAFETY: `clts` does not break memory safety.
+    unsafe { asm!("clts", options(nomem, nostack, preserves_flags)) }
+}
+
+/// Sets `CR0.TS` so that the next FPU or SIMD instruction raises `#NM`.
+pub(crate) fn disable() {
+    // SAFETY: The kernel does not use the FPU, and `#NM` is handled.
+    unsafe { Cr0::update(|f| f.insert(Cr0Flags::TASK_SWITCHED)) }
+}
+
+/// Sets `CR4` and returns the save area for the enabled features.
+fn enable_features() -> SaveArea {
+    // SAFETY: CPUID leaf 1 is available on all `x86_64` processors.
+    let features = unsafe { __cpuid(1) };
+
+    let xsave = features.ecx & (1 << 26) != 0;
+    let avx = features.ecx & (1 << 28) != 0;
+
+    let mut cr4 = Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE;
+
+    if xsave {
+        cr4 |= Cr4Flags::OSXSAVE;
+    }
+
+    // SAFETY: The kernel does not use the SIMD registers, and the save area is allocated
+    // according to the enabled features.
+    unsafe { Cr4::update(|f| f.insert(cr4)) };
+
+    let size = if xsave {
+        enable_xsave_features(avx)
+    } else {
+        LEGACY_AREA_SIZE
+    };
+
+    SaveArea { size, xsave }
+}
+
+/// Sets XCR0 and returns the size of the XSAVE area for the enabled features.
+fn enable_xsave_features(avx: bool) -> usize {
+    let mut xcr0 = XCr0Flags::X87 | XCr0Flags::SSE;
+
+    if avx {
+        xcr0 |= XCr0Flags::AVX;
+    }
+
+    // SAFETY: `CR4.OSXSAVE` is set, and the processor supports the features.
+    unsafe { XCr0::write(xcr0) };
+
+    // SAFETY: The processor supports `XSAVE`, so CPUID leaf 0xd is available.
+    // EBX is the size of the area for the features enabled in XCR0.
+    let size = unsafe { __cpuid_count(0xd, 0) }.ebx;
+
+    usize::try_from(size).unwrap()
+}
+
+/// The FPU and SIMD state of a process.
+pub(crate) struct State(NonNull<u8>);
+impl State {
+    /// Creates the initial state, which is the same as the state after `FNINIT` with the reset
+    /// value of MXCSR.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if [`init`] is not called.
+    pub(crate) fn new() -> Self {
+        let state = Self::allocate(save_area());
+
+        // SAFETY: The area is at least `LEGACY_AREA_SIZE` bytes and 64-byte aligned. The zeroed
+        // XSAVE header makes `xrstor` initialize the components other than x87 and SSE.
+        unsafe {
+            ptr::copy_nonoverlapping(INITIAL_FCW.to_le_bytes().as_ptr(), state.0.as_ptr(), 2);
+            ptr::copy_nonoverlapping(
+                INITIAL_MXCSR.to_le_bytes().as_ptr(),
+                state.0.as_ptr().add(24),
+                4,
+            );
+        }
+
+        state
+    }
+
+    /// Saves the current FPU and SIMD registers to this area.
+    ///
+    /// # Safety
+    ///
+    /// `CR0.TS` must be cleared.
+    pub(crate) unsafe fn save(&mut self) {
+        let p = self.0.as_ptr();
+
+        // SAFETY: The area is large enough and aligned for the enabled features, and the caller
+        // ensures that the instruction does not raise `#NM`.
+        unsafe {
+            if save_area().xsave {
+                asm!("xsave64 [{}]", in(reg) p, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags));
+            } else {
+                asm!("fxsave64 [{}]", in(reg) p, options(nostack, preserves_flags));
+            }
+        }
+    }
+
+    /// Loads the FPU and SIMD registers from this area.
+    ///
+    /// # Safety
+    ///
+    /// `CR0.TS` must be cleared.
+    pub(crate) unsafe fn restore(&self) {
+        let p = self.0.as_ptr();
+
+        // SAFETY: The area holds a valid state, which is either the initial one or saved by
+        // `save`, and the caller ensures that the instruction does not raise `#NM`.
+        unsafe {
+            if save_area().xsave {
+                asm!("xrstor64 [{}]", in(reg) p, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags, readonly));
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) p, options(nostack, preserves_flags, readonly));
+            }
+        }
+    }
+
+    fn allocate(area: SaveArea) -> Self {
+        let layout = area.layout();
+
+        // SAFETY: The size of the layout is not zero.
+        let p = unsafe { alloc_zeroed(layout) };
+
+        Self(NonNull::new(p).unwrap_or_else(|| handle_alloc_error(layout)))
+    }
+}
+impl Clone for State {
+    fn clone(&self) -> Self {
+        let area = save_area();
+
+        let state = Self::allocate(area);
+
+        // SAFETY: Both areas are `area.size` bytes long and do not overlap.
+        unsafe {
+            ptr::copy_nonoverlapping(self.0.as_ptr(), state.0.as_ptr(), area.size);
+        }
+
+        state
+    }
+}
+impl Drop for State {
+    fn drop(&mut self) {
+        // SAFETY: The area is allocated by `State::allocate` with the same layout.
+        unsafe { dealloc(self.0.as_ptr(), save_area().layout()) }
+    }
+}
+impl fmt::Debug for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("State").field(&self.0).finish()
+    }
+}
+// SAFETY: `State` owns the area exclusively.
+unsafe impl Send for State {}
+
+fn save_area() -> SaveArea {
+    *SAVE_AREA.try_get().expect("`fpu::init` is not called.")
+}
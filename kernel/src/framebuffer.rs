@@ -0,0 +1,37 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    crate::{mem, process},
+    boot_info::vram,
+    conquer_once::spin::OnceCell,
+    syscalls::CacheType,
+    x86_64::{
+        structures::paging::{PageSize, Size4KiB},
+        VirtAddr,
+    },
+};
+
+static INFO: OnceCell<vram::Info> = OnceCell::uninit();
+
+pub(super) fn init(info: vram::Info) {
+    INFO.try_init_once(|| info)
+        .expect("`framebuffer::init` is called more than once.");
+}
+
+/// Maps the framebuffer to the address space of the current process.
+///
+/// The kernel maps the framebuffer only to the higher half, which user processes cannot access.
+/// A process which draws to the screen must request the mapping explicitly.
+///
+/// This function returns [`None`] if there is no space to map the framebuffer.
+pub(crate) fn map_for_user() -> Option<VirtAddr> {
+    let info = INFO.try_get();
+    let info = info.expect("The framebuffer information is not initialized.");
+
+    let start = info.phys_ptr();
+    let frames = mem::frames_spanning(start, info.bytes());
+
+    let virt = process::with_current_address_space(|a| a.map(frames, CacheType::WriteCombining))?;
+
+    Some(virt + start.as_u64() % Size4KiB::SIZE)
+}
@@ -24,7 +24,11 @@ pub(super) fn list_names() {
 }
 
 pub(super) fn get_handler(name: &str) -> CpioArchievedFile {
-    iter().find(|x| x.name() == name).expect("No such file.")
+    find(name).expect("No such file.")
+}
+
+pub(super) fn find(name: &str) -> Option<CpioArchievedFile> {
+    iter().find(|x| x.name() == name)
 }
 
 fn iter() -> impl Iterator<Item = CpioArchievedFile> {
@@ -35,7 +39,8 @@ pub(super) struct CpioArchievedFile {
     ptr: VirtAddr,
 }
 impl CpioArchievedFile {
-    pub(super) fn content(&self) -> &[u8] {
+    /// Returns the content of the file. The initrd is never freed.
+    pub(super) fn content(&self) -> &'static [u8] {
         let p = self.content_start().as_ptr();
         let sz: usize = self.header().file_size().try_into().unwrap();
         unsafe { slice::from_raw_parts(p, sz) }
@@ -1,11 +1,12 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use {
-    crate::tss,
+    crate::{smp::MAX_CPUS, tss},
+    array_init::array_init,
     conquer_once::spin::OnceCell,
     x86_64::{
         instructions::{
-            segmentation::{Segment, CS, DS, ES, FS, GS, SS},
+            segmentation::{Segment, CS, DS, ES, FS, SS},
             tables,
         },
         registers::model_specific::Star,
@@ -13,7 +14,8 @@ use {
     },
 };
 
-static GDT: OnceCell<GlobalDescriptorTable> = OnceCell::uninit();
+/// The GDT of each processor. They differ only in the TSS descriptor.
+static GDT: OnceCell<[GlobalDescriptorTable; MAX_CPUS]> = OnceCell::uninit();
 
 static SELECTORS: OnceCell<Selectors> = OnceCell::uninit();
 
@@ -26,6 +28,8 @@ struct Selectors {
     tss: SegmentSelector,
 }
 
+/// Initializes the GDTs and loads the one of the bootstrap processor.
+///
 /// # Safety
 ///
 /// The caller must ensure that there is no data races for `TSS`.
@@ -33,14 +37,16 @@ pub(crate) unsafe fn init() {
     // SAFETY: The caller ensures that there is no data races for `TSS`.
     unsafe { init_statics() };
 
-    load_gdt();
-
-    // SAFETY: `init_statics` initializes `SELECTORS` with the correct segment selectors.
-    unsafe {
-        set_segment_registers();
-    }
+    load(0);
+}
 
-    init_star();
+/// Loads the GDT of the application processor `cpu`.
+///
+/// # Panics
+///
+/// This function panics if [`init`] is not called.
+pub(crate) fn init_ap(cpu: usize) {
+    load(cpu);
 }
 
 pub(crate) fn kernel_code_selector() -> SegmentSelector {
@@ -63,15 +69,30 @@ pub(crate) fn user_data_selector() -> SegmentSelector {
 ///
 /// The caller must ensure that there is no data races for `TSS`.
 unsafe fn init_statics() {
-    // SAFETY: The caller ensures that there is no data races for `TSS`.
-    let (gdt, selectors) = unsafe { generate_gdt_and_selectors() };
+    let mut selectors = None;
+
+    let gdt = array_init(|cpu| {
+        // SAFETY: The caller ensures that there is no data races for `TSS`.
+        let (gdt, s) = unsafe { generate_gdt_and_selectors(cpu) };
+
+        selectors = Some(s);
+
+        gdt
+    });
 
     GDT.init_once(|| gdt);
-    SELECTORS.init_once(|| selectors);
+    SELECTORS.init_once(|| selectors.expect("No GDT is generated."));
 }
 
-fn load_gdt() {
-    gdt().load();
+fn load(cpu: usize) {
+    gdt(cpu).load();
+
+    // SAFETY: `init_statics` initializes `SELECTORS` with the correct segment selectors.
+    unsafe {
+        set_segment_registers();
+    }
+
+    init_star();
 }
 
 /// # Safety
@@ -87,7 +108,7 @@ unsafe fn set_segment_registers() {
         DS::set_reg(selectors.kernel_data);
         ES::set_reg(selectors.kernel_data);
         FS::set_reg(selectors.kernel_data);
-        GS::set_reg(selectors.kernel_data);
+        // GS is not loaded as it clears the base address of the per-CPU area.
         SS::set_reg(selectors.kernel_data);
         tables::load_tss(selectors.tss);
     }
@@ -96,7 +117,7 @@ unsafe fn set_segment_registers() {
 /// # Safety
 ///
 /// The caller must ensure that there is no data races for `TSS`.
-unsafe fn generate_gdt_and_selectors() -> (GlobalDescriptorTable, Selectors) {
+unsafe fn generate_gdt_and_selectors(cpu: usize) -> (GlobalDescriptorTable, Selectors) {
     let mut gdt = GlobalDescriptorTable::new();
     let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
     let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
@@ -105,7 +126,7 @@ unsafe fn generate_gdt_and_selectors() -> (GlobalDescriptorTable, Selectors) {
 
     // SAFETY: This operation is safe because there is no instances of `MutexGuard` which wraps
     // `TSS`.
-    let tss = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss::get_ptr() }));
+    let tss = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss::get_ptr(cpu) }));
 
     let selectors = Selectors {
         kernel_data,
@@ -130,8 +151,8 @@ fn init_star() {
     .unwrap();
 }
 
-fn gdt<'a>() -> &'a GlobalDescriptorTable {
-    GDT.get().expect("GDT is not initialized.")
+fn gdt<'a>(cpu: usize) -> &'a GlobalDescriptorTable {
+    &GDT.get().expect("GDT is not initialized.")[cpu]
 }
 
 fn selectors<'a>() -> &'a Selectors {
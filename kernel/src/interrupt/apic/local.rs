@@ -1,12 +1,202 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use x86_64::PhysAddr;
+use {
+    crate::mem,
+    conquer_once::spin::OnceCell,
+    core::{arch::x86_64::__cpuid, convert::TryFrom, ptr},
+    log::info,
+    os_units::Bytes,
+    syscalls::CacheType,
+    x86_64::{registers::model_specific::Msr, PhysAddr, VirtAddr},
+};
 
-const REGISTER_BASE: PhysAddr = PhysAddr::new_truncate(0xfee0_0000);
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+
+const IA32_APIC_BASE: Msr = Msr::new(0x1b);
+
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+const X2APIC_ENABLE: u64 = 1 << 10;
+const BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+const REGISTERS_SIZE: Bytes = Bytes::new(0x400);
+
+const DELIVERY_STATUS_PENDING: u32 = 1 << 12;
+
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+pub(crate) const SPURIOUS_VECTOR: u8 = 0xff;
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Register {
+    Id = 0x20,
+    EndOfInterrupt = 0xb0,
+    SpuriousInterruptVector = 0xf0,
+    InterruptCommandLow = 0x300,
+    InterruptCommandHigh = 0x310,
+    LvtTimer = 0x320,
+    InitialCount = 0x380,
+    CurrentCount = 0x390,
+    DivideConfiguration = 0x3e0,
+}
+impl Register {
+    fn offset(self) -> usize {
+        self as usize
+    }
+
+    fn msr(self) -> Msr {
+        Msr::new(X2APIC_MSR_BASE + (self as u32 >> 4))
+    }
+}
+
+/// How the kernel accesses the registers of the local APIC.
+#[derive(Copy, Clone, Debug)]
+enum LocalApic {
+    /// The registers are mapped to this virtual address.
+    XApic(VirtAddr),
+    /// The registers are accessed through MSRs.
+    X2Apic,
+}
+
+/// Enables the x2APIC mode if the processor supports it. Otherwise maps the registers of the
+/// local APIC to the kernel region.
+///
+/// The mapping lives until the kernel halts.
+///
+/// # Panics
+///
+/// This function panics if it is called more than once.
+pub(crate) fn init() {
+    let apic = if supports_x2apic() {
+        enable_x2apic();
+        LocalApic::X2Apic
+    } else {
+        LocalApic::XApic(map_registers())
+    };
+
+    info!("Local APIC mode: {:?}", apic);
+
+    LOCAL_APIC
+        .try_init_once(|| apic)
+        .expect("`local::init` is called more than once.");
+
+    enable();
+}
+
+/// Enables the local APIC of an application processor in the same mode as the bootstrap
+/// processor.
+///
+/// # Panics
+///
+/// This function panics if [`init`] is not called.
+pub(crate) fn init_ap() {
+    if let LocalApic::X2Apic = local_apic() {
+        enable_x2apic();
+    }
+
+    enable();
+}
+
+/// Returns the ID of the local APIC of the running processor.
+pub(crate) fn id() -> u32 {
+    let id = read(Register::Id);
+
+    match local_apic() {
+        LocalApic::XApic(_) => id >> 24,
+        LocalApic::X2Apic => id,
+    }
+}
 
 pub(crate) fn end_of_interrupt() {
-    // SAFETY: This operation is safe because `REGISTER_BASE` is the valid address to the Local APIC
-    // registers.
-    let mut r = unsafe { crate::mem::accessor::new::<u32>(REGISTER_BASE + 0xb0_usize) };
-    r.write_volatile(0);
+    write(Register::EndOfInterrupt, 0);
+}
+
+/// Sends the inter-processor interrupt `command` to the local APIC whose ID is `destination`.
+///
+/// `command` is the lower 32 bits of the Interrupt Command Register.
+pub(crate) fn send_ipi(destination: u32, command: u32) {
+    match local_apic() {
+        LocalApic::XApic(_) => {
+            write(Register::InterruptCommandHigh, destination << 24);
+            write(Register::InterruptCommandLow, command);
+
+            while read(Register::InterruptCommandLow) & DELIVERY_STATUS_PENDING != 0 {}
+        }
+        LocalApic::X2Apic => {
+            let mut msr = Register::InterruptCommandLow.msr();
+
+            // SAFETY: In the x2APIC mode, writing to this MSR sends an IPI and does not violate
+            // memory safety.
+            unsafe {
+                msr.write(u64::from(destination) << 32 | u64::from(command));
+            }
+        }
+    }
+}
+
+pub(crate) fn read(register: Register) -> u32 {
+    match local_apic() {
+        // SAFETY: The registers are mapped to `base`.
+        LocalApic::XApic(base) => unsafe {
+            ptr::read_volatile((base + register.offset()).as_ptr())
+        },
+        LocalApic::X2Apic => {
+            // SAFETY: The x2APIC mode is enabled, and reading from the register does not violate
+            // memory safety.
+            let v = unsafe { register.msr().read() };
+
+            // The upper half of the registers except the Interrupt Command Register is reserved.
+            u32::try_from(v & 0xffff_ffff).unwrap()
+        }
+    }
+}
+
+pub(crate) fn write(register: Register, value: u32) {
+    match local_apic() {
+        // SAFETY: The registers are mapped to `base`.
+        LocalApic::XApic(base) => unsafe {
+            ptr::write_volatile((base + register.offset()).as_mut_ptr(), value);
+        },
+        // SAFETY: The x2APIC mode is enabled. The registers do not affect memory safety.
+        LocalApic::X2Apic => unsafe { register.msr().write(value.into()) },
+    }
+}
+
+fn local_apic() -> LocalApic {
+    let apic = LOCAL_APIC.try_get();
+    *apic.expect("The local APIC is not initialized.")
+}
+
+fn supports_x2apic() -> bool {
+    const X2APIC: u32 = 1 << 21;
+
+    // SAFETY: All x86_64 processors support the CPUID leaf 1.
+    let r = unsafe { __cpuid(1) };
+
+    r.ecx & X2APIC != 0
+}
+
+fn enable_x2apic() {
+    let mut msr = IA32_APIC_BASE;
+
+    // SAFETY: The processor supports the x2APIC mode, and the xAPIC registers are not mapped.
+    unsafe {
+        let v = msr.read();
+        msr.write(v | APIC_GLOBAL_ENABLE | X2APIC_ENABLE);
+    }
+}
+
+/// Sets the software enable bit, which is cleared after an INIT IPI.
+fn enable() {
+    write(
+        Register::SpuriousInterruptVector,
+        APIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+}
+
+fn map_registers() -> VirtAddr {
+    // SAFETY: Reading from IA32_APIC_BASE does not violate memory safety.
+    let base = unsafe { IA32_APIC_BASE.read() } & BASE_ADDRESS_MASK;
+
+    mem::map_pages_for_kernel(PhysAddr::new(base), REGISTERS_SIZE, CacheType::Uncacheable)
 }
@@ -1,10 +1,79 @@
 use {
-    crate::{interrupt::apic::local, process},
-    x86_64::structures::idt::InterruptStackFrame,
+    crate::{interrupt::apic::local, mem::tlb, percpu::SwapGsGuard, process, time},
+    x86_64::{
+        registers::control::Cr2,
+        structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    },
 };
 
-pub(super) extern "x86-interrupt" fn h_20(_: InterruptStackFrame) {
+pub(super) extern "x86-interrupt" fn h_20(stack_frame: InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(&stack_frame);
+
     local::end_of_interrupt();
 
+    time::tick();
+
+    tlb::flush_if_stale();
+
+    process::wake_sleepers();
+
     process::switch();
 }
+
+pub(super) extern "x86-interrupt" fn reschedule(stack_frame: InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(&stack_frame);
+
+    local::end_of_interrupt();
+
+    process::switch();
+}
+
+/// The local APIC does not expect the end of interrupt for spurious interrupts.
+///
+/// This handler does not touch the per-CPU area, so it does not need `swapgs`.
+pub(super) extern "x86-interrupt" fn spurious(_: InterruptStackFrame) {}
+
+pub(super) extern "x86-interrupt" fn page_fault(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let _gs = SwapGsGuard::new(&stack_frame);
+
+    let addr = Cr2::read();
+
+    if let Err(fault) = process::handle_page_fault(addr, error_code) {
+        if error_code.contains(PageFaultErrorCode::USER_MODE) {
+            process::exit_on_segmentation_fault(fault);
+        } else {
+            panic!(
+                "Page fault in the kernel. {}\nError code: {:?}\n{:#?}",
+                fault, error_code, stack_frame
+            );
+        }
+    }
+}
+
+pub(super) extern "x86-interrupt" fn device_not_available(stack_frame: InterruptStackFrame) {
+    let _gs = SwapGsGuard::new(&stack_frame);
+
+    process::handle_device_not_available();
+}
+
+pub(super) extern "x86-interrupt" fn double_fault(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    // The guard is never dropped as this handler does not return.
+    let _gs = SwapGsGuard::new(&stack_frame);
+
+    let addr = Cr2::read();
+
+    if let Some(name) = process::process_overflowing_kernel_stack(addr) {
+        panic!(
+            "Stack overflow in the kernel stack of {}. Accessed {:?}.\n{:#?}",
+            name, addr, stack_frame
+        );
+    } else {
+        panic!("Double fault. CR2: {:?}\n{:#?}", addr, stack_frame);
+    }
+}
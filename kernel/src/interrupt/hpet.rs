@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The High Precision Event Timer.
+//!
+//! The kernel uses only the main counter of the HPET as a clocksource. The comparators are not
+//! used.
+
+use {
+    crate::mem::{self, allocator},
+    acpi::{AcpiTables, HpetInfo},
+    conquer_once::spin::OnceCell,
+    core::{
+        convert::TryFrom,
+        ptr,
+        sync::atomic::{AtomicU64, Ordering},
+    },
+    log::info,
+    os_units::Bytes,
+    syscalls::CacheType,
+    x86_64::{PhysAddr, VirtAddr},
+};
+
+static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+/// The last value of a 32-bit main counter, extended to 64 bits.
+static EXTENDED_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+const REGISTERS_SIZE: Bytes = Bytes::new(0x400);
+
+const ENABLE_CNF: u64 = 1 << 0;
+const LEG_RT_CNF: u64 = 1 << 1;
+
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+#[derive(Copy, Clone, Debug)]
+enum Register {
+    GeneralCapabilitiesAndId = 0x00,
+    GeneralConfiguration = 0x10,
+    MainCounterValue = 0xf0,
+}
+impl Register {
+    fn offset(self) -> usize {
+        self as usize
+    }
+}
+
+/// Maps the registers of the HPET described in the ACPI HPET table and starts the main counter.
+///
+/// The mapping lives until the kernel halts. This function does nothing if there is no HPET.
+///
+/// # Panics
+///
+/// This function panics if it is called more than once.
+pub(crate) fn init(table: &AcpiTables<allocator::acpi::Mapper>) {
+    if let Ok(info) = HpetInfo::new(table) {
+        let hpet = Hpet::new(&info);
+
+        hpet.start();
+
+        info!(
+            "HPET: {} fs per tick, {}-bit counter.",
+            hpet.period,
+            if hpet.is_64bit { 64 } else { 32 }
+        );
+
+        HPET.try_init_once(|| hpet)
+            .expect("`hpet::init` is called more than once.");
+    }
+}
+
+pub(crate) fn is_available() -> bool {
+    HPET.is_initialized()
+}
+
+/// Returns the nanoseconds elapsed since [`init`] started the main counter.
+///
+/// A 32-bit main counter is extended in software, which misses a wrap around unless this function
+/// is called at least once per period of the counter.
+///
+/// # Panics
+///
+/// This function panics if there is no HPET.
+pub(crate) fn nanoseconds() -> u64 {
+    let hpet = hpet();
+
+    ticks_to_nanoseconds(hpet.extended_counter(), hpet.period)
+}
+
+/// Waits for `t` microseconds with the main counter.
+///
+/// # Panics
+///
+/// This function panics if there is no HPET.
+pub(crate) fn wait_microseconds(t: u32) {
+    let hpet = hpet();
+
+    let ticks = u64::from(t) * 1000 * FEMTOSECONDS_PER_NANOSECOND / hpet.period;
+    let mask = hpet.mask();
+
+    let mut elapsed = 0;
+    let mut last = hpet.counter();
+
+    while elapsed < ticks {
+        let now = hpet.counter();
+
+        elapsed += now.wrapping_sub(last) & mask;
+        last = now;
+    }
+}
+
+fn hpet<'a>() -> &'a Hpet {
+    let hpet = HPET.try_get();
+    hpet.expect("The HPET is not available.")
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn ticks_to_nanoseconds(ticks: u64, period: u64) -> u64 {
+    // The result fits in `u64` for hundreds of years.
+    (u128::from(ticks) * u128::from(period) / u128::from(FEMTOSECONDS_PER_NANOSECOND)) as u64
+}
+
+struct Hpet {
+    /// The registers are mapped to this virtual address.
+    base: VirtAddr,
+
+    /// The period of the main counter in femtoseconds.
+    period: u64,
+
+    is_64bit: bool,
+}
+impl Hpet {
+    fn new(info: &HpetInfo) -> Self {
+        let base = PhysAddr::new(u64::try_from(info.base_address).unwrap());
+        let base = mem::map_pages_for_kernel(base, REGISTERS_SIZE, CacheType::Uncacheable);
+
+        let mut hpet = Self {
+            base,
+            period: 0,
+            is_64bit: info.main_counter_is_64bits(),
+        };
+
+        hpet.period = hpet.read(Register::GeneralCapabilitiesAndId) >> 32;
+
+        assert_ne!(hpet.period, 0, "The HPET reports the period of 0 fs.");
+
+        hpet
+    }
+
+    /// Starts the main counter from 0 without the legacy replacement routing.
+    fn start(&self) {
+        let config = self.read(Register::GeneralConfiguration);
+        let stopped = config & !(ENABLE_CNF | LEG_RT_CNF);
+
+        self.write(Register::GeneralConfiguration, stopped);
+        self.write(Register::MainCounterValue, 0);
+        self.write(Register::GeneralConfiguration, stopped | ENABLE_CNF);
+    }
+
+    fn counter(&self) -> u64 {
+        self.read(Register::MainCounterValue) & self.mask()
+    }
+
+    /// Returns the value of the main counter, adding the elapsed ticks to [`EXTENDED_COUNTER`] if
+    /// the counter has only 32 bits.
+    fn extended_counter(&self) -> u64 {
+        if self.is_64bit {
+            return self.counter();
+        }
+
+        let mut last = EXTENDED_COUNTER.load(Ordering::Acquire);
+
+        loop {
+            let now = last + (self.counter().wrapping_sub(last) & self.mask());
+
+            match EXTENDED_COUNTER.compare_exchange_weak(
+                last,
+                now,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return now,
+                Err(updated) => last = updated,
+            }
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        if self.is_64bit {
+            u64::MAX
+        } else {
+            0xffff_ffff
+        }
+    }
+
+    fn read(&self, register: Register) -> u64 {
+        // SAFETY: The registers are mapped to `base`.
+        unsafe { ptr::read_volatile((self.base + register.offset()).as_ptr()) }
+    }
+
+    fn write(&self, register: Register, value: u64) {
+        // SAFETY: The registers are mapped to `base`.
+        unsafe { ptr::write_volatile((self.base + register.offset()).as_mut_ptr(), value) }
+    }
+}
@@ -1,14 +1,34 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use {
-    crate::interrupt::handler::h_20, conquer_once::spin::Lazy,
+    crate::{
+        interrupt::{
+            apic::local::SPURIOUS_VECTOR,
+            handler::{device_not_available, double_fault, h_20, page_fault, reschedule, spurious},
+        },
+        smp::RESCHEDULE_VECTOR,
+        tss,
+    },
+    conquer_once::spin::Lazy,
     x86_64::structures::idt::InterruptDescriptorTable,
 };
 
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
 
+    idt.device_not_available
+        .set_handler_fn(device_not_available);
+    idt.page_fault.set_handler_fn(page_fault);
+
+    // SAFETY: The stack is used only by the double fault handler.
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault)
+            .set_stack_index(tss::DOUBLE_FAULT_IST_INDEX);
+    }
     idt[0x20].set_handler_fn(h_20);
+    idt[usize::from(RESCHEDULE_VECTOR)].set_handler_fn(reschedule);
+    idt[usize::from(SPURIOUS_VECTOR)].set_handler_fn(spurious);
 
     idt
 });
@@ -2,5 +2,6 @@
 
 pub(crate) mod apic;
 mod handler;
+pub(crate) mod hpet;
 pub(crate) mod idt;
 pub(crate) mod timer;
@@ -1,47 +1,87 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use {
-    crate::mem::{accessor::Single, allocator},
+    super::{
+        apic::local::{self, Register},
+        hpet,
+    },
+    crate::{
+        mem::{accessor::Single, allocator},
+        time,
+    },
     acpi::{platform::address::AddressSpace, AcpiTables},
-    core::convert::TryInto,
+    conquer_once::spin::OnceCell,
+    core::{
+        convert::{TryFrom, TryInto},
+        time::Duration,
+    },
     log::info,
     x86_64::{instructions::port::PortReadOnly, PhysAddr},
 };
 
-const LVT_TIMER: PhysAddr = PhysAddr::new_truncate(0xfee0_0320);
-const INITIAL_COUNT: PhysAddr = PhysAddr::new_truncate(0xfee0_0380);
-const CURRENT_COUNT: PhysAddr = PhysAddr::new_truncate(0xfee0_0390);
-const DIVIDE_CONFIG: PhysAddr = PhysAddr::new_truncate(0xfee0_03e0);
 const TIMER_VECTOR: u8 = 0x20;
 
+/// The interval of the timer interrupts.
+pub(crate) const TICK: Duration = Duration::from_millis(10);
+
+/// The frequency of the local APIC timer measured by the bootstrap processor.
+static FREQUENCY: OnceCell<u32> = OnceCell::uninit();
+
+/// Starts the HPET if there is one, the local APIC timer of the bootstrap processor, and the
+/// monotonic clock.
 pub(crate) fn init(table: &AcpiTables<allocator::acpi::Mapper>) {
-    let mut local_apic_tm = LocalApic::new(table);
+    hpet::init(table);
+
+    let mut local_apic_tm = ApicTimer::new(table);
     local_apic_tm.init();
+
+    time::init(table);
+}
+
+/// Starts the local APIC timer of an application processor with the frequency measured by
+/// [`init`].
+///
+/// # Panics
+///
+/// This function panics if [`init`] is not called.
+pub(crate) fn init_ap() {
+    let f = FREQUENCY.try_get();
+    let f = *f.expect("The frequency of the local APIC timer is not measured.");
+
+    set_modes(f);
+}
+
+/// Waits for `t` microseconds with the ACPI PM timer, or the HPET if there is no PM timer.
+///
+/// # Panics
+///
+/// This function panics if there is neither the PM timer nor the HPET.
+pub(crate) fn wait_microseconds(table: &AcpiTables<allocator::acpi::Mapper>, t: u32) {
+    Clock::new(table).wait_microseconds(t);
 }
 
-struct LocalApic {
-    lvt_timer: Single<u32>,
-    initial_count: Single<u32>,
-    current_count: Single<u32>,
-    divide_config: Single<u32>,
-    pm: AcpiPm,
+/// Makes the local APIC timer, which counts `frequency` times per second without a divisor,
+/// generate an interrupt every [`TICK`].
+fn set_modes(frequency: u32) {
+    const DIVIDE_BY_16: u32 = 0b0011;
+
+    let count = u128::from(frequency / 16) * TICK.as_nanos() / 1_000_000_000;
+
+    local::write(Register::DivideConfiguration, DIVIDE_BY_16);
+    local::write(Register::LvtTimer, u32::from(TIMER_VECTOR) | (1 << 17));
+    local::write(Register::InitialCount, u32::try_from(count).unwrap());
+}
+
+struct ApicTimer {
+    clock: Clock,
     frequency: Option<u32>,
 }
-impl LocalApic {
+impl ApicTimer {
     fn new(table: &AcpiTables<allocator::acpi::Mapper>) -> Self {
-        // SAFETY: These operations are safe because the addresses are the correct ones.
-        let lvt_timer = unsafe { crate::mem::accessor::new::<u32>(LVT_TIMER) };
-        let initial_count = unsafe { crate::mem::accessor::new::<u32>(INITIAL_COUNT) };
-        let current_count = unsafe { crate::mem::accessor::new::<u32>(CURRENT_COUNT) };
-        let divide_config = unsafe { crate::mem::accessor::new::<u32>(DIVIDE_CONFIG) };
-        let pm = AcpiPm::new(table);
+        let clock = Clock::new(table);
 
         Self {
-            lvt_timer,
-            initial_count,
-            current_count,
-            divide_config,
-            pm,
+            clock,
             frequency: None,
         }
     }
@@ -54,21 +94,48 @@ impl LocalApic {
     fn get_frequency(&mut self) {
         const MAX_COUNT: u32 = !0;
 
-        self.divide_config.write_volatile(0b1011);
-        self.lvt_timer.write_volatile(1 << 16 | 32);
-        self.initial_count.write_volatile(MAX_COUNT);
-        self.pm.wait_milliseconds(100);
+        local::write(Register::DivideConfiguration, 0b1011);
+        local::write(Register::LvtTimer, 1 << 16 | 32);
+        local::write(Register::InitialCount, MAX_COUNT);
+        self.clock.wait_microseconds(100_000);
 
-        self.frequency = Some((MAX_COUNT - self.current_count.read_volatile()) * 10);
+        self.frequency = Some((MAX_COUNT - local::read(Register::CurrentCount)) * 10);
     }
 
     fn set_modes(&mut self) {
         let f = self.frequency.expect("Get the frequency first.");
         info!("Frequency: {}", f);
-        self.divide_config.write_volatile(3);
-        self.lvt_timer
-            .write_volatile(u32::from(TIMER_VECTOR) | (1 << 17));
-        self.initial_count.write_volatile(f * 10);
+
+        FREQUENCY.init_once(|| f);
+
+        set_modes(f);
+    }
+}
+
+/// The timer which measures the time before the local APIC timer is calibrated.
+enum Clock {
+    AcpiPm(AcpiPm),
+    Hpet,
+}
+impl Clock {
+    /// Selects the ACPI PM timer if there is one, and the HPET otherwise.
+    fn new(table: &AcpiTables<allocator::acpi::Mapper>) -> Self {
+        let pm_timer = table.platform_info().ok().and_then(|p| p.pm_timer);
+
+        if pm_timer.is_some() {
+            Self::AcpiPm(AcpiPm::new(table))
+        } else if hpet::is_available() {
+            Self::Hpet
+        } else {
+            panic!("Neither the ACPI PM timer nor the HPET is available.");
+        }
+    }
+
+    fn wait_microseconds(&mut self, t: u32) {
+        match self {
+            Self::AcpiPm(pm) => pm.wait_microseconds(t),
+            Self::Hpet => hpet::wait_microseconds(t),
+        }
     }
 }
 
@@ -95,19 +162,24 @@ impl AcpiPm {
         }
     }
 
-    pub(crate) fn wait_milliseconds(&mut self, t: u32) {
-        const FREQUENCY: u32 = 3_579_545;
-        let start = self.reader.read();
-        let mut end = start.wrapping_add(FREQUENCY.wrapping_mul(t / 1000));
-        if let SupportedBits::Bits24 = self.supported {
-            end &= 0x00ff_ffff;
-        }
+    fn wait_microseconds(&mut self, t: u32) {
+        const FREQUENCY: u64 = 3_579_545;
 
-        if end < start {
-            while self.reader.read() >= start {}
-        }
+        let ticks = FREQUENCY * u64::from(t) / 1_000_000;
+        let mask = match self.supported {
+            SupportedBits::Bits32 => 0xffff_ffff,
+            SupportedBits::Bits24 => 0x00ff_ffff,
+        };
 
-        while self.reader.read() < end {}
+        let mut elapsed = 0;
+        let mut last = self.reader.read();
+
+        while elapsed < ticks {
+            let now = self.reader.read();
+
+            elapsed += u64::from(now.wrapping_sub(last) & mask);
+            last = now;
+        }
     }
 }
 
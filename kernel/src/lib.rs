@@ -14,18 +14,24 @@
 extern crate alloc;
 
 mod acpi;
+mod fpu;
+mod framebuffer;
 mod fs;
 mod gdt;
 mod interrupt;
 mod mem;
 mod panic;
+mod percpu;
 mod process;
 mod qemu;
+mod smp;
+mod sync;
 mod syscall;
 mod sysproc;
 
 #[cfg(feature = "qemu_test")]
 mod tests;
+mod time;
 mod tss;
 
 use {
@@ -46,8 +52,13 @@ pub unsafe extern "sysv64" fn os_main(boot_info: *mut boot_info::Info) -> ! {
 }
 
 fn init(mut boot_info: boot_info::Info) {
+    // The locks use the per-CPU area to find the processor which holds them.
+    percpu::init(0);
+
     vram::init(&boot_info);
 
+    framebuffer::init(boot_info.vram());
+
     terminal::log::init().unwrap();
 
     info!("Hello Ramen OS!");
@@ -59,14 +70,23 @@ fn init(mut boot_info: boot_info::Info) {
 
     idt::init();
 
+    fpu::init();
+
     mem::init(boot_info.mem_map_mut());
 
     let acpi = unsafe { acpi::get(boot_info.rsdp()) };
 
+    apic::local::init();
     apic::io::init(&acpi);
 
     timer::init(&acpi);
 
+    smp::init(&acpi);
+
+    // The kernel does not touch ACPI tables after this line.
+    drop(acpi);
+    mem::reclaim_acpi_memory();
+
     vram::print_info();
 
     syscall::init();
@@ -74,8 +94,38 @@ fn init(mut boot_info: boot_info::Info) {
     process::init();
 }
 
+/// The entry point of the application processors, called by the trampoline in `smp`.
+extern "sysv64" fn ap_main(cpu: usize) -> ! {
+    init_ap(cpu);
+    idle();
+}
+
+fn init_ap(cpu: usize) {
+    percpu::init(cpu);
+
+    gdt::init_ap(cpu);
+
+    idt::init();
+
+    mem::init_ap();
+
+    fpu::init();
+
+    apic::local::init_ap();
+
+    timer::init_ap();
+
+    syscall::init();
+
+    process::init_ap();
+
+    smp::mark_as_started();
+}
+
 fn idle() -> ! {
     loop {
-        interrupts::enable_and_hlt();
+        if !mem::allocator::zero::fill_pool() {
+            interrupts::enable_and_hlt();
+        }
     }
 }
@@ -0,0 +1,700 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    super::{
+        allocator,
+        allocator::{phys, zero},
+        paging,
+    },
+    alloc::{collections::BTreeMap, vec::Vec},
+    core::{convert::TryFrom, fmt, ptr},
+    os_units::{Bytes, NumOfPages},
+    range_allocator::RangeAllocator,
+    syscalls::{CacheType, DmaConstraints, MemoryError},
+    x86_64::{
+        structures::{
+            idt::PageFaultErrorCode,
+            paging::{
+                frame::PhysFrameRange, page::PageRange, FrameAllocator, FrameDeallocator, Page,
+                PageSize, PageTableFlags, PhysFrame, Size4KiB,
+            },
+        },
+        PhysAddr, VirtAddr,
+    },
+};
+
+pub(super) const USER_START: VirtAddr = VirtAddr::new_truncate(0x1000);
+pub(super) const USER_END: VirtAddr = VirtAddr::new_truncate(0x0000_8000_0000_0000);
+
+/// The flag of the pages which are shared with other processes and copied on the first write.
+const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+const FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
+
+/// The virtual memory areas of a process.
+///
+/// The methods which touch page tables operate on the current address space. Call them only when
+/// the address space is active.
+#[derive(Debug)]
+pub(crate) struct AddressSpace {
+    areas: BTreeMap<VirtAddr, Area>,
+    free: RangeAllocator,
+}
+impl AddressSpace {
+    pub(crate) fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+            free: user_range(),
+        }
+    }
+
+    /// Reserves `num_of_pages` pages which are mapped to zeroed frames on the first access.
+    pub(crate) fn allocate_anonymous(
+        &mut self,
+        num_of_pages: NumOfPages<Size4KiB>,
+    ) -> Option<VirtAddr> {
+        let start = self.reserve(num_of_pages)?;
+
+        self.insert(Area::new(
+            page_range(start, num_of_pages),
+            Kind::Anonymous,
+            FLAGS,
+        ));
+
+        Some(start)
+    }
+
+    /// Reserves a stack of `num_of_pages` pages with a guard page below it.
+    ///
+    /// The stack is mapped on demand as an anonymous area. Accessing the guard page is reported as
+    /// a stack overflow.
+    pub(crate) fn allocate_stack(
+        &mut self,
+        num_of_pages: NumOfPages<Size4KiB>,
+    ) -> Option<VirtAddr> {
+        let guard = NumOfPages::new(1);
+
+        let guard_start = self.reserve(guard + num_of_pages)?;
+        let start = guard_start + guard.as_bytes().as_usize();
+
+        self.insert(Area::new(
+            page_range(guard_start, guard),
+            Kind::Guard,
+            PageTableFlags::empty(),
+        ));
+        self.insert(Area::new(
+            page_range(start, num_of_pages),
+            Kind::Anonymous,
+            FLAGS,
+        ));
+
+        Some(start)
+    }
+
+    /// Returns the start address of `num_of_pages` free pages without reserving them.
+    pub(crate) fn find_free(&mut self, num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
+        let start = self.reserve(num_of_pages)?;
+
+        self.unreserve(start, num_of_pages);
+
+        Some(start)
+    }
+
+    /// Returns the start address of `num_of_pages` free pages at a position chosen by `random`,
+    /// without reserving them.
+    pub(crate) fn find_free_randomly(
+        &mut self,
+        num_of_pages: NumOfPages<Size4KiB>,
+        random: u64,
+    ) -> Option<VirtAddr> {
+        let start = self.free.allocate_randomly(num_of_pages, random)?;
+
+        self.unreserve(start, num_of_pages);
+
+        Some(start)
+    }
+
+    /// Records `page_range` as an anonymous area and maps all pages in it to new frames.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if `page_range` overlaps with one of the existing areas or
+    /// is out of the user address space.
+    pub(crate) fn map_anonymous_eagerly(
+        &mut self,
+        page_range: PageRange,
+        flags: PageTableFlags,
+    ) -> Result<(), OverlapError> {
+        self.insert_anonymous_at(page_range, flags)?;
+
+        for page in page_range {
+            map_zeroed_frame(page, flags);
+        }
+
+        Ok(())
+    }
+
+    /// Records `page_range` as a read-only anonymous area and maps the pages to `frames`, which
+    /// other address spaces may also map.
+    ///
+    /// The pages are executable. The area holds a reference to each frame, like the ones shared
+    /// by [`AddressSpace::fork`].
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if `page_range` overlaps with one of the existing areas or
+    /// is out of the user address space.
+    pub(crate) fn map_shared(
+        &mut self,
+        page_range: PageRange,
+        frames: &[PhysFrame],
+    ) -> Result<(), OverlapError> {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        assert_eq!(
+            page_range.count(),
+            frames.len(),
+            "The number of frames is wrong."
+        );
+
+        self.insert_anonymous_at(page_range, flags)?;
+
+        for (page, frame) in page_range.zip(frames) {
+            phys::add_reference(*frame);
+
+            // SAFETY: The frame is not writable through this page, so sharing it does not affect
+            // the other address spaces.
+            unsafe {
+                paging::map_to(page, *frame, flags).expect("Failed to map a page.");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Maps `frames` to the user space, and returns the address of the first page.
+    ///
+    /// The frames are not owned by the address space. They are never returned to the frame
+    /// allocator.
+    pub(crate) fn map(&mut self, frames: PhysFrameRange, cache: CacheType) -> Option<VirtAddr> {
+        let flags = FLAGS | super::cache_flags(cache);
+
+        let num_of_pages = NumOfPages::new(usize::try_from(frames.end - frames.start).unwrap());
+
+        let virt = self.reserve(num_of_pages)?;
+
+        let area = Area::new(page_range(virt, num_of_pages), Kind::Mmio, flags);
+
+        self.map_at(area, frames.start.start_address());
+
+        Some(virt)
+    }
+
+    /// Allocates physically contiguous memory satisfying `constraints` and maps it to the user
+    /// space.
+    pub(crate) fn allocate_dma(
+        &mut self,
+        bytes: Bytes,
+        constraints: DmaConstraints,
+    ) -> Option<(VirtAddr, PhysAddr)> {
+        let num_of_pages = bytes.as_num_of_pages();
+
+        let virt = self.reserve(num_of_pages)?;
+
+        let phys = allocator::allocate_dma(bytes, constraints);
+
+        if phys.is_none() {
+            self.unreserve(virt, num_of_pages);
+        }
+
+        let phys = phys?;
+
+        let pages = page_range(virt, num_of_pages);
+        let flags = FLAGS | super::cache_flags(constraints.cache);
+
+        self.map_at(Area::new(pages, Kind::Contiguous(phys), flags), phys);
+
+        // SAFETY: The memory is mapped right now and is not used by others.
+        unsafe {
+            ptr::write_bytes(
+                virt.as_mut_ptr::<u8>(),
+                0,
+                num_of_pages.as_bytes().as_usize(),
+            );
+        }
+
+        Some((virt, phys))
+    }
+
+    /// Removes the area starting from `start`, and frees the frames of it.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if the address space does not have an area starting from
+    /// `start`, or the area is not the allocated one, or its size is not `num_of_pages`.
+    pub(crate) fn deallocate(
+        &mut self,
+        start: VirtAddr,
+        num_of_pages: NumOfPages<Size4KiB>,
+    ) -> Result<(), MemoryError> {
+        let area = self.owned_area(start, num_of_pages)?;
+
+        if matches!(area.kind, Kind::Mmio | Kind::Guard) {
+            return Err(MemoryError::Mismatch);
+        }
+
+        self.remove(area);
+
+        Ok(())
+    }
+
+    /// Removes the area mapped by [`AddressSpace::map`], and unmaps the pages of it.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if `start` and `object_size` do not match the area returned by
+    /// [`AddressSpace::map`].
+    pub(crate) fn unmap(&mut self, start: VirtAddr, object_size: Bytes) -> Result<(), MemoryError> {
+        let area_start = start.align_down(Size4KiB::SIZE);
+        let end = (start + object_size.as_usize()).align_up(Size4KiB::SIZE);
+
+        let num_of_pages = Bytes::new(usize::try_from(end - area_start).unwrap()).as_num_of_pages();
+
+        let area = self.owned_area(area_start, num_of_pages)?;
+
+        if area.kind != Kind::Mmio {
+            return Err(MemoryError::Mismatch);
+        }
+
+        self.remove(area);
+
+        Ok(())
+    }
+
+    /// Handles the page fault at `addr`.
+    ///
+    /// If the page is in an anonymous area, this method maps a zeroed frame to it on the first
+    /// access, or copies the shared frame on the first write after [`AddressSpace::fork`].
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if no area contains `addr`, `addr` is in a guard page, or the
+    /// access violates the permission of the area.
+    pub(crate) fn handle_page_fault(
+        &mut self,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> Result<(), SegmentationFault> {
+        let area = self.area_containing(addr);
+        let area = area.ok_or(SegmentationFault::InvalidAccess(addr))?;
+
+        if area.kind == Kind::Guard {
+            return Err(SegmentationFault::StackOverflow(addr));
+        }
+
+        let page = Page::containing_address(addr);
+
+        let is_write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+
+        if !area.maps_on_demand(is_write) {
+            return Err(SegmentationFault::InvalidAccess(addr));
+        }
+
+        if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            map_zeroed_frame(page, area.flags);
+
+            Ok(())
+        } else if is_write && is_copy_on_write(page) {
+            copy_on_write(page, area.flags);
+
+            Ok(())
+        } else {
+            Err(SegmentationFault::InvalidAccess(addr))
+        }
+    }
+
+    /// Translates `addr` to the physical address so that the kernel can write to it.
+    ///
+    /// If `addr` is in an anonymous area, this method maps a frame to it if it is not mapped yet,
+    /// and copies the frame if it is shared with another process.
+    pub(crate) fn translate_for_write(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        let page = Page::containing_address(addr);
+
+        if let Some(area) = self.area_containing(addr) {
+            if area.kind == Kind::Anonymous {
+                if paging::translate_addr(addr).is_none() {
+                    map_zeroed_frame(page, area.flags);
+                } else if is_copy_on_write(page) {
+                    copy_on_write(page, area.flags);
+                }
+            }
+        }
+
+        paging::translate_addr(addr)
+    }
+
+    /// Translates `addr` to the physical address so that the kernel can access it on behalf of
+    /// the process.
+    ///
+    /// Unlike [`AddressSpace::translate_for_write`], this method returns [`None`] if `addr` is not
+    /// in any area of this address space, or the process cannot read the page, or write to it if
+    /// `is_write` is `true`.
+    pub(crate) fn translate_for_user_access(
+        &mut self,
+        addr: VirtAddr,
+        is_write: bool,
+    ) -> Option<PhysAddr> {
+        let area = self.area_containing(addr)?;
+        let page = Page::containing_address(addr);
+
+        if area.maps_on_demand(is_write) {
+            if paging::translate_addr(addr).is_none() {
+                map_zeroed_frame(page, area.flags);
+            } else if is_write && is_copy_on_write(page) {
+                copy_on_write(page, area.flags);
+            }
+        }
+
+        // The flags of the area may be stale because `protect` changes only the page table.
+        let (_, flags) = paging::translate_page(page)?;
+
+        let permitted = flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            && (!is_write || flags.contains(PageTableFlags::WRITABLE));
+
+        if permitted {
+            paging::translate_addr(addr)
+        } else {
+            None
+        }
+    }
+
+    /// Creates a copy of this address space for a child process whose PML4 is `child_pml4`.
+    ///
+    /// The mapped anonymous pages are shared by both processes. Writable ones become read-only,
+    /// and are copied on the first write. DMA memory is not inherited by the child.
+    ///
+    /// # Safety
+    ///
+    /// `child_pml4` must map the kernel as the current one does, and must not map any pages in
+    /// the user space.
+    pub(crate) unsafe fn fork(&mut self, child_pml4: PhysFrame) -> AddressSpace {
+        let mut child = AddressSpace::new();
+        let mut mappings = Vec::new();
+
+        for area in self.areas.values() {
+            if let Kind::Contiguous(_) = area.kind {
+                continue;
+            }
+
+            mappings.extend(area.mappings_for_child());
+
+            let r = child.free.allocate_at(area.start, area.num_of_pages);
+            r.expect("The area is not free in the child.");
+
+            child.insert(*area);
+        }
+
+        // SAFETY: The caller must ensure that `child_pml4` maps the kernel and does not map any
+        // pages in the user space yet.
+        unsafe {
+            paging::switch_pml4_do(child_pml4, || {
+                for (page, frame, flags) in mappings {
+                    paging::map_to(page, frame, flags).expect("Failed to map a page.");
+                }
+            });
+        }
+
+        child
+    }
+
+    /// Unmaps all areas and frees the owned frames and the page tables of the user space.
+    pub(crate) fn release(&mut self) {
+        for (_, area) in core::mem::take(&mut self.areas) {
+            area.release();
+        }
+
+        self.free = user_range();
+
+        paging::clean_up_user_page_tables();
+    }
+
+    fn insert_anonymous_at(
+        &mut self,
+        page_range: PageRange,
+        flags: PageTableFlags,
+    ) -> Result<(), OverlapError> {
+        let num_of_pages = NumOfPages::new(page_range.count());
+        let area = Area::new(page_range, Kind::Anonymous, flags);
+
+        if !area.is_in_user_space() || self.free.allocate_at(area.start, num_of_pages).is_err() {
+            return Err(OverlapError(area.start));
+        }
+
+        self.insert(area);
+
+        Ok(())
+    }
+
+    /// Records `area` and maps its pages to the frames from `phys`.
+    fn map_at(&mut self, area: Area, phys: PhysAddr) {
+        super::map_pages_at(area.pages(), phys, area.flags);
+
+        self.insert(area);
+    }
+
+    fn reserve(&mut self, num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
+        self.free.allocate(num_of_pages)
+    }
+
+    fn unreserve(&mut self, start: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
+        let r = self.free.deallocate(start, num_of_pages);
+        r.expect("Failed to return a virtual range.");
+    }
+
+    fn owned_area(
+        &self,
+        start: VirtAddr,
+        num_of_pages: NumOfPages<Size4KiB>,
+    ) -> Result<Area, MemoryError> {
+        let area = self.areas.get(&start).ok_or(MemoryError::NotOwned)?;
+
+        if area.num_of_pages == num_of_pages {
+            Ok(*area)
+        } else {
+            Err(MemoryError::Mismatch)
+        }
+    }
+
+    fn area_containing(&self, addr: VirtAddr) -> Option<Area> {
+        let (_, area) = self.areas.range(..=addr).next_back()?;
+
+        area.contains(addr).then(|| *area)
+    }
+
+    fn insert(&mut self, area: Area) {
+        let r = self.areas.insert(area.start, area);
+
+        assert!(r.is_none(), "Duplicated area at {:?}.", area.start);
+    }
+
+    /// Removes `area`, releases the pages of it, and returns its range to the free ranges.
+    fn remove(&mut self, area: Area) {
+        self.areas.remove(&area.start);
+        area.release();
+
+        self.unreserve(area.start, area.num_of_pages);
+    }
+}
+
+fn page_range(start: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) -> PageRange {
+    let start = Page::containing_address(start);
+
+    Page::range(
+        start,
+        start + u64::try_from(num_of_pages.as_usize()).unwrap(),
+    )
+}
+
+fn user_range() -> RangeAllocator {
+    let num_of_pages = Bytes::new(usize::try_from(USER_END - USER_START).unwrap());
+
+    RangeAllocator::with_range(USER_START, num_of_pages.as_num_of_pages())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum SegmentationFault {
+    /// The process accessed memory which it does not own, or violated the permission of it.
+    InvalidAccess(VirtAddr),
+    /// The process touched the guard page below a stack.
+    StackOverflow(VirtAddr),
+}
+impl fmt::Display for SegmentationFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidAccess(addr) => write!(f, "Segmentation fault at {:?}.", addr),
+            Self::StackOverflow(addr) => write!(f, "Stack overflow at {:?}.", addr),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct OverlapError(pub(crate) VirtAddr);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Kind {
+    /// The frames are allocated page by page. Unmapped pages are mapped to zeroed frames on the
+    /// first access.
+    Anonymous,
+    /// The frames starting from the physical address are allocated at once, for example for DMA.
+    Contiguous(PhysAddr),
+    /// The pages are mapped to memory which the address space does not own, such as MMIO
+    /// registers or the framebuffer.
+    Mmio,
+    /// The pages are never mapped so that overflowing the stack above them faults.
+    Guard,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Area {
+    start: VirtAddr,
+    num_of_pages: NumOfPages<Size4KiB>,
+    kind: Kind,
+    flags: PageTableFlags,
+}
+impl Area {
+    fn new(pages: PageRange, kind: Kind, flags: PageTableFlags) -> Self {
+        Self {
+            start: pages.start.start_address(),
+            num_of_pages: NumOfPages::new(pages.count()),
+            kind,
+            flags,
+        }
+    }
+
+    fn end(&self) -> VirtAddr {
+        self.start + self.num_of_pages.as_bytes().as_usize()
+    }
+
+    fn contains(&self, addr: VirtAddr) -> bool {
+        (self.start..self.end()).contains(&addr)
+    }
+
+    /// Returns `true` if the page fault handler maps a frame to a page of the area on the read,
+    /// or the write if `is_write` is `true`.
+    fn maps_on_demand(&self, is_write: bool) -> bool {
+        self.kind == Kind::Anonymous && (!is_write || self.flags.contains(PageTableFlags::WRITABLE))
+    }
+
+    fn is_in_user_space(&self) -> bool {
+        USER_START <= self.start && self.end() <= USER_END
+    }
+
+    fn pages(&self) -> PageRange {
+        PageRange {
+            start: Page::containing_address(self.start),
+            end: Page::containing_address(self.end()),
+        }
+    }
+
+    /// Returns the mappings which the child of [`AddressSpace::fork`] copies.
+    ///
+    /// The anonymous pages become copy-on-write.
+    fn mappings_for_child(&self) -> Vec<(Page, PhysFrame, PageTableFlags)> {
+        match self.kind {
+            Kind::Anonymous => self.pages().filter_map(share_page).collect(),
+            Kind::Mmio => self
+                .pages()
+                .filter_map(|p| paging::translate_page(p).map(|(f, fl)| (p, f, fl)))
+                .collect(),
+            Kind::Guard | Kind::Contiguous(_) => Vec::new(),
+        }
+    }
+
+    /// Unmaps the pages of the area and frees the frames which the area owns.
+    fn release(&self) {
+        for page in self.pages() {
+            let frame = paging::unmap(page);
+
+            if let (Kind::Anonymous, Ok(frame)) = (self.kind, frame) {
+                // SAFETY: The frame was allocated for this area, and is no longer mapped.
+                unsafe {
+                    phys::allocator().deallocate_frame(frame);
+                }
+            }
+        }
+
+        if let Kind::Contiguous(phys) = self.kind {
+            phys::free(phys, self.num_of_pages);
+        }
+    }
+}
+
+/// Makes `page` copy-on-write and adds a reference to the frame of it.
+///
+/// This function returns the page, the frame and the new flags if `page` is mapped.
+fn share_page(page: Page) -> Option<(Page, PhysFrame, PageTableFlags)> {
+    let (frame, mut flags) = paging::translate_page(page)?;
+
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags.remove(PageTableFlags::WRITABLE);
+        flags.insert(COPY_ON_WRITE);
+
+        // SAFETY: The page becomes read-only. Writing to it causes a page fault, and the handler
+        // copies the frame.
+        unsafe {
+            paging::update_flags(page, flags).expect("Failed to update flags.");
+        }
+    }
+
+    phys::add_reference(frame);
+
+    Some((page, frame, flags))
+}
+
+fn is_copy_on_write(page: Page) -> bool {
+    paging::translate_page(page).map_or(false, |(_, flags)| flags.contains(COPY_ON_WRITE))
+}
+
+/// Gives the page its own writable frame.
+fn copy_on_write(page: Page, flags: PageTableFlags) {
+    let (frame, _) = paging::translate_page(page).expect("The page is not mapped.");
+
+    if phys::reference_count(frame) == 1 {
+        // No other process shares the frame.
+        // SAFETY: The frame is used only by this page.
+        unsafe {
+            paging::update_flags(page, flags).expect("Failed to update flags.");
+        }
+
+        return;
+    }
+
+    let new_frame = phys::allocator().allocate_frame().expect("No free frames.");
+
+    copy_page_to_frame(page, new_frame);
+
+    let frame = paging::unmap(page).expect("Failed to unmap a page.");
+
+    // SAFETY: The page no longer refers to the frame. This only drops a reference to it because
+    // the other processes still share it.
+    unsafe {
+        phys::allocator().deallocate_frame(frame);
+    }
+
+    // SAFETY: `new_frame` is used only by this page.
+    unsafe {
+        paging::map_to(page, new_frame, flags).expect("Failed to map a page.");
+    }
+}
+
+/// Copies the content of `page` to `frame` through a temporary mapping in the kernel region.
+fn copy_page_to_frame(page: Page, frame: PhysFrame) {
+    let bytes = Bytes::new(usize::try_from(Size4KiB::SIZE).unwrap());
+
+    let virt = super::map_pages_for_kernel(frame.start_address(), bytes, CacheType::WriteBack);
+
+    // SAFETY: `page` is mapped and readable, and `virt` is mapped to `frame`, which nobody else
+    // uses.
+    unsafe {
+        ptr::copy_nonoverlapping(
+            page.start_address().as_ptr::<u8>(),
+            virt.as_mut_ptr::<u8>(),
+            bytes.as_usize(),
+        );
+    }
+
+    super::unmap_pages(virt, bytes);
+}
+
+fn map_zeroed_frame(page: Page, flags: PageTableFlags) {
+    let frame = zero::allocate_frame().expect("No free frames.");
+
+    // SAFETY: The frame is not used by others, and the user process reads only zeros from it.
+    unsafe {
+        paging::map_to(page, frame, flags).expect("Failed to map a page.");
+    }
+}
@@ -3,15 +3,52 @@
 // WORKAROUND: https://stackoverflow.com/questions/63933070/clippy-says-too-many-arguments-to-static-declaration
 #![allow(clippy::too_many_arguments)]
 
-use {core::alloc::Layout, linked_list_allocator::LockedHeap};
+use {
+    super::phys,
+    crate::{
+        mem::paging,
+        smp,
+        sync::{IrqSpinlock, IrqSpinlockGuard},
+    },
+    core::{
+        alloc::{GlobalAlloc, Layout},
+        fmt, hint,
+        ptr::{self, NonNull},
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    linked_list_allocator::Heap,
+    os_units::Bytes,
+    predefined_mmap::HEAP_EXTENSION_ADDR,
+    x86_64::{
+        structures::paging::{Page, PageSize, PageTableFlags, Size4KiB},
+        VirtAddr,
+    },
+};
 
 extern "C" {
     static HEAP_START: usize;
     static HEAP_END: usize;
 }
 
+/// The maximum size of the memory which the kernel heap maps in addition to the initial region.
+///
+/// Raise this if the kernel needs more heap memory.
+const MAX_EXTENSION_SIZE: Bytes = Bytes::new(64 * 1024 * 1024);
+
+/// The minimum size by which the heap is extended at once.
+const MIN_EXTENSION_SIZE: Bytes = Bytes::new(64 * 1024);
+
+/// The heap is extended in advance when its free memory falls below this.
+///
+/// The frame allocator and the page table mapper allocate memory from the heap while the heap is
+/// extended. These allocations must be served from the remaining memory.
+const LOW_WATERMARK: Bytes = Bytes::new(16 * 1024);
+
+/// The value of [`GrowableHeap::extending`] while no processor extends the heap.
+const NOT_EXTENDING: usize = usize::MAX;
+
 #[global_allocator]
-pub(crate) static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: GrowableHeap = GrowableHeap::new(MAX_EXTENSION_SIZE);
 
 // Using UEFI's `allocate_pages` doesn't work for allocating larger memory. It returns out of
 // resrouces.
@@ -22,10 +59,245 @@ pub(crate) fn init() {
     let e: *const usize = unsafe { &HEAP_END };
     let e = e as usize;
 
-    unsafe { ALLOCATOR.lock().init(s, e - s) }
+    unsafe { ALLOCATOR.lock().initial.init(s, e - s) }
+}
+
+pub(crate) fn stats() -> Stats {
+    ALLOCATOR.lock().stats()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Stats {
+    /// The size of the memory which the heap manages, including the extended one.
+    pub(crate) size: Bytes,
+    pub(crate) used: Bytes,
+}
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} used out of {}.", self.used, self.size)
+    }
+}
+
+/// The kernel heap which maps more frames when it runs out of memory.
+///
+/// The initial region is located in the kernel image so that the heap is available before the
+/// frame allocator is initialized. The extended memory is mapped from [`HEAP_EXTENSION_ADDR`], up
+/// to `max_extension` bytes.
+struct GrowableHeap {
+    heaps: IrqSpinlock<Heaps>,
+
+    /// The CPU number of the processor extending the heap, or [`NOT_EXTENDING`].
+    extending: AtomicUsize,
+
+    max_extension: Bytes,
+
+    /// Whether the extended memory reaches `max_extension`. Allocations fail without extending the
+    /// heap once this becomes `true`.
+    limit_reached: AtomicBool,
+}
+impl GrowableHeap {
+    const fn new(max_extension: Bytes) -> Self {
+        Self {
+            heaps: IrqSpinlock::new(Heaps {
+                initial: Heap::empty(),
+                extension: Heap::empty(),
+            }),
+            extending: AtomicUsize::new(NOT_EXTENDING),
+            max_extension,
+            limit_reached: AtomicBool::new(false),
+        }
+    }
+
+    fn allocate(&self, layout: Layout) -> Option<NonNull<u8>> {
+        // The guard must be dropped before `extend`, which allocates memory.
+        let p = self.lock().allocate(layout);
+
+        let p = p.or_else(|| {
+            if self.limit_reached.load(Ordering::Acquire) {
+                return None;
+            }
+
+            self.extend(Bytes::new(layout.size() + layout.align()));
+            self.lock().allocate(layout)
+        });
+
+        if !self.limit_reached.load(Ordering::Acquire) && self.lock().free() < LOW_WATERMARK {
+            self.extend(MIN_EXTENSION_SIZE);
+        }
+
+        p
+    }
+
+    /// Maps at least `bytes` bytes to the end of the extended memory.
+    ///
+    /// This method does nothing if the running processor holds the frame allocator or the page
+    /// tables, because the allocation is made by them, or if it is already extending the heap. If
+    /// another processor is extending the heap, this method waits for it and then extends the
+    /// heap again.
+    fn extend(&self, bytes: Bytes) {
+        if phys::is_held_by_current_cpu() || paging::is_held_by_current_cpu() {
+            return;
+        }
+
+        if !self.start_extending() {
+            return;
+        }
+
+        let top = self.lock().extension_top();
+
+        let mapped = self.map_extension(top, bytes);
+
+        if mapped.as_usize() > 0 {
+            // SAFETY: The memory `top..top+mapped` is mapped and is not used by anyone.
+            unsafe {
+                self.lock().extend(mapped);
+            }
+        }
+
+        self.extending.store(NOT_EXTENDING, Ordering::Release);
+    }
+
+    /// Maps frames to the pages from `top` so that at least `bytes` bytes are available, within
+    /// `max_extension` bytes from [`HEAP_EXTENSION_ADDR`].
+    ///
+    /// This method returns the number of bytes actually mapped, which may be smaller than `bytes`
+    /// if the frames run out or the limit is reached.
+    fn map_extension(&self, top: usize, bytes: Bytes) -> Bytes {
+        let start = usize::try_from(HEAP_EXTENSION_ADDR.as_u64()).unwrap();
+        let limit = start + self.max_extension.as_usize();
+
+        let bytes = bytes.max(MIN_EXTENSION_SIZE);
+        let end = (top + bytes.as_usize()).min(limit);
+
+        let page_size = usize::try_from(Size4KiB::SIZE).unwrap();
+
+        let mut mapped = Bytes::zero();
+
+        for addr in (top..end).step_by(page_size) {
+            let page = Page::containing_address(VirtAddr::new(u64::try_from(addr).unwrap()));
+
+            let r = paging::map_to_unused(page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+
+            if r.is_err() {
+                break;
+            }
+
+            mapped += page_size;
+        }
+
+        if top + mapped.as_usize() >= limit {
+            self.limit_reached.store(true, Ordering::Release);
+        }
+
+        mapped
+    }
+
+    /// Waits until no other processor extends the heap, and records the running processor as the
+    /// extending one.
+    ///
+    /// This method returns `false` if the running processor is already extending the heap.
+    fn start_extending(&self) -> bool {
+        let cpu = smp::current();
+
+        loop {
+            let r = self.extending.compare_exchange_weak(
+                NOT_EXTENDING,
+                cpu,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            );
+
+            match r {
+                Ok(_) => return true,
+                Err(c) if c == cpu => return false,
+                Err(_) => hint::spin_loop(),
+            }
+        }
+    }
+
+    fn lock(&self) -> IrqSpinlockGuard<'_, Heaps> {
+        self.heaps.lock()
+    }
+}
+unsafe impl GlobalAlloc for GrowableHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.allocate(layout)
+            .map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: The caller must ensure that `ptr` is allocated by this allocator with `layout`.
+        unsafe {
+            self.lock().deallocate(NonNull::new_unchecked(ptr), layout);
+        }
+    }
+}
+
+struct Heaps {
+    initial: Heap,
+    extension: Heap,
+}
+impl Heaps {
+    fn allocate(&mut self, layout: Layout) -> Option<NonNull<u8>> {
+        let p = self.initial.allocate_first_fit(layout);
+        let p = p.or_else(|()| self.extension.allocate_first_fit(layout));
+
+        p.ok()
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must be allocated by this heap with `layout`.
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        let addr = ptr.as_ptr() as usize;
+
+        let heap = if (self.extension.bottom()..self.extension.top()).contains(&addr) {
+            &mut self.extension
+        } else {
+            &mut self.initial
+        };
+
+        // SAFETY: The caller must uphold the safety requirements.
+        unsafe {
+            heap.deallocate(ptr, layout);
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The memory `extension_top()..extension_top()+bytes` must be mapped and unused.
+    unsafe fn extend(&mut self, bytes: Bytes) {
+        // SAFETY: The caller must ensure that the memory is mapped and unused.
+        unsafe {
+            if self.extension.size() == 0 {
+                self.extension.init(self.extension_top(), bytes.as_usize());
+            } else {
+                self.extension.extend(bytes.as_usize());
+            }
+        }
+    }
+
+    fn extension_top(&self) -> usize {
+        if self.extension.size() == 0 {
+            usize::try_from(HEAP_EXTENSION_ADDR.as_u64()).unwrap()
+        } else {
+            self.extension.top()
+        }
+    }
+
+    fn free(&self) -> Bytes {
+        Bytes::new(self.initial.free() + self.extension.free())
+    }
+
+    fn stats(&self) -> Stats {
+        Stats {
+            size: Bytes::new(self.initial.size() + self.extension.size()),
+            used: Bytes::new(self.initial.used() + self.extension.used()),
+        }
+    }
 }
 
 #[alloc_error_handler]
 fn alloc_error(layout: Layout) -> ! {
-    panic!("Allocation failed! {:?}", layout);
+    panic!("Allocation failed! {:?} Heap: {}", layout, stats());
 }
@@ -182,11 +182,6 @@ impl<T: ?Sized> KpBox<T> {
         paging::translate_addr(self.virt).expect("This KpBox is not mapped.")
     }
 
-    #[must_use]
-    pub(crate) fn bytes(&self) -> Bytes {
-        self.bytes
-    }
-
     fn from_bytes(bytes: Bytes) -> Self {
         let virt = super::allocate_pages_for_kernel(bytes.as_num_of_pages())
             .expect("Failed to allocate pages.");
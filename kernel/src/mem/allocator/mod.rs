@@ -1,37 +1,53 @@
 use {
     super::paging,
     core::convert::TryFrom,
-    os_units::NumOfPages,
-    x86_64::{
-        structures::paging::{Page, PageSize, Size4KiB},
-        PhysAddr, VirtAddr,
-    },
+    os_units::{Bytes, NumOfPages},
+    syscalls::{CacheType, DmaConstraints},
+    x86_64::{structures::paging::Size4KiB, PhysAddr, VirtAddr},
 };
 
 pub(crate) mod acpi;
 pub(crate) mod heap;
 pub(crate) mod kpbox;
 pub(crate) mod phys;
+pub(crate) mod slab;
+pub(crate) mod stack;
 pub(crate) mod virt;
+pub(crate) mod zero;
 
-pub(crate) fn allocate_pages_for_user(num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
+pub(crate) fn allocate_pages_for_kernel(num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
     let phys_addr = allocate_phys(num_of_pages)?;
 
-    let virt_addr = super::map_pages_for_user(phys_addr, num_of_pages.as_bytes());
+    let virt_addr =
+        super::map_pages_for_kernel(phys_addr, num_of_pages.as_bytes(), CacheType::WriteBack);
 
     Some(virt_addr)
 }
 
-pub(crate) fn allocate_pages_for_kernel(num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
-    let phys_addr = allocate_phys(num_of_pages)?;
+/// Allocates physically contiguous memory satisfying `constraints`.
+///
+/// This function returns [`None`] if the alignment is not a power of two, or there is no memory
+/// satisfying the constraints.
+pub(crate) fn allocate_dma(bytes: Bytes, constraints: DmaConstraints) -> Option<PhysAddr> {
+    let alignment = Bytes::new(usize::try_from(constraints.alignment).ok()?);
 
-    let virt_addr = super::map_pages_for_kernel(phys_addr, num_of_pages.as_bytes());
+    if !alignment.as_usize().is_power_of_two() || bytes.as_usize() == 0 {
+        return None;
+    }
 
-    Some(virt_addr)
+    let num_of_pages = bytes.as_num_of_pages();
+
+    phys::alloc_for_dma(num_of_pages, alignment, constraints.below_4gib)
+}
+
+/// Allocates physically contiguous memory in the first megabyte, which a processor in the real
+/// mode can access.
+pub(crate) fn allocate_below_1mib(num_of_pages: NumOfPages<Size4KiB>) -> Option<PhysAddr> {
+    phys::alloc_below_1mib(num_of_pages)
 }
 
 pub(crate) fn deallocate_pages(virt: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
-    deallocate_phys(virt);
+    deallocate_phys(virt, num_of_pages);
     deallocate_virt(virt, num_of_pages);
 }
 
@@ -39,15 +55,11 @@ fn allocate_phys(num_of_pages: NumOfPages<Size4KiB>) -> Option<PhysAddr> {
     phys::alloc(num_of_pages)
 }
 
-fn deallocate_phys(virt: VirtAddr) {
+fn deallocate_phys(virt: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
     let phys = paging::translate_addr(virt).unwrap();
-    phys::free(phys);
+    phys::free(phys, num_of_pages);
 }
 
 fn deallocate_virt(virt: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
-    for i in 0..u64::try_from(num_of_pages.as_usize()).unwrap() {
-        let page = Page::<Size4KiB>::from_start_address(virt + Size4KiB::SIZE * i).unwrap();
-
-        paging::unmap(page).unwrap();
-    }
+    super::unmap_pages(virt, num_of_pages.as_bytes());
 }
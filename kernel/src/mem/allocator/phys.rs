@@ -1,23 +1,27 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use {
-    boot_info::mem::MemoryDescriptor,
+    crate::sync::IrqSpinlock,
+    boot_info::mem::{MemoryDescriptor, MemoryType},
     core::ops::DerefMut,
     frame_manager::FrameManager,
-    os_units::NumOfPages,
-    spinning_top::Spinlock,
+    os_units::{Bytes, NumOfPages},
     x86_64::{
-        structures::paging::{FrameAllocator, FrameDeallocator, Size4KiB},
+        structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
         PhysAddr,
     },
 };
 
-static FRAME_MANAGER: Spinlock<FrameManager> = Spinlock::new(FrameManager::new());
+static FRAME_MANAGER: IrqSpinlock<FrameManager> = IrqSpinlock::new(FrameManager::new());
 
 pub(crate) fn init(mem_map: &[MemoryDescriptor]) {
     FRAME_MANAGER.lock().init(mem_map);
 }
 
+pub(crate) fn reclaim(ty: MemoryType) {
+    lock_manager().reclaim(ty);
+}
+
 pub(in super::super) fn allocator(
 ) -> impl DerefMut<Target = impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>> {
     lock_manager()
@@ -27,12 +31,48 @@ pub(super) fn alloc(num_of_pages: NumOfPages<Size4KiB>) -> Option<PhysAddr> {
     lock_manager().deref_mut().alloc(num_of_pages)
 }
 
-pub(super) fn free(addr: PhysAddr) {
-    lock_manager().deref_mut().free(addr);
+pub(super) fn alloc_for_dma(
+    num_of_pages: NumOfPages<Size4KiB>,
+    alignment: Bytes,
+    below_4gib: bool,
+) -> Option<PhysAddr> {
+    let mut manager = lock_manager();
+
+    if below_4gib {
+        manager.alloc_below(num_of_pages, alignment, PhysAddr::new(0x1_0000_0000))
+    } else {
+        manager.alloc_aligned(num_of_pages, alignment)
+    }
+}
+
+/// Allocates frames in the first megabyte, which a processor in the real mode can access.
+pub(super) fn alloc_below_1mib(num_of_pages: NumOfPages<Size4KiB>) -> Option<PhysAddr> {
+    lock_manager().alloc_below(num_of_pages, Bytes::new(0x1000), PhysAddr::new(0x10_0000))
+}
+
+pub(in super::super) fn free(addr: PhysAddr, num_of_pages: NumOfPages<Size4KiB>) {
+    lock_manager()
+        .deref_mut()
+        .free(addr, num_of_pages)
+        .expect("Failed to free frames.");
+}
+
+/// Adds a reference to the frame so that it is freed only after all references are dropped.
+pub(in super::super) fn add_reference(frame: PhysFrame) {
+    lock_manager()
+        .add_reference(frame.start_address())
+        .expect("Failed to add a reference to a frame.");
+}
+
+pub(in super::super) fn reference_count(frame: PhysFrame) -> usize {
+    lock_manager().reference_count(frame.start_address())
+}
+
+/// Returns `true` if the running processor is using the frame manager.
+pub(super) fn is_held_by_current_cpu() -> bool {
+    FRAME_MANAGER.is_held_by_current_cpu()
 }
 
 fn lock_manager() -> impl DerefMut<Target = FrameManager> {
-    FRAME_MANAGER
-        .try_lock()
-        .expect("Failed to lock the frame manager.")
+    FRAME_MANAGER.lock()
 }
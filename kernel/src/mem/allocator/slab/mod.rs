@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    crate::sync::{IrqSpinlock, IrqSpinlockGuard},
+    core::{
+        alloc::Layout,
+        convert::TryFrom,
+        fmt,
+        marker::PhantomData,
+        mem,
+        ops::{Deref, DerefMut},
+        ptr::{self, NonNull},
+    },
+    os_units::{Bytes, NumOfPages},
+    x86_64::structures::paging::{PageSize, Size4KiB},
+};
+
+pub(crate) mod queue;
+
+/// The byte written to freed objects in debug builds.
+///
+/// The object is checked on the next allocation. Any other byte means that someone wrote to it
+/// after it was freed.
+const POISON: u8 = 0x6b;
+
+/// The minimum number of objects in a slab.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+
+/// A cache of objects with the same layout.
+///
+/// A slab is a set of pages divided into objects. Freed objects are kept in the free list of the
+/// cache, and are reused by the next allocation without going through the kernel heap. Slabs are
+/// never returned to the frame allocator.
+pub(crate) struct Cache {
+    name: &'static str,
+    layout: Layout,
+    inner: IrqSpinlock<Inner>,
+}
+impl Cache {
+    pub(crate) const fn new(name: &'static str, layout: Layout) -> Self {
+        Self {
+            name,
+            layout,
+            inner: IrqSpinlock::new(Inner {
+                free: None,
+                stats: Stats {
+                    slabs: 0,
+                    in_use: 0,
+                    allocations: 0,
+                    frees: 0,
+                },
+            }),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> Stats {
+        self.lock().stats
+    }
+
+    fn allocate(&self) -> NonNull<u8> {
+        let mut inner = self.lock();
+
+        if inner.free.is_none() {
+            self.grow(&mut inner);
+        }
+
+        let object = inner.free.expect("The free list is empty.");
+
+        // SAFETY: The object is in the free list, so nobody else uses it.
+        inner.free = unsafe { object.as_ref().next };
+
+        let object = object.cast();
+
+        if cfg!(debug_assertions) {
+            self.check_poison(object);
+        }
+
+        inner.stats.in_use += 1;
+        inner.stats.allocations += 1;
+
+        object
+    }
+
+    /// # Safety
+    ///
+    /// `object` must be allocated by this cache and must not be used after calling this method.
+    unsafe fn deallocate(&self, object: NonNull<u8>) {
+        if cfg!(debug_assertions) {
+            // SAFETY: The caller must ensure that nobody uses the object.
+            unsafe {
+                ptr::write_bytes(object.as_ptr(), POISON, self.object_size());
+            }
+        }
+
+        let mut inner = self.lock();
+
+        let object = object.cast::<FreeObject>();
+
+        // SAFETY: The object is large enough and aligned for `FreeObject`.
+        unsafe {
+            object.as_ptr().write(FreeObject { next: inner.free });
+        }
+
+        inner.free = Some(object);
+
+        inner.stats.in_use -= 1;
+        inner.stats.frees += 1;
+    }
+
+    /// Allocates a new slab and adds the objects in it to the free list.
+    fn grow(&self, inner: &mut Inner) {
+        let num_of_pages = self.num_of_pages_per_slab();
+
+        let slab = super::allocate_pages_for_kernel(num_of_pages);
+        let slab = slab.unwrap_or_else(|| panic!("Failed to allocate a slab for {}.", self.name));
+
+        let size = self.object_size();
+        let num_of_objects = num_of_pages.as_bytes().as_usize() / size;
+
+        for i in (0..num_of_objects).rev() {
+            let object: *mut FreeObject = (slab + i * size).as_mut_ptr();
+
+            if cfg!(debug_assertions) {
+                // SAFETY: The object is in the newly allocated slab.
+                unsafe {
+                    ptr::write_bytes(object.cast::<u8>(), POISON, size);
+                }
+            }
+
+            let object = NonNull::new(object).expect("The slab is at null.");
+
+            // SAFETY: The object is in the newly allocated slab, and is aligned.
+            unsafe {
+                object.as_ptr().write(FreeObject { next: inner.free });
+            }
+
+            inner.free = Some(object);
+        }
+
+        inner.stats.slabs += 1;
+    }
+
+    fn check_poison(&self, object: NonNull<u8>) {
+        let header = mem::size_of::<FreeObject>();
+
+        // SAFETY: The object is not used by anyone, and `object_size` bytes are allocated for it.
+        let bytes = unsafe { core::slice::from_raw_parts(object.as_ptr(), self.object_size()) };
+
+        assert!(
+            bytes[header..].iter().all(|b| *b == POISON),
+            "An object in the {} cache was modified after it was freed.",
+            self.name
+        );
+    }
+
+    fn object_size(&self) -> usize {
+        let layout = self.layout.align_to(mem::align_of::<FreeObject>());
+        let layout = layout.expect("Invalid layout.").pad_to_align();
+
+        layout.size().max(mem::size_of::<FreeObject>())
+    }
+
+    fn num_of_pages_per_slab(&self) -> NumOfPages<Size4KiB> {
+        let page_size = usize::try_from(Size4KiB::SIZE).unwrap();
+
+        assert!(
+            self.layout.align() <= page_size,
+            "Objects in the {} cache require too large alignment.",
+            self.name
+        );
+
+        Bytes::new(self.object_size() * MIN_OBJECTS_PER_SLAB).as_num_of_pages()
+    }
+
+    fn lock(&self) -> IrqSpinlockGuard<'_, Inner> {
+        self.inner.lock()
+    }
+}
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("name", &self.name)
+            .field("layout", &self.layout)
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The usage counters of a [`Cache`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Stats {
+    /// The number of slabs allocated for the cache.
+    pub(crate) slabs: usize,
+    /// The number of objects currently used.
+    pub(crate) in_use: usize,
+    /// The total number of allocations.
+    pub(crate) allocations: usize,
+    /// The total number of deallocations.
+    pub(crate) frees: usize,
+}
+
+/// A pointer type which places `T` in a slab of a [`Cache`].
+pub(crate) struct SlabBox<T> {
+    ptr: NonNull<T>,
+    cache: &'static Cache,
+    _marker: PhantomData<T>,
+}
+impl<T> SlabBox<T> {
+    /// # Panics
+    ///
+    /// This method panics if the layout of `T` differs from the one of `cache`.
+    pub(crate) fn new(x: T, cache: &'static Cache) -> Self {
+        assert_eq!(
+            cache.layout,
+            Layout::new::<T>(),
+            "The layout of the {} cache does not match the object.",
+            cache.name
+        );
+
+        let ptr = cache.allocate().cast::<T>();
+
+        // SAFETY: The memory is allocated for `T`.
+        unsafe {
+            ptr.as_ptr().write(x);
+        }
+
+        Self {
+            ptr,
+            cache,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Moves the object out of the slab, and frees the memory of it.
+    pub(crate) fn into_inner(b: Self) -> T {
+        let b = mem::ManuallyDrop::new(b);
+
+        // SAFETY: The object is initialized. It is never used after this because `b` is not
+        // dropped.
+        unsafe {
+            let x = b.ptr.as_ptr().read();
+            b.cache.deallocate(b.ptr.cast());
+            x
+        }
+    }
+}
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: The object is initialized and owned by this box.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: The object is initialized and owned by this box.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+impl<T: fmt::Debug> fmt::Debug for SlabBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        // SAFETY: The object is initialized and is never used after this.
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.deallocate(self.ptr.cast());
+        }
+    }
+}
+// SAFETY: `SlabBox` owns `T` as `Box` does.
+unsafe impl<T: Send> Send for SlabBox<T> {}
+// SAFETY: `SlabBox` owns `T` as `Box` does.
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+struct Inner {
+    free: Option<NonNull<FreeObject>>,
+    stats: Stats,
+}
+// SAFETY: The objects in the free list are not used by anyone, and are accessed only with the
+// lock held.
+unsafe impl Send for Inner {}
+
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
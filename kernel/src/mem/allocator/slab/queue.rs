@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    super::{Cache, SlabBox},
+    core::{fmt, ptr::NonNull},
+};
+
+/// A FIFO queue whose nodes are allocated from a slab [`Cache`].
+///
+/// The cache must be created with the layout of [`Node<T>`].
+pub(crate) struct Queue<T: 'static> {
+    head: Option<SlabBox<Node<T>>>,
+    tail: Option<NonNull<Node<T>>>,
+    cache: &'static Cache,
+}
+impl<T> Queue<T> {
+    pub(crate) const fn new(cache: &'static Cache) -> Self {
+        Self {
+            head: None,
+            tail: None,
+            cache,
+        }
+    }
+
+    pub(crate) fn push_back(&mut self, value: T) {
+        let mut node = SlabBox::new(Node { value, next: None }, self.cache);
+        let ptr = NonNull::from(&mut *node);
+
+        match self.tail {
+            // SAFETY: `tail` points to the last node, which is owned by this queue.
+            Some(mut tail) => unsafe { tail.as_mut().next = Some(node) },
+            None => self.head = Some(node),
+        }
+
+        self.tail = Some(ptr);
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<T> {
+        let mut head = self.head.take()?;
+
+        self.head = head.next.take();
+
+        if self.head.is_none() {
+            self.tail = None;
+        }
+
+        Some(SlabBox::into_inner(head).value)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &T> {
+        let mut node = self.head.as_deref();
+
+        core::iter::from_fn(move || {
+            let n = node?;
+            node = n.next.as_deref();
+            Some(&n.value)
+        })
+    }
+}
+impl<T: fmt::Debug> fmt::Debug for Queue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // Drop the nodes one by one. Dropping the head recursively drops all nodes otherwise.
+        while self.pop_front().is_some() {}
+    }
+}
+// SAFETY: The queue owns all nodes. `tail` points to one of them.
+unsafe impl<T: Send> Send for Queue<T> {}
+
+pub(crate) struct Node<T: 'static> {
+    value: T,
+    next: Option<SlabBox<Node<T>>>,
+}
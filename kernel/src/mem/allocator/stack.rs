@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    super::{phys, virt},
+    crate::mem::paging,
+    core::convert::TryFrom,
+    os_units::NumOfPages,
+    x86_64::{
+        structures::paging::{page::PageRange, FrameDeallocator, Page, PageTableFlags, Size4KiB},
+        VirtAddr,
+    },
+};
+
+/// A kernel stack with an unmapped guard page below it.
+///
+/// Overflowing the stack causes a fault instead of silently overwriting the memory below it.
+#[derive(Debug)]
+pub(crate) struct KernelStack {
+    guard: Page,
+    num_of_pages: NumOfPages<Size4KiB>,
+}
+impl KernelStack {
+    /// # Panics
+    ///
+    /// This method panics if there is no free virtual memory or frames for the stack.
+    pub(crate) fn new(num_of_pages: NumOfPages<Size4KiB>) -> Self {
+        let virt = virt::allocate(num_of_pages + 1);
+        let virt = virt.expect("No free virtual memory for a kernel stack.");
+
+        // The guard page is reserved but never mapped.
+        let guard = Page::containing_address(virt);
+
+        let stack = Self {
+            guard,
+            num_of_pages,
+        };
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let r = paging::map_range_to_unused_phys_range(stack.pages(), flags);
+        r.expect("Failed to map a kernel stack.");
+
+        stack
+    }
+
+    /// Returns the address next to the highest byte of the stack.
+    pub(crate) fn bottom_addr(&self) -> VirtAddr {
+        self.pages().end.start_address()
+    }
+
+    /// Returns `true` if `addr` is in the guard page of the stack.
+    pub(crate) fn guard_contains(&self, addr: VirtAddr) -> bool {
+        Page::containing_address(addr) == self.guard
+    }
+
+    fn pages(&self) -> PageRange {
+        let start = self.guard + 1;
+        let end = start + u64::try_from(self.num_of_pages.as_usize()).unwrap();
+
+        PageRange { start, end }
+    }
+}
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        for page in self.pages() {
+            let frame = paging::unmap(page).expect("Failed to unmap a kernel stack.");
+
+            // SAFETY: The frame was allocated for the stack, and is no longer mapped.
+            unsafe {
+                phys::allocator().deallocate_frame(frame);
+            }
+        }
+
+        virt::deallocate_unmapped(self.guard.start_address(), self.num_of_pages + 1);
+    }
+}
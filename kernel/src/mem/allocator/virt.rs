@@ -1,39 +1,115 @@
 use {
-    crate::mem::paging,
+    crate::{mem::tlb, sync::IrqSpinlock},
+    alloc::vec::Vec,
+    conquer_once::spin::Lazy,
+    core::ops::DerefMut,
     os_units::NumOfPages,
+    predefined_mmap::STACK_BASE,
+    range_allocator::RangeAllocator,
     x86_64::{
-        structures::paging::{page::PageRange, Size4KiB},
+        structures::paging::{PageSize, Size4KiB},
         VirtAddr,
     },
 };
 
-pub(crate) fn search_free_addr_from(
-    num_pages: NumOfPages<Size4KiB>,
-    region: PageRange,
+/// The end of the region where the kernel maps pages dynamically.
+const KERNEL_REGION_END: VirtAddr = VirtAddr::new_truncate(0xffff_ffff_ffff_f000);
+
+static KERNEL_REGION: Lazy<IrqSpinlock<KernelRegion>> = Lazy::new(|| {
+    let bytes = KERNEL_REGION_END - STACK_BASE;
+    let num_of_pages = NumOfPages::new(usize::try_from(bytes / Size4KiB::SIZE).unwrap());
+
+    IrqSpinlock::new(KernelRegion {
+        allocator: RangeAllocator::with_range(STACK_BASE, num_of_pages),
+        unmapped: Vec::new(),
+    })
+});
+
+/// Reserves `num_of_pages` pages in the region where the kernel maps pages dynamically.
+pub(crate) fn allocate(num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
+    lock().allocate(num_of_pages)
+}
+
+/// Reserves `num_of_pages` pages whose start address is aligned to `alignment` bytes.
+pub(crate) fn allocate_aligned(
+    num_of_pages: NumOfPages<Size4KiB>,
+    alignment: u64,
 ) -> Option<VirtAddr> {
-    let mut cnt = 0;
-    let mut start = None;
-    for page in region {
-        let addr = page.start_address();
-        if available(addr) {
-            if start.is_none() {
-                start = Some(addr);
-            }
+    lock().allocate_aligned(num_of_pages, alignment)
+}
 
-            cnt += 1;
+/// Returns the pages reserved by [`allocate`] or [`allocate_aligned`].
+///
+/// The pages must have never been mapped. Use [`deallocate_unmapped`] for the pages which were.
+///
+/// # Panics
+///
+/// This function panics if the pages are not reserved.
+pub(crate) fn deallocate(start: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
+    let r = lock().allocator.deallocate(start, num_of_pages);
+    r.expect("Failed to free the virtual memory.");
+}
 
-            if cnt >= num_pages.as_usize() {
-                return start;
-            }
-        } else {
-            cnt = 0;
-            start = None;
-        }
+/// Returns the pages which were mapped and are unmapped.
+///
+/// The other processors may still cache the translations of the pages, so they are reused after
+/// every processor flushes its TLB.
+pub(crate) fn deallocate_unmapped(start: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
+    let generation = tlb::advance();
+
+    lock().unmapped.push(Unmapped {
+        start,
+        num_of_pages,
+        generation,
+    });
+}
+
+fn lock() -> impl DerefMut<Target = KernelRegion> {
+    KERNEL_REGION.lock()
+}
+
+struct KernelRegion {
+    allocator: RangeAllocator,
+
+    /// The pages waiting for the TLBs of all processors to be flushed.
+    unmapped: Vec<Unmapped>,
+}
+impl KernelRegion {
+    fn allocate(&mut self, num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
+        self.reclaim();
+        self.allocator.allocate(num_of_pages)
+    }
+
+    fn allocate_aligned(
+        &mut self,
+        num_of_pages: NumOfPages<Size4KiB>,
+        alignment: u64,
+    ) -> Option<VirtAddr> {
+        self.reclaim();
+        self.allocator.allocate_aligned(num_of_pages, alignment)
     }
 
-    None
+    /// Returns the unmapped pages which no processor translates anymore to the allocator.
+    fn reclaim(&mut self) {
+        let allocator = &mut self.allocator;
+
+        self.unmapped.retain(|u| {
+            let flushed = tlb::is_flushed_everywhere(u.generation);
+
+            if flushed {
+                let r = allocator.deallocate(u.start, u.num_of_pages);
+                r.expect("Failed to free the virtual memory.");
+            }
+
+            !flushed
+        });
+    }
 }
 
-fn available(addr: VirtAddr) -> bool {
-    paging::translate_addr(addr).is_none() && !addr.is_null()
+struct Unmapped {
+    start: VirtAddr,
+    num_of_pages: NumOfPages<Size4KiB>,
+
+    /// The generation at which every processor must flush its TLB before the pages are reused.
+    generation: u64,
 }
@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    super::phys,
+    crate::{mem, sync::IrqSpinlock},
+    alloc::vec::Vec,
+    core::{convert::TryFrom, ops::DerefMut, ptr},
+    os_units::Bytes,
+    syscalls::CacheType,
+    x86_64::structures::paging::{FrameAllocator, PageSize, PhysFrame, Size4KiB},
+};
+
+/// The maximum number of zeroed frames kept in the pool.
+const POOL_SIZE: usize = 64;
+
+static POOL: IrqSpinlock<Vec<PhysFrame>> = IrqSpinlock::new(Vec::new());
+
+/// Allocates a frame filled with zeros.
+///
+/// This function takes a frame from the pool of zeroed frames if there is one. Otherwise it
+/// clears a new frame.
+pub(crate) fn allocate_frame() -> Option<PhysFrame> {
+    let frame = lock_pool().pop();
+
+    frame.or_else(|| {
+        let frame = phys::allocator().allocate_frame()?;
+        clear(frame);
+        Some(frame)
+    })
+}
+
+/// Clears a free frame and adds it to the pool of zeroed frames.
+///
+/// The idle process calls this function when nothing else is runnable. This function returns
+/// `false` if the pool is full or there is no free frame.
+pub(crate) fn fill_pool() -> bool {
+    if lock_pool().len() >= POOL_SIZE {
+        return false;
+    }
+
+    let frame = phys::allocator().allocate_frame();
+
+    if let Some(frame) = frame {
+        clear(frame);
+        lock_pool().push(frame);
+    }
+
+    frame.is_some()
+}
+
+fn clear(frame: PhysFrame) {
+    let bytes = Bytes::new(usize::try_from(Size4KiB::SIZE).unwrap());
+
+    let virt = mem::map_pages_for_kernel(frame.start_address(), bytes, CacheType::WriteBack);
+
+    // SAFETY: `virt` is mapped to `frame`, which nobody uses.
+    unsafe {
+        ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, bytes.as_usize());
+    }
+
+    mem::unmap_pages(virt, bytes);
+}
+
+fn lock_pool() -> impl DerefMut<Target = Vec<PhysFrame>> {
+    POOL.lock()
+}
@@ -1,109 +1,430 @@
 use {
-    super::paging,
+    super::{
+        address_space::{self, AddressSpace, OverlapError},
+        allocator::phys,
+        paging,
+    },
+    crate::{fs, sync::IrqSpinlock},
     aligned_ptr::ptr,
-    elfloader::{
-        ElfBinary, ElfLoader, ElfLoaderErr, Flags, LoadableHeaders, ProgramHeader, Rela, VAddr, P64,
+    alloc::{collections::BTreeMap, vec::Vec},
+    conquer_once::spin::Lazy,
+    core::{
+        arch::x86_64::_rdtsc,
+        convert::{TryFrom, TryInto},
+        fmt,
+        ops::Range,
     },
+    elf_layout::{Layout, Region, Tls},
+    elfloader::{ElfBinary, ElfLoaderErr},
+    os_units::{Bytes, NumOfPages},
     x86_64::{
-        structures::paging::{
-            mapper::{FlagUpdateError, MapToError},
-            page::PageRange,
-            Page, PageSize, PageTableFlags, Size4KiB,
-        },
+        instructions::random::RdRand,
+        structures::paging::{page::PageRange, Page, PageTableFlags, PhysFrame, Size4KiB},
         VirtAddr,
     },
 };
 
-pub(crate) unsafe fn map_to_current_address_space(binary: &[u8]) -> Result<VirtAddr, ElfLoaderErr> {
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Error {
+    /// The binary is not a valid ELF file, or the segments are not loadable to the user space.
+    Layout(elf_layout::Error),
+    /// A segment overlaps with the memory which the address space already has.
+    Overlap(OverlapError),
+    Load(ElfLoaderErr),
+    /// There is no free range large enough for a position-independent object.
+    NoSpace,
+    /// The shared object is not in the initrd.
+    LibraryNotFound(&'static str),
+    /// The shared object has the thread-local storage, which is not supported.
+    TlsInLibrary(&'static str),
+}
+impl From<elf_layout::Error> for Error {
+    fn from(e: elf_layout::Error) -> Self {
+        Self::Layout(e)
+    }
+}
+impl From<OverlapError> for Error {
+    fn from(e: OverlapError) -> Self {
+        Self::Overlap(e)
+    }
+}
+impl From<ElfLoaderErr> for Error {
+    fn from(e: ElfLoaderErr) -> Self {
+        Self::Load(e)
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Layout(e) => write!(f, "{}", e),
+            Self::Overlap(OverlapError(addr)) => {
+                write!(
+                    f,
+                    "The segment at {:?} overlaps with existing memory.",
+                    addr
+                )
+            }
+            Self::Load(e) => write!(f, "{}", e),
+            Self::NoSpace => write!(f, "No space to load the binary."),
+            Self::LibraryNotFound(name) => write!(f, "The shared object {} is not found.", name),
+            Self::TlsInLibrary(name) => write!(
+                f,
+                "The shared object {} has the thread-local storage, which is not supported.",
+                name
+            ),
+        }
+    }
+}
+
+/// The state which the first thread of a loaded binary starts with.
+pub(crate) struct Image {
+    pub(crate) entry: VirtAddr,
+    /// The value of the FS base.
+    pub(crate) thread_pointer: VirtAddr,
+}
+
+/// Maps `binary` and the shared objects which it depends on to the current address space, and
+/// records the mapped segments in `address_space`.
+///
+/// The part of each segment which does not exist in the file, such as `.bss`, is filled with
+/// zeros.
+///
+/// Position-independent objects are loaded at free ranges of `address_space`, and their
+/// relocations are applied. The shared objects named by the `DT_NEEDED` entries are read from the
+/// initrd. A symbol is looked up in `binary` first, and then in the shared objects in the
+/// breadth-first order of the dependencies. The read-only pages of the shared objects are shared
+/// between processes.
+///
+/// This function also allocates a thread-local storage block for the first thread. The block only
+/// has the thread control block if the binary does not have the thread-local storage.
+///
+/// # Errors
+///
+/// This function returns an error if any object is not a valid ELF file or is not found, `binary`
+/// depends on shared objects but is not position-independent, any segment overlaps with the
+/// kernel half, the other segments, or the existing areas of `address_space`, any relocation
+/// cannot be applied, or a shared object has the thread-local storage.
+///
+/// # Safety
+///
+/// The active page tables must belong to `address_space`, either as the address space of the
+/// running process or through [`paging::switch_pml4_do`]. The areas of `address_space` must not
+/// overlap with each other.
+pub(crate) unsafe fn map_to_current_address_space(
+    binary: &'static [u8],
+    address_space: &mut AddressSpace,
+) -> Result<Image, Error> {
     let elf = ElfBinary::new(binary)?;
 
-    elf.load(&mut Loader)?;
+    // The relocations of non-position-independent executables are not applied.
+    if !elf_layout::needed(binary)?.is_empty() && elf_layout::relocatable_range(binary)?.is_none() {
+        return Err(elf_layout::Error::NotPositionIndependent.into());
+    }
+
+    let objects = load_objects(binary, address_space)?;
+
+    // SAFETY: The objects are mapped to the current address space just now, and they are not
+    // protected yet.
+    unsafe { apply_relocations(&objects)? };
 
-    Ok(VirtAddr::new(elf.entry_point()))
+    for o in &objects {
+        o.protect();
+    }
+
+    let tls = objects[0].layout.tls.unwrap_or(Tls {
+        start: 0,
+        file_size: 0,
+        mem_size: 0,
+        align: 1,
+    });
+    let thread_pointer = allocate_tls_block(&tls, address_space)?;
+
+    Ok(Image {
+        entry: VirtAddr::new(objects[0].layout.base.wrapping_add(elf.entry_point())),
+        thread_pointer,
+    })
 }
 
-struct Loader;
-impl Loader {
-    fn allocate_for_header(header: ProgramHeader<'_>) -> Result<(), MapToError<Size4KiB>> {
-        let page_range = Self::page_range_from_header(header);
+/// Maps `binary` and the shared objects which it depends on, and returns them in the
+/// breadth-first order of the dependencies.
+fn load_objects(
+    binary: &'static [u8],
+    address_space: &mut AddressSpace,
+) -> Result<Vec<Object>, Error> {
+    let mut objects = alloc::vec![Object::load(None, binary, address_space)?];
 
-        let flags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let mut i = 0;
+    while i < objects.len() {
+        for name in elf_layout::needed(objects[i].binary)? {
+            if objects.iter().all(|o| o.name != Some(name)) {
+                let library = fs::find(name).ok_or(Error::LibraryNotFound(name))?;
+                let library = Object::load(Some(name), library.content(), address_space)?;
 
-        paging::map_range_to_unused_phys_range(page_range, flags)
+                if library.layout.tls.is_some() {
+                    return Err(Error::TlsInLibrary(name));
+                }
+
+                objects.push(library);
+            }
+        }
+
+        i += 1;
     }
 
-    fn page_range_from_header<S: PageSize>(header: ProgramHeader<'_>) -> PageRange<S> {
-        Self::page_range_from_vaddr_and_len(
-            header.virtual_addr(),
-            header.mem_size().try_into().unwrap(),
-        )
+    Ok(objects)
+}
+
+/// Applies the relocations of `objects`, looking up a symbol in them in order.
+///
+/// # Safety
+///
+/// `objects` must be mapped to the current address space, and their writable segments must be
+/// writable.
+unsafe fn apply_relocations(objects: &[Object]) -> Result<(), Error> {
+    let resolve = |name: &str| objects.iter().find_map(|o| o.symbol(name));
+
+    for o in objects {
+        for r in o.layout.relocations(o.binary, resolve)? {
+            // SAFETY: `elf_layout` ensures that the address is inside a writable segment. The
+            // caller ensures that it is mapped and writable.
+            unsafe { core::ptr::write_unaligned(VirtAddr::new(r.addr).as_mut_ptr(), r.value) }
+        }
     }
 
-    fn page_range_from_vaddr_and_len<S: PageSize>(base: VAddr, len: usize) -> PageRange<S> {
-        let start = VirtAddr::new(base);
+    Ok(())
+}
 
-        let end = start + len;
-        let end = end.align_up(S::SIZE);
+/// An ELF object mapped to the current address space.
+struct Object {
+    /// The name of the shared object, or [`None`] for the executable.
+    name: Option<&'static str>,
+    binary: &'static [u8],
+    layout: Layout,
+}
+impl Object {
+    /// Maps the segments of `binary` and copies the file data to them.
+    ///
+    /// The pages are writable until [`Object::protect`] is called, except the read-only pages
+    /// shared with the other processes.
+    fn load(
+        name: Option<&'static str>,
+        binary: &'static [u8],
+        address_space: &mut AddressSpace,
+    ) -> Result<Self, Error> {
+        let user = address_space::USER_START.as_u64()..address_space::USER_END.as_u64();
 
-        let start = Page::containing_address(start);
-        let end = Page::containing_address(end);
+        let base = match elf_layout::relocatable_range(binary)? {
+            Some(range) => choose_base(&range, address_space)?,
+            None => 0,
+        };
 
-        PageRange { start, end }
-    }
+        let object = Self {
+            name,
+            binary,
+            layout: elf_layout::layout(binary, base, user)?,
+        };
 
-    unsafe fn update_flags(page_range: PageRange, flags: Flags) -> Result<(), FlagUpdateError> {
-        unsafe {
-            paging::update_flags_for_range(page_range, Self::elf_flags_to_page_table_flags(flags))
+        for region in &object.layout.regions {
+            object.map_region(region, address_space)?;
         }
+
+        Ok(object)
     }
 
-    fn elf_flags_to_page_table_flags(flags: Flags) -> PageTableFlags {
-        let mut page_table_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    fn map_region(&self, region: &Region, address_space: &mut AddressSpace) -> Result<(), Error> {
+        if let Some(frames) = self.shared_key(region).and_then(shared_frames) {
+            // `protect` sets the permissions of the region.
+            address_space.map_shared(page_range(region), &frames)?;
 
-        if flags.is_write() {
-            page_table_flags |= PageTableFlags::WRITABLE;
+            return Ok(());
         }
 
-        if !flags.is_execute() {
-            page_table_flags |= PageTableFlags::NO_EXECUTE;
-        }
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
 
-        page_table_flags
-    }
-}
-impl ElfLoader for Loader {
-    fn allocate(&mut self, load_headers: LoadableHeaders<'_, '_>) -> Result<(), ElfLoaderErr> {
-        for header in load_headers {
-            Self::allocate_for_header(header).expect("Failed to allocate memory.");
+        address_space.map_anonymous_eagerly(page_range(region), flags)?;
+
+        // The frames are already filled with zeros.
+        for d in self.layout.file_data(region) {
+            let offset = usize::try_from(d.offset).unwrap();
+            let size = usize::try_from(d.size).unwrap();
+
+            // SAFETY: The region is mapped just now, and `elf_layout` ensures that the data is
+            // inside the binary and the region.
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    self.binary[offset..].as_ptr(),
+                    VirtAddr::new(d.addr).as_mut_ptr(),
+                    size,
+                );
+            }
         }
 
         Ok(())
     }
 
-    /// # Safety
-    ///
-    /// **This method is actually an unsafe one.**
-    ///
-    /// The caller must ensure that the addresses `base..(base + region.len())` must be allocated.
-    fn load(&mut self, flags: Flags, base: VAddr, region: &[u8]) -> Result<(), ElfLoaderErr> {
-        let base = VirtAddr::new(base);
+    /// Sets the permissions of the regions, and registers the read-only pages of a shared object
+    /// so that the other processes share them.
+    fn protect(&self) {
+        for region in &self.layout.regions {
+            // SAFETY: The pages belong to the user space and nobody references them yet.
+            unsafe {
+                paging::update_flags_for_range(page_range(region), region_flags(region))
+                    .expect("Failed to update flags.");
+            }
 
-        // SAFETY: The caller ensures that the addresses `base..(base+region.len())` are allocated.
-        unsafe {
-            ptr::copy_nonoverlapping(region.as_ptr(), base.as_mut_ptr(), region.len());
+            if let Some(key) = self.shared_key(region) {
+                share_frames(key, page_range(region));
+            }
         }
+    }
+
+    /// Returns the run-time address of the global symbol `name` which this object defines.
+    fn symbol(&self, name: &str) -> Option<u64> {
+        let addr = elf_layout::symbol(self.binary, name).ok().flatten()?;
+
+        Some(self.layout.base.wrapping_add(addr))
+    }
+
+    /// Returns the key of [`SHARED_FRAMES`] if `region` is read-only and belongs to a shared
+    /// object.
+    fn shared_key(&self, region: &Region) -> Option<SharedKey> {
+        let name = self.name?;
+
+        (!region.writable).then(|| (name, region.start - self.layout.base))
+    }
+}
+
+/// The name of a shared object and the link-time address of its pages.
+type SharedKey = (&'static str, u64);
+
+/// The frames of the read-only pages of shared objects.
+///
+/// The frames are never freed as this map holds a reference to each of them.
+static SHARED_FRAMES: Lazy<IrqSpinlock<BTreeMap<SharedKey, Vec<PhysFrame>>>> =
+    Lazy::new(|| IrqSpinlock::new(BTreeMap::new()));
+
+fn shared_frames(key: SharedKey) -> Option<Vec<PhysFrame>> {
+    SHARED_FRAMES.lock().get(&key).cloned()
+}
+
+fn share_frames(key: SharedKey, pages: PageRange) {
+    let mut shared = SHARED_FRAMES.lock();
+
+    if shared.contains_key(&key) {
+        return;
+    }
+
+    let frames = pages.map(|p| {
+        let (frame, _) = paging::translate_page(p).expect("The page is not mapped.");
+
+        phys::add_reference(frame);
 
-        let page_range = Self::page_range_from_vaddr_and_len(base.as_u64(), region.len());
+        frame
+    });
 
-        unsafe {
-            Self::update_flags(page_range, flags).expect("Failed to update flags.");
+    shared.insert(key, frames.collect());
+}
+
+/// Allocates a thread-local storage block initialized with the image `tls`, and returns the
+/// thread pointer.
+///
+/// The block follows the TLS variant II of the `x86_64` ABI. The storage ends at the thread
+/// pointer, and the first word of the thread control block points to itself.
+fn allocate_tls_block(tls: &Tls, address_space: &mut AddressSpace) -> Result<VirtAddr, Error> {
+    let bytes = Bytes::new(usize::try_from(tls.block_size()).unwrap());
+    let start = map_free_pages(bytes.as_num_of_pages(), address_space)?;
+
+    let thread_pointer = start + tls.thread_pointer_offset();
+    let file_size = usize::try_from(tls.file_size).unwrap();
+
+    // SAFETY: `elf_layout` ensures that the image is inside a loaded segment, and the block is
+    // mapped just now. The rest of the storage is already filled with zeros.
+    unsafe {
+        if file_size > 0 {
+            ptr::copy_nonoverlapping(
+                VirtAddr::new(tls.start).as_ptr::<u8>(),
+                start.as_mut_ptr(),
+                file_size,
+            );
         }
 
-        Ok(())
+        ptr::write(thread_pointer.as_mut_ptr(), thread_pointer.as_u64());
     }
 
-    fn relocate(&mut self, _: &Rela<P64>) -> Result<(), ElfLoaderErr> {
-        todo!()
+    Ok(thread_pointer)
+}
+
+/// Maps writable and non-executable pages filled with zeros to a free range of `address_space`,
+/// and returns the start address of them.
+fn map_free_pages(
+    num_of_pages: NumOfPages<Size4KiB>,
+    address_space: &mut AddressSpace,
+) -> Result<VirtAddr, Error> {
+    let start = address_space
+        .find_free(num_of_pages)
+        .ok_or(Error::NoSpace)?;
+
+    let first = Page::containing_address(start);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+
+    address_space.map_anonymous_eagerly(
+        Page::range(first, first + num_of_pages.as_usize().try_into().unwrap()),
+        flags,
+    )?;
+
+    Ok(start)
+}
+
+/// Returns the offset which moves the link-time `range` to a random position in the free ranges
+/// of `address_space`.
+fn choose_base(range: &Range<u64>, address_space: &mut AddressSpace) -> Result<u64, Error> {
+    let bytes = Bytes::new(usize::try_from(range.end - range.start).unwrap());
+    let num_of_pages: NumOfPages<Size4KiB> = bytes.as_num_of_pages();
+
+    let start = address_space
+        .find_free_randomly(num_of_pages, random())
+        .ok_or(Error::NoSpace)?;
+
+    Ok(start.as_u64().wrapping_sub(range.start))
+}
+
+/// Returns a random value with RDRAND, or one derived from the TSC if the processor does not
+/// support RDRAND.
+fn random() -> u64 {
+    RdRand::new().and_then(RdRand::get_u64).unwrap_or_else(|| {
+        // SAFETY: Reading the TSC does not violate memory safety.
+        let tsc = unsafe { _rdtsc() };
+
+        // The finalizer of SplitMix64 spreads the changes in the lower bits of the TSC to all bits.
+        let z = tsc.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        let z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+        z ^ (z >> 31)
+    })
+}
+
+fn page_range(region: &Region) -> PageRange {
+    Page::range(
+        Page::containing_address(VirtAddr::new(region.start)),
+        Page::containing_address(VirtAddr::new(region.end)),
+    )
+}
+
+fn region_flags(region: &Region) -> PageTableFlags {
+    let mut page_table_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    if region.writable {
+        page_table_flags |= PageTableFlags::WRITABLE;
     }
+
+    if !region.executable {
+        page_table_flags |= PageTableFlags::NO_EXECUTE;
+    }
+
+    page_table_flags
 }
@@ -1,87 +1,157 @@
 use {
     allocator::virt,
-    boot_info::mem::MemoryDescriptor,
+    boot_info::mem::{MemoryDescriptor, MemoryType},
     core::convert::TryFrom,
-    os_units::Bytes,
-    predefined_mmap::{KERNEL_ADDR, STACK_BASE},
+    os_units::{Bytes, NumOfPages},
+    syscalls::CacheType,
     x86_64::{
         structures::paging::{
-            page::PageRange, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB,
+            frame::PhysFrameRange, page::PageRange, PageSize, PageTableFlags, PhysFrame, Size2MiB,
+            Size4KiB,
         },
         PhysAddr, VirtAddr,
     },
 };
 
 pub(crate) mod accessor;
+pub(crate) mod address_space;
 pub(crate) mod allocator;
 pub(crate) mod elf;
 pub(crate) mod paging;
+mod pat;
+pub(crate) mod tlb;
 
 pub(super) fn init(mem_map: &[MemoryDescriptor]) {
+    pat::init();
     allocator::heap::init();
     allocator::phys::init(mem_map);
     paging::mark_pages_as_unused();
+
+    // The current PML4 is created by UEFI and is located in the boot services memory.
+    paging::switch_to_kernel_pml4();
+    paging::enable_write_protection();
+    tlb::init();
+    allocator::phys::reclaim(MemoryType::BootServices);
 }
 
-pub(super) fn map_pages_for_user(start: PhysAddr, object_size: Bytes) -> VirtAddr {
-    map_pages_from(
-        start,
-        object_size,
-        PageRange {
-            start: Page::from_start_address(VirtAddr::new(0x1000)).unwrap(),
-            end: Page::from_start_address(KERNEL_ADDR).unwrap(),
-        },
-    )
+/// Sets up the memory management of an application processor.
+///
+/// The processor switches from the page tables of the trampoline to its own PML4.
+pub(super) fn init_ap() {
+    pat::init();
+    paging::switch_to_kernel_pml4();
+    paging::enable_write_protection();
+    tlb::init();
 }
 
-pub(super) fn map_pages_for_kernel(start: PhysAddr, object_size: Bytes) -> VirtAddr {
-    map_pages_from(
-        start,
-        object_size,
-        PageRange {
-            start: Page::from_start_address(STACK_BASE).unwrap(),
-            end: Page::from_start_address(VirtAddr::new(0xffff_ffff_ffff_f000)).unwrap(),
-        },
-    )
+/// Adds the memory containing ACPI tables to the pool of frames.
+///
+/// Call this function after the kernel finishes parsing the tables.
+pub(super) fn reclaim_acpi_memory() {
+    allocator::phys::reclaim(MemoryType::AcpiReclaim);
+}
+
+/// Maps the physical memory `start..start+object_size` to the kernel region with the memory type
+/// `cache`.
+pub(super) fn map_pages_for_kernel(
+    start: PhysAddr,
+    object_size: Bytes,
+    cache: CacheType,
+) -> VirtAddr {
+    let frames = frames_spanning(start, object_size);
+    let num_of_pages = NumOfPages::new(usize::try_from(frames.end - frames.start).unwrap());
+
+    let virt = allocate_virt_for(frames.start.start_address(), num_of_pages);
+    let virt = virt.expect("No free virtual memory in the kernel region.");
+
+    // SAFETY: `virt` is reserved by the virtual memory allocator, and nothing is mapped there.
+    unsafe {
+        paging::map_with_huge_pages(
+            virt,
+            frames,
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | cache_flags(cache),
+        );
+    }
+
+    virt + start.as_u64() % Size4KiB::SIZE
 }
 
 pub(super) fn unmap_pages(start: VirtAddr, object_size: Bytes) {
-    let start_frame_addr = start.align_down(Size4KiB::SIZE);
-    let end_frame_addr = (start + object_size.as_usize()).align_down(Size4KiB::SIZE);
+    let start_page_addr = start.align_down(Size4KiB::SIZE);
+    let num_of_pages = num_of_pages_spanning(start.as_u64(), object_size);
 
-    let num_pages = Bytes::new(usize::try_from(end_frame_addr - start_frame_addr).unwrap())
-        .as_num_of_pages::<Size4KiB>();
+    let end = start_page_addr + num_of_pages.as_bytes().as_usize();
 
-    for i in 0..num_pages.as_usize() {
-        let page =
-            Page::<Size4KiB>::containing_address(start_frame_addr + Size4KiB::SIZE * i as u64);
+    let mut addr = start_page_addr;
 
-        paging::unmap(page).unwrap();
+    while addr < end {
+        addr += paging::unmap_containing(addr).expect("Failed to unmap a page.");
     }
+
+    virt::deallocate_unmapped(start_page_addr, num_of_pages);
 }
 
-fn map_pages_from(start: PhysAddr, object_size: Bytes, region: PageRange) -> VirtAddr {
-    let start_frame_addr = start.align_down(Size4KiB::SIZE);
-    let end_frame_addr = (start + object_size.as_usize()).align_down(Size4KiB::SIZE);
+/// Reserves `num_of_pages` pages in the kernel region to map the frames from `phys`.
+///
+/// If the range is at least as large as a [`Size2MiB`] page, the returned address has the same
+/// offset in such a page as `phys` so that the mapping can use huge pages.
+fn allocate_virt_for(phys: PhysAddr, num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
+    let huge = usize::try_from(Size2MiB::SIZE).unwrap();
 
-    let num_pages = Bytes::new(usize::try_from(end_frame_addr - start_frame_addr).unwrap() + 1)
-        .as_num_of_pages::<Size4KiB>();
+    if num_of_pages.as_bytes().as_usize() < huge {
+        return virt::allocate(num_of_pages);
+    }
 
-    let virt = virt::search_free_addr_from(num_pages, region)
-        .expect("OOM during creating a new accessor to a register.");
+    let offset = Bytes::new(usize::try_from(phys.as_u64() % Size2MiB::SIZE).unwrap());
+    let offset = offset.as_num_of_pages::<Size4KiB>();
 
-    for i in 0..num_pages.as_usize() {
-        let page = Page::<Size4KiB>::containing_address(virt + Size4KiB::SIZE * i as u64);
-        let frame = PhysFrame::containing_address(start_frame_addr + Size4KiB::SIZE * i as u64);
-        let flag =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    let virt = virt::allocate_aligned(num_of_pages + offset, Size2MiB::SIZE)?;
 
-        unsafe {
-            paging::map_to(page, frame, flag).unwrap();
-        }
+    if offset.as_usize() > 0 {
+        virt::deallocate(virt, offset);
     }
 
-    let page_offset = start.as_u64() % Size4KiB::SIZE;
+    Some(virt + offset.as_bytes().as_usize())
+}
+
+/// Returns the number of pages containing `object_size` bytes from the address `start`.
+///
+/// An empty object still occupies the page containing `start`.
+fn num_of_pages_spanning(start: u64, object_size: Bytes) -> NumOfPages<Size4KiB> {
+    let first = start / Size4KiB::SIZE;
+    let last =
+        (start + u64::try_from(object_size.as_usize()).unwrap()).saturating_sub(1) / Size4KiB::SIZE;
+
+    NumOfPages::new(usize::try_from(last.max(first) - first + 1).unwrap())
+}
+
+/// Returns the frames containing `object_size` bytes from the address `start`.
+pub(crate) fn frames_spanning(start: PhysAddr, object_size: Bytes) -> PhysFrameRange {
+    let first = PhysFrame::containing_address(start);
+    let num_of_pages = num_of_pages_spanning(start.as_u64(), object_size);
+
+    PhysFrame::range(
+        first,
+        first + u64::try_from(num_of_pages.as_usize()).unwrap(),
+    )
+}
 
-    virt + page_offset
+fn cache_flags(cache: CacheType) -> PageTableFlags {
+    match cache {
+        CacheType::WriteBack => PageTableFlags::empty(),
+        // `pat::init` sets the PAT entry 1 to write-combining.
+        CacheType::WriteCombining => PageTableFlags::WRITE_THROUGH,
+        CacheType::Uncacheable => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+    }
+}
+
+/// Maps `pages` to the frames from `start`.
+fn map_pages_at(pages: PageRange, start: PhysAddr, flags: PageTableFlags) {
+    for (i, page) in pages.enumerate() {
+        let frame = PhysFrame::containing_address(start + Size4KiB::SIZE * i as u64);
+
+        unsafe {
+            paging::map_to(page, frame, flags).unwrap();
+        }
+    }
 }
@@ -1,21 +1,29 @@
 use {
-    crate::mem::allocator::phys,
+    crate::{
+        mem::allocator::{kpbox::KpBox, phys},
+        sync::IrqSpinlock,
+    },
     conquer_once::spin::Lazy,
+    core::{arch::x86_64::__cpuid, fmt, mem},
     predefined_mmap::RECUR_PML4_ADDR,
-    spinning_top::Spinlock,
     x86_64::{
+        registers::control::{Cr0, Cr0Flags, Cr3},
         structures::paging::{
-            mapper::{FlagUpdateError, MapToError, MapperFlush, UnmapError},
+            frame::PhysFrameRange,
+            mapper::{
+                CleanUp, FlagUpdateError, MapToError, MappedFrame, MapperFlush, TranslateResult,
+                UnmapError,
+            },
             page::PageRange,
-            FrameAllocator, Mapper, Page, PageTable, PageTableFlags, PhysFrame, RecursivePageTable,
-            Size4KiB, Translate,
+            FrameAllocator, Mapper, Page, PageSize, PageTable, PageTableFlags, PhysFrame,
+            RecursivePageTable, Size1GiB, Size2MiB, Size4KiB, Translate,
         },
         PhysAddr, VirtAddr,
     },
 };
 
-static PML4: Lazy<Spinlock<RecursivePageTable<'_>>> = Lazy::new(|| unsafe {
-    Spinlock::new(
+static PML4: Lazy<IrqSpinlock<RecursivePageTable<'_>>> = Lazy::new(|| unsafe {
+    IrqSpinlock::new(
         (RecursivePageTable::new(&mut *(RECUR_PML4_ADDR.as_mut_ptr())))
             .expect("PML4 has no recursive entry."),
     )
@@ -30,6 +38,41 @@ pub(crate) fn mark_pages_as_unused() {
     }
 }
 
+/// Switches to a PML4 which the kernel allocates.
+///
+/// The new PML4 shares the kernel mappings with the current one.
+pub(crate) fn switch_to_kernel_pml4() {
+    let mut pml4 = KpBox::<PageTable>::default();
+
+    let addr = pml4.phys_addr();
+
+    pml4[510].set_addr(addr, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    pml4[511] = level_4_table()[511].clone();
+
+    let frame = PhysFrame::from_start_address(addr).expect("PML4 is not page-aligned.");
+    let (_, flags) = Cr3::read();
+
+    // SAFETY: The new PML4 maps the kernel as the current one does, and the recursive entry
+    // points to the new PML4 itself.
+    unsafe {
+        Cr3::write(frame, flags);
+    }
+
+    // The kernel uses this PML4 until it halts.
+    mem::forget(pml4);
+}
+
+/// Makes the running processor respect the read-only pages in the kernel mode.
+///
+/// The bootloader clears `CR0.WP` to modify the page tables which UEFI may map as read-only.
+/// Without it, a write from the kernel to a copy-on-write page lands in the shared frame instead
+/// of raising a page fault.
+pub(crate) fn enable_write_protection() {
+    // SAFETY: The kernel maps all of its memory as writable, and the page tables of UEFI are no
+    // longer used after `switch_to_kernel_pml4`.
+    unsafe { Cr0::update(|f| f.insert(Cr0Flags::WRITE_PROTECT)) };
+}
+
 pub(crate) fn map_range_to_unused_phys_range(
     page_range: PageRange,
     flags: PageTableFlags,
@@ -65,19 +108,82 @@ pub(crate) fn map_to_unused(page: Page, flags: PageTableFlags) -> Result<(), Map
 /// # Safety
 ///
 /// Refer to [`x86_64::structures::paging::Mapper`].
-pub(crate) unsafe fn map_to(
-    page: Page,
-    frame: PhysFrame,
+pub(crate) unsafe fn map_to<S: PageSize>(
+    page: Page<S>,
+    frame: PhysFrame<S>,
     flags: PageTableFlags,
-) -> Result<(), MapToError<Size4KiB>> {
+) -> Result<(), MapToError<S>>
+where
+    for<'a> RecursivePageTable<'a>: Mapper<S>,
+{
+    // The parent tables are always writable. Otherwise a read-only page would prevent the other
+    // pages sharing the same tables from being writable.
+    let parent_table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+
     // SAFETY: The caller must ensure the all safety requirements.
     unsafe {
         PML4.lock()
-            .map_to(page, frame, flags, &mut *phys::allocator())
+            .map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                parent_table_flags,
+                &mut *phys::allocator(),
+            )
             .map(MapperFlush::flush)
     }
 }
 
+/// Maps `frames` to the virtual memory from `virt`.
+///
+/// This function uses [`Size2MiB`] pages, and [`Size1GiB`] pages if the processor supports them,
+/// where both addresses are aligned to the page size and the rest of the range covers a whole
+/// page.
+///
+/// # Safety
+///
+/// Refer to [`x86_64::structures::paging::Mapper`].
+///
+/// # Panics
+///
+/// This function panics if any of the pages are already mapped, or there is no frame for the page
+/// tables.
+pub(crate) unsafe fn map_with_huge_pages(
+    virt: VirtAddr,
+    frames: PhysFrameRange,
+    flags: PageTableFlags,
+) {
+    let supports_1gib = supports_1gib_pages();
+
+    let phys = frames.start.start_address();
+    let bytes = (frames.end - frames.start) * Size4KiB::SIZE;
+
+    let mut offset = 0;
+
+    while offset < bytes {
+        let v = virt + offset;
+        let p = phys + offset;
+        let rest = bytes - offset;
+
+        // SAFETY: The caller must uphold the safety requirements.
+        offset += unsafe {
+            if supports_1gib && fits::<Size1GiB>(v, p, rest) {
+                map_one::<Size1GiB>(v, p, flags)
+            } else if fits::<Size2MiB>(v, p, rest) {
+                map_one::<Size2MiB>(v, p, flags)
+            } else {
+                map_one::<Size4KiB>(v, p, flags)
+            }
+        };
+    }
+}
+
+/// Unmaps `page`, flushing its translation from the TLB of the running processor only.
+///
+/// The virtual memory of an unmapped page in the kernel region must be returned with
+/// [`deallocate_unmapped`](super::allocator::virt::deallocate_unmapped).
 pub(crate) fn unmap(page: Page) -> Result<PhysFrame, UnmapError> {
     PML4.lock().unmap(page).map(|(frame, flush)| {
         flush.flush();
@@ -85,6 +191,101 @@ pub(crate) fn unmap(page: Page) -> Result<PhysFrame, UnmapError> {
     })
 }
 
+/// Unmaps the page containing `addr` whichever size it is, and returns the size of the page in
+/// bytes.
+pub(crate) fn unmap_containing(addr: VirtAddr) -> Result<u64, UnmapError> {
+    let mut pml4 = PML4.lock();
+
+    match pml4.translate(addr) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(_),
+            ..
+        } => unmap_with::<Size4KiB>(&mut pml4, addr),
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size2MiB(_),
+            ..
+        } => unmap_with::<Size2MiB>(&mut pml4, addr),
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size1GiB(_),
+            ..
+        } => unmap_with::<Size1GiB>(&mut pml4, addr),
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => {
+            Err(UnmapError::PageNotMapped)
+        }
+    }
+}
+
+/// Returns `true` if the processor supports [`Size1GiB`] pages.
+pub(crate) fn supports_1gib_pages() -> bool {
+    const PDPE1GB: u32 = 1 << 26;
+
+    // SAFETY: All x86_64 processors support the CPUID leaf 0x8000_0001.
+    let r = unsafe { __cpuid(0x8000_0001) };
+
+    r.edx & PDPE1GB != 0
+}
+
+/// Frees the page tables which map no pages in the user space of the current address space.
+pub(crate) fn clean_up_user_page_tables() {
+    let range = Page::range_inclusive(
+        Page::containing_address(VirtAddr::zero()),
+        Page::containing_address(VirtAddr::new(0x0000_7fff_ffff_ffff)),
+    );
+
+    // SAFETY: The kernel does not map any pages in the lower half. The page tables which map
+    // nothing there are not used by anyone.
+    unsafe {
+        PML4.lock()
+            .clean_up_addr_range(range, &mut *phys::allocator());
+    }
+}
+
+/// Returns the frame which `page` is mapped to and the flags of the entry.
+pub(crate) fn translate_page(page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+    match PML4.lock().translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => Some((frame, flags)),
+        _ => None,
+    }
+}
+
+fn fits<S: PageSize>(virt: VirtAddr, phys: PhysAddr, rest: u64) -> bool {
+    virt.is_aligned(S::SIZE) && phys.is_aligned(S::SIZE) && rest >= S::SIZE
+}
+
+unsafe fn map_one<S: PageSize + fmt::Debug>(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> u64
+where
+    for<'a> RecursivePageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::from_start_address(virt).expect("The address is not aligned.");
+    let frame = PhysFrame::<S>::from_start_address(phys).expect("The address is not aligned.");
+
+    // SAFETY: The caller must uphold the safety requirements.
+    unsafe { map_to(page, frame, flags).expect("Failed to map a page.") };
+
+    S::SIZE
+}
+
+fn unmap_with<S: PageSize>(
+    pml4: &mut RecursivePageTable<'_>,
+    addr: VirtAddr,
+) -> Result<u64, UnmapError>
+where
+    for<'a> RecursivePageTable<'a>: Mapper<S>,
+{
+    let (_, flush) = pml4.unmap(Page::<S>::containing_address(addr))?;
+    flush.flush();
+
+    Ok(S::SIZE)
+}
+
 pub(crate) fn translate_addr(a: VirtAddr) -> Option<PhysAddr> {
     PML4.lock().translate_addr(a)
 }
@@ -100,6 +301,32 @@ pub(crate) unsafe fn update_flags(
     }
 }
 
+/// Calls `f` while the PML4 `pml4` is active.
+///
+/// # Safety
+///
+/// `pml4` must map the kernel as the current one does.
+pub(crate) unsafe fn switch_pml4_do<T>(pml4: PhysFrame, f: impl FnOnce() -> T) -> T {
+    let (old_pml4, flags) = Cr3::read();
+
+    unsafe {
+        Cr3::write(pml4, flags);
+    }
+
+    let r = f();
+
+    unsafe {
+        Cr3::write(old_pml4, flags);
+    }
+
+    r
+}
+
+/// Returns `true` if the running processor is modifying the page tables.
+pub(crate) fn is_held_by_current_cpu() -> bool {
+    PML4.is_held_by_current_cpu()
+}
+
 pub(crate) fn level_4_table() -> PageTable {
     PML4.lock().level_4_table().clone()
 }
@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    core::arch::asm,
+    x86_64::{instructions::tlb, registers::model_specific::Msr},
+};
+
+const IA32_PAT: Msr = Msr::new(0x277);
+
+const WRITE_BACK: u64 = 0x06;
+const WRITE_COMBINING: u64 = 0x01;
+const UNCACHED_MINUS: u64 = 0x07;
+const UNCACHEABLE: u64 = 0x00;
+
+/// The entries selected by the PAT, PCD and PWT bits of a page table entry.
+///
+/// Only the entry 1 differs from the default value, which is write-through. Thus the pages mapped
+/// without PWT or with both PCD and PWT keep their memory types.
+const ENTRIES: [u64; 8] = [
+    WRITE_BACK,
+    WRITE_COMBINING,
+    UNCACHED_MINUS,
+    UNCACHEABLE,
+    WRITE_BACK,
+    WRITE_COMBINING,
+    UNCACHED_MINUS,
+    UNCACHEABLE,
+];
+
+/// Programs the Page Attribute Table so that PWT selects the write-combining memory type.
+pub(super) fn init() {
+    let pat = ENTRIES
+        .iter()
+        .enumerate()
+        .fold(0, |acc, (i, e)| acc | e << (i * 8));
+
+    let mut msr = IA32_PAT;
+
+    // SAFETY: Changing the memory type of the pages mapped with PWT does not violate memory
+    // safety. The caches and the TLB are flushed so that no stale attributes remain.
+    unsafe {
+        msr.write(pat);
+
+        asm!("wbinvd", options(nostack, preserves_flags));
+    }
+
+    tlb::flush_all();
+}
@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Flushing the TLBs of the other processors without IPIs.
+//!
+//! Unmapping a page in the kernel region flushes the TLB of the running processor only, and the
+//! other processors may still translate the page. Instead of interrupting them, the unmapping
+//! processor advances the generation, and each processor flushes its TLB on the next timer tick if
+//! it has not flushed at the latest generation. The virtual memory of the unmapped pages is reused
+//! after every processor has flushed.
+
+use {
+    crate::smp::{self, MAX_CPUS},
+    core::sync::atomic::{AtomicU64, Ordering},
+    x86_64::instructions::tlb,
+};
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// A constant is required as an array initializer because atomics are not `Copy`.
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_STARTED: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// The generation at which each processor flushed its TLB last, indexed by the CPU number.
+///
+/// A processor which is not started caches no translations, so it never delays the reuse.
+static FLUSHED: [AtomicU64; MAX_CPUS] = [NOT_STARTED; MAX_CPUS];
+
+/// Starts tracking the TLB of the running processor.
+///
+/// This function must be called after the processor switches to the kernel PML4.
+pub(crate) fn init() {
+    flush(smp::current());
+}
+
+/// Records that pages in the kernel region are unmapped, and returns the generation at which
+/// every processor must flush its TLB before the pages are reused.
+pub(crate) fn advance() -> u64 {
+    GENERATION.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Flushes the TLB of the running processor if pages are unmapped since the last flush.
+///
+/// The timer interrupt handler calls this function on every tick.
+pub(crate) fn flush_if_stale() {
+    let cpu = smp::current();
+
+    if FLUSHED[cpu].load(Ordering::Relaxed) < GENERATION.load(Ordering::SeqCst) {
+        flush(cpu);
+    }
+}
+
+/// Returns `true` if every processor has flushed its TLB at `generation` or later.
+pub(crate) fn is_flushed_everywhere(generation: u64) -> bool {
+    FLUSHED
+        .iter()
+        .all(|flushed| flushed.load(Ordering::Acquire) >= generation)
+}
+
+fn flush(cpu: usize) {
+    // The generation is read before flushing so that the pages unmapped after this line are not
+    // regarded as flushed.
+    let generation = GENERATION.load(Ordering::SeqCst);
+
+    tlb::flush_all();
+
+    FLUSHED[cpu].store(generation, Ordering::Release);
+}
@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The per-CPU area reached through the GS segment.
+//!
+//! While a processor runs in the kernel mode, `IA32_GS_BASE` holds the address of its area, and
+//! `IA32_KERNEL_GS_BASE` holds the GS base of the user space. Every entry from the user mode
+//! executes `swapgs`, and every return to the user mode executes it again. The system call entry
+//! does it in `prepare_syscall`, and the interrupt handlers do it with [`SwapGsGuard`].
+
+use {
+    crate::{process::Process, smp::MAX_CPUS},
+    core::{
+        arch::asm,
+        ptr,
+        sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, Ordering},
+    },
+    x86_64::{
+        instructions::segmentation::GS,
+        registers::model_specific::{GsBase, KernelGsBase},
+        structures::idt::InterruptStackFrame,
+        VirtAddr,
+    },
+};
+
+/// The offset of [`PerCpu::user_stack`] used by the assembly code.
+pub(crate) const USER_STACK_OFFSET: usize = 0x08;
+
+/// The offset of [`PerCpu::kernel_stack_bottom`] used by the assembly code.
+pub(crate) const KERNEL_STACK_BOTTOM_OFFSET: usize = 0x10;
+
+// A constant is required as an array initializer because atomics are not `Copy`.
+#[allow(clippy::declare_interior_mutable_const)]
+const UNINIT: PerCpu = PerCpu::new();
+
+static AREAS: [PerCpu; MAX_CPUS] = [UNINIT; MAX_CPUS];
+
+/// Makes GS point to the area of the processor `cpu`.
+///
+/// GS must not be loaded after this function, as it clears the base address. `gdt` does not load
+/// it for this reason.
+pub(crate) fn init(cpu: usize) {
+    let area = &AREAS[cpu];
+
+    let addr = VirtAddr::from_ptr(area);
+
+    area.this.store(addr.as_u64(), Ordering::Relaxed);
+    area.cpu.store(cpu, Ordering::Relaxed);
+
+    GsBase::write(addr);
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// Returns the CPU number of the running processor.
+pub(crate) fn cpu() -> usize {
+    area().cpu.load(Ordering::Relaxed)
+}
+
+/// Sets the stack which the system call entry of the running processor switches to.
+pub(crate) fn set_kernel_stack_bottom(addr: VirtAddr) {
+    area()
+        .kernel_stack_bottom
+        .store(addr.as_u64(), Ordering::Relaxed);
+}
+
+/// Records `p` as the process which the running processor runs.
+pub(crate) fn set_running_process(p: *mut Process) {
+    area().process.store(p, Ordering::Relaxed);
+}
+
+/// Returns the process which the running processor runs.
+///
+/// The pointer is null until the scheduler registers the processor.
+pub(crate) fn running_process() -> *const Process {
+    area().process.load(Ordering::Relaxed)
+}
+
+/// Executes `swapgs` when created and dropped if an interrupt happened in the user mode.
+///
+/// An interrupt handler must create this guard before touching the per-CPU area, and keep it
+/// until it returns.
+pub(crate) struct SwapGsGuard(bool);
+impl SwapGsGuard {
+    pub(crate) fn new(frame: &InterruptStackFrame) -> Self {
+        let from_user = frame.code_segment & 3 == 3;
+
+        if from_user {
+            // SAFETY: The interrupt happened in the user mode, so `IA32_KERNEL_GS_BASE` holds the
+            // address of the per-CPU area.
+            unsafe { GS::swap() };
+        }
+
+        Self(from_user)
+    }
+}
+impl Drop for SwapGsGuard {
+    fn drop(&mut self) {
+        if self.0 {
+            // SAFETY: The handler returns to the user mode right after this.
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+fn area<'a>() -> &'a PerCpu {
+    let area: *const PerCpu;
+
+    // SAFETY: `gs:0` holds the address of the per-CPU area after `init`.
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) area, options(nostack, preserves_flags, readonly));
+    }
+
+    // SAFETY: The areas are static, and their fields are atomics.
+    unsafe { &*area }
+}
+
+#[repr(C)]
+struct PerCpu {
+    /// The address of this structure, so that `gs:0` gives the pointer to it.
+    this: AtomicU64,
+
+    /// The scratch space where `prepare_syscall` saves the user stack pointer.
+    user_stack: AtomicU64,
+
+    /// The stack which `prepare_syscall` switches to.
+    kernel_stack_bottom: AtomicU64,
+
+    cpu: AtomicUsize,
+
+    process: AtomicPtr<Process>,
+}
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            this: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            kernel_stack_bottom: AtomicU64::new(0),
+            cpu: AtomicUsize::new(0),
+            process: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+}
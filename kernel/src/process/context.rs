@@ -1,6 +1,9 @@
 use {
-    crate::gdt,
-    core::{arch::asm, mem::size_of},
+    crate::{
+        gdt,
+        syscall::{self, SavedRegisters},
+    },
+    core::{arch::asm, convert::TryInto, mem::size_of},
     static_assertions::const_assert_eq,
     x86_64::{
         registers::rflags::RFlags,
@@ -40,11 +43,10 @@ pub(super) struct Context {
     cr3: u64,
     rip: u64,
     rflags: u64,
-    _fxsave_requires_16_bytes_alinged_address: u64,
-
-    fxsave_area: FxsaveArea,
+    /// The value of `IA32_FS_BASE`.
+    fs_base: u64,
 }
-const_assert_eq!(size_of::<Context>(), 8 * 4 * 6 + 512);
+const_assert_eq!(size_of::<Context>(), 8 * 4 * 6);
 impl Context {
     pub(super) fn kernel(entry: VirtAddr, pml4: PhysFrame, rsp: VirtAddr) -> Self {
         Self::new(
@@ -66,6 +68,52 @@ impl Context {
         )
     }
 
+    /// Creates the context of a child process created by `fork`.
+    ///
+    /// The child starts from [`syscall::return_from_fork`] with the kernel stack `rsp`, and
+    /// returns to the user space with the registers in `saved`. The user stack pointer is passed
+    /// in RDX.
+    pub(super) fn forked(pml4: PhysFrame, rsp: VirtAddr, saved: &SavedRegisters) -> Self {
+        let entry = VirtAddr::new((syscall::return_from_fork as usize).try_into().unwrap());
+
+        let mut context = Self::kernel(entry, pml4, rsp);
+
+        context.fs = gdt::user_data_selector().0.into();
+        context.gs = gdt::user_data_selector().0.into();
+
+        // Interrupts must be disabled until `sysretq` switches the stack to the user one.
+        context.rflags = RFlags::PARITY_FLAG.bits();
+
+        context.rcx = saved.rip;
+        context.rdx = saved.user_stack;
+        context.r11 = saved.rflags;
+
+        context.rbp = saved.rbp;
+        context.rbx = saved.rbx;
+        context.r12 = saved.r12;
+        context.r13 = saved.r13;
+        context.r14 = saved.r14;
+        context.r15 = saved.r15;
+
+        context
+    }
+
+    pub(super) fn fs_base(&self) -> VirtAddr {
+        VirtAddr::new(self.fs_base)
+    }
+
+    pub(super) fn set_fs_base(&mut self, fs_base: VirtAddr) {
+        self.fs_base = fs_base.as_u64();
+    }
+
+    /// Saves the current context to `old` and switches to `new`.
+    ///
+    /// `IA32_FS_BASE` is not saved as nothing but [`Context::set_fs_base`] changes it. It is set
+    /// after loading FS because loading a segment register clears the base. For the same reason,
+    /// GS is not loaded, as its base holds the address of the per-CPU area. `swapgs` is executed
+    /// before entering the user mode. See [`crate::percpu`].
+    ///
+    /// The FPU and SIMD registers are not switched here. See [`crate::fpu`].
     #[naked]
     #[allow(clippy::too_many_lines)]
     pub(super) extern "sysv64" fn switch(old: *mut Context, new: *mut Context) {
@@ -105,7 +153,13 @@ impl Context {
     pushfq
     pop qword ptr [rdi+0xb0]
 
-    fxsave [rdi+0xc0]
+    mov rax, [rsi+0x90]
+    mov fs, ax
+
+    mov ecx, 0xc0000100
+    mov eax, [rsi+0xb8]
+    mov edx, [rsi+0xbc]
+    wrmsr
 
     mov rax, [rsi+0x00]
     mov rbx, [rsi+0x08]
@@ -125,16 +179,9 @@ impl Context {
     mov r14, [rsi+0x70]
     mov r15, [rsi+0x78]
 
-    mov rax, [rsi+0x90]
-    mov fs, ax
-    mov rax, [rsi+0x98]
-    mov gs, ax
-
     mov rax, [rsi+0xa0]
     mov cr3, rax
 
-    fxrstor [rsi+0xc0]
-
     push qword ptr [rsi+0x88]
     push qword ptr [rsi+0x20]
     push qword ptr [rsi+0xb0]
@@ -143,6 +190,10 @@ impl Context {
 
     mov rsi, [rsi+0x30]
 
+    test qword ptr [rsp+0x08], 3
+    jz 2f
+    swapgs
+2:
     iretq
     ",
                 options(noreturn)
@@ -177,12 +228,3 @@ impl Context {
         }
     }
 }
-
-#[repr(transparent)]
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
-struct FxsaveArea([u8; 512]);
-impl Default for FxsaveArea {
-    fn default() -> Self {
-        Self([0; 512])
-    }
-}
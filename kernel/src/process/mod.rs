@@ -5,6 +5,7 @@ mod priority;
 mod receive_from;
 pub(crate) mod scheduler;
 mod status;
+mod timer_wheel;
 
 #[cfg(feature = "qemu_test")]
 use crate::tests;
@@ -16,73 +17,102 @@ use {
         status::Status,
     },
     crate::{
+        fpu,
         mem::{
-            self,
-            allocator::{allocate_pages_for_user, kpbox::KpBox},
-            paging,
+            address_space::AddressSpace,
+            allocator::{
+                kpbox::KpBox,
+                slab::{
+                    queue::{Node, Queue},
+                    Cache,
+                },
+                stack::KernelStack,
+            },
+            elf, paging,
         },
+        syscall::SavedRegisters,
         sysproc,
     },
-    alloc::collections::VecDeque,
-    core::{cell::UnsafeCell, convert::TryInto},
-    os_units::{Bytes, NumOfPages},
-    static_assertions::const_assert,
+    core::{alloc::Layout, convert::TryInto},
+    log::error,
+    os_units::NumOfPages,
     x86_64::{
-        registers::control::Cr3,
-        structures::paging::{PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB},
+        structures::paging::{PageTable, PageTableFlags, PhysFrame, Size4KiB},
         PhysAddr, VirtAddr,
     },
 };
-pub(crate) use {pid::Pid, scheduler::switch};
+pub(crate) use {
+    pid::Pid,
+    scheduler::{
+        exit_on_segmentation_fault, fork, handle_device_not_available, handle_page_fault,
+        process_overflowing_kernel_stack, sleep_until, switch, wake_sleepers,
+        with_current_address_space,
+    },
+};
 
-// No truncation from u64 to usize on the x86_64 platform.
-#[allow(clippy::cast_possible_truncation)]
-const STACK_SIZE: usize = Size4KiB::SIZE as usize * 4;
-const STACK_GUARD_SIZE: Bytes = Bytes::new(4096);
-const STACK_MAGIC: &str = "Oh god! What a man!";
+static PROCESSES: Cache = Cache::new("process", Layout::new::<Process>());
+static PID_QUEUE_NODES: Cache = Cache::new("PID queue node", Layout::new::<Node<Pid>>());
 
-const_assert!(STACK_GUARD_SIZE.as_usize() + STACK_MAGIC.as_bytes().len() <= STACK_SIZE);
+const NUM_OF_KERNEL_STACK_PAGES: usize = 4;
+const NUM_OF_USER_STACK_PAGES: usize = 5;
 
 pub(super) fn init() {
     scheduler::init();
 
-    scheduler::add_process_as_runnable(Process::binary("xhci.bin"));
+    match Process::binary("xhci.bin") {
+        Ok(p) => scheduler::add_process_as_runnable(p),
+        Err(e) => error!("Failed to load xhci.bin: {}", e),
+    }
     scheduler::add_process_as_runnable(Process::from_function(sysproc::main, "sysproc"));
 
     #[cfg(feature = "qemu_test")]
     scheduler::add_process_as_runnable(Process::from_function(tests::main, "tests"));
 }
 
+/// Registers the running application processor with the scheduler.
+pub(super) fn init_ap() {
+    scheduler::init();
+}
+
 #[derive(Debug)]
 pub(crate) struct Process {
     pid: Pid,
 
-    _pml4: KpBox<PageTable>,
+    pml4: KpBox<PageTable>,
+    address_space: AddressSpace,
 
     context: Context,
-    kernel_stack: KpBox<UnsafeCell<[u8; STACK_SIZE]>>,
+    /// The FPU and SIMD state, which is allocated when the process uses them for the first time.
+    fpu: Option<fpu::State>,
+    kernel_stack: KernelStack,
     priority: Priority,
     status: Status,
     msg_ptr: Option<PhysAddr>,
     send_to: Option<Pid>,
     receive_from: Option<ReceiveFrom>,
-    pids_try_to_send_this_process: VecDeque<Pid>,
+    pids_try_to_send_this_process: Queue<Pid>,
     name: &'static str,
+
+    /// The processor which runs this process.
+    cpu: usize,
 }
 impl Process {
     fn idle() -> Self {
         Self {
             pid: pid::generate(),
-            _pml4: Self::generate_pml4(),
+            pml4: Self::generate_pml4(),
+            address_space: AddressSpace::new(),
             context: Context::default(),
+            fpu: None,
             kernel_stack: Self::generate_kernel_stack(),
             priority: LEAST_PRIORITY,
             msg_ptr: None,
             send_to: None,
             status: Status::Running,
             receive_from: None,
-            pids_try_to_send_this_process: VecDeque::new(),
+            pids_try_to_send_this_process: Queue::new(&PID_QUEUE_NODES),
             name: "idle",
+            cpu: 0,
         }
     }
 
@@ -97,15 +127,15 @@ impl Process {
 
         let kernel_stack = Self::generate_kernel_stack();
 
-        let stack_bottom = kernel_stack.virt_addr() + kernel_stack.bytes().as_usize();
-
-        let context = Context::kernel(entry, pml4_frame, stack_bottom - 8_u64);
+        let context = Context::kernel(entry, pml4_frame, kernel_stack.bottom_addr() - 8_u64);
 
         Process {
             pid: pid::generate(),
-            _pml4: pml4,
+            pml4,
+            address_space: AddressSpace::new(),
 
             context,
+            fpu: None,
             kernel_stack,
             priority: Priority::new(0),
 
@@ -116,13 +146,19 @@ impl Process {
             send_to: None,
             receive_from: None,
 
-            pids_try_to_send_this_process: VecDeque::new(),
+            pids_try_to_send_this_process: Queue::new(&PID_QUEUE_NODES),
             name,
+            cpu: 0,
         }
     }
 
+    /// Creates a process which runs the ELF binary `name` in the initrd.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if the binary cannot be loaded.
     #[allow(clippy::too_many_lines)]
-    fn binary(name: &'static str) -> Self {
+    fn binary(name: &'static str) -> Result<Self, elf::Error> {
         let pml4 = Self::generate_pml4();
 
         let pml4_frame = PhysFrame::from_start_address(pml4.phys_addr());
@@ -134,24 +170,35 @@ impl Process {
         let kernel_stack = Self::generate_kernel_stack();
 
         unsafe {
-            switch_pml4_do(pml4_frame, || {
-                let entry = mem::elf::map_to_current_address_space(raw).unwrap();
+            paging::switch_pml4_do(pml4_frame, || {
+                let mut address_space = AddressSpace::new();
 
-                let stack_size = NumOfPages::<Size4KiB>::new(5);
+                let image = match elf::map_to_current_address_space(raw, &mut address_space) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        address_space.release();
+                        return Err(e);
+                    }
+                };
 
-                let stack_top = allocate_pages_for_user(NumOfPages::new(5)).unwrap();
+                let stack_size = NumOfPages::<Size4KiB>::new(NUM_OF_USER_STACK_PAGES);
 
-                let context = Context::user(
-                    entry,
+                let stack_top = address_space.allocate_stack(stack_size).unwrap();
+
+                let mut context = Context::user(
+                    image.entry,
                     pml4_frame,
                     stack_top + stack_size.as_bytes().as_usize() - 8_u64,
                 );
+                context.set_fs_base(image.thread_pointer);
 
-                Self {
+                Ok(Self {
                     pid: pid::generate(),
-                    _pml4: pml4,
+                    pml4,
+                    address_space,
 
                     context,
+                    fpu: None,
                     kernel_stack,
                     priority: Priority::new(0),
 
@@ -162,48 +209,95 @@ impl Process {
                     send_to: None,
                     receive_from: None,
 
-                    pids_try_to_send_this_process: VecDeque::new(),
+                    pids_try_to_send_this_process: Queue::new(&PID_QUEUE_NODES),
                     name,
-                }
+                    cpu: 0,
+                })
             })
         }
     }
 
+    /// Creates a child process which shares the memory with this process until either of them
+    /// writes to it.
+    ///
+    /// The child returns from the system call with the registers in `saved`.
+    fn fork(&mut self, saved: &SavedRegisters) -> Self {
+        let (pml4, pml4_frame, address_space) = self.fork_address_space();
+
+        let kernel_stack = Self::generate_kernel_stack();
+
+        let mut context = Context::forked(pml4_frame, kernel_stack.bottom_addr() - 8_u64, saved);
+
+        // The child has the copy of the thread-local storage at the same address.
+        context.set_fs_base(self.context.fs_base());
+
+        Self {
+            pid: pid::generate(),
+            pml4,
+            address_space,
+
+            context,
+            fpu: self.fpu.clone(),
+            kernel_stack,
+            priority: self.priority,
+
+            status: Status::Runnable,
+
+            msg_ptr: None,
+
+            send_to: None,
+            receive_from: None,
+
+            pids_try_to_send_this_process: Queue::new(&PID_QUEUE_NODES),
+            name: self.name,
+            cpu: 0,
+        }
+    }
+
+    /// Creates a PML4 and an address space which shares the memory with this process.
+    fn fork_address_space(&mut self) -> (KpBox<PageTable>, PhysFrame, AddressSpace) {
+        let pml4 = Self::generate_pml4();
+
+        let pml4_frame = PhysFrame::from_start_address(pml4.phys_addr());
+        let pml4_frame = pml4_frame.expect("PML4 is not page-aligned.");
+
+        // SAFETY: The new PML4 maps the kernel only.
+        let address_space = unsafe { self.address_space.fork(pml4_frame) };
+
+        (pml4, pml4_frame, address_space)
+    }
+
     fn id(&self) -> Pid {
         self.pid
     }
 
-    /// # Safety
-    ///
-    /// Do not call this function for the process which is running and whose kernel stack is
-    /// currently used.
-    unsafe fn assert_kernel_stack_is_not_smashed(&self) {
-        // SAFETY: The caller ensures that he calls this function for the process which is not
-        // running or whose kernel stack is not used.
-        let stack = unsafe { &*self.kernel_stack.get() };
-        let magic =
-            &stack[STACK_GUARD_SIZE.as_usize()..STACK_GUARD_SIZE.as_usize() + STACK_MAGIC.len()];
-
-        assert_eq!(
-            magic,
-            STACK_MAGIC.as_bytes(),
-            "The kernel stack is smashed."
-        );
+    /// Frees the user memory of the process.
+    fn release_address_space(&mut self) {
+        let pml4_frame = PhysFrame::from_start_address(self.pml4.phys_addr());
+        let pml4_frame = pml4_frame.expect("PML4 is not page-aligned.");
+
+        let address_space = &mut self.address_space;
+
+        // SAFETY: The PML4 maps the kernel as the current one does.
+        unsafe {
+            paging::switch_pml4_do(pml4_frame, || address_space.release());
+        }
     }
 
     fn kernel_stack_bottom_addr(&self) -> VirtAddr {
-        self.kernel_stack.virt_addr() + self.kernel_stack.bytes().as_usize()
+        self.kernel_stack.bottom_addr()
     }
 
-    fn generate_pml4() -> KpBox<PageTable> {
+    pub(crate) fn generate_pml4() -> KpBox<PageTable> {
         let mut pml4 = KpBox::<PageTable>::default();
 
         for i in 0..510 {
             pml4[i].set_unused();
         }
 
-        let flags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        // Do not set `USER_ACCESSIBLE`. Otherwise the process can rewrite its own page tables
+        // through the recursive mapping.
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
         let addr = pml4.phys_addr();
 
@@ -213,29 +307,7 @@ impl Process {
         pml4
     }
 
-    fn generate_kernel_stack() -> KpBox<UnsafeCell<[u8; STACK_SIZE]>> {
-        let mut stack = KpBox::from(UnsafeCell::from([0; STACK_SIZE]));
-
-        for (i, c) in STACK_MAGIC.as_bytes().iter().enumerate() {
-            stack.get_mut()[STACK_GUARD_SIZE.as_usize() + i] = *c;
-        }
-
-        stack
-    }
-}
-
-unsafe fn switch_pml4_do<T>(pml4: PhysFrame, f: impl FnOnce() -> T) -> T {
-    let (old_pml4, flags) = Cr3::read();
-
-    unsafe {
-        Cr3::write(pml4, flags);
+    fn generate_kernel_stack() -> KernelStack {
+        KernelStack::new(NumOfPages::new(NUM_OF_KERNEL_STACK_PAGES))
     }
-
-    let r = f();
-
-    unsafe {
-        Cr3::write(old_pml4, flags);
-    }
-
-    r
 }
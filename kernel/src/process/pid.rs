@@ -1,20 +1,20 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use {
-    alloc::collections::BTreeSet, conquer_once::spin::Lazy, core::ops::DerefMut,
-    spinning_top::Spinlock,
+    crate::sync::IrqSpinlock, alloc::collections::BTreeSet, conquer_once::spin::Lazy,
+    core::ops::DerefMut,
 };
 
 pub(crate) type Pid = i32;
 
-static GENERATOR: Lazy<Spinlock<Generator>> = Lazy::new(|| Spinlock::new(Generator::new()));
+static GENERATOR: Lazy<IrqSpinlock<Generator>> = Lazy::new(|| IrqSpinlock::new(Generator::new()));
 
 pub(super) fn generate() -> Pid {
     lock_generator().generate()
 }
 
 fn lock_generator() -> impl DerefMut<Target = Generator> {
-    GENERATOR.try_lock().expect("Failed to lock `GENERATOR`.")
+    GENERATOR.lock()
 }
 
 #[derive(Default)]
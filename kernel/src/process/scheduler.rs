@@ -3,40 +3,72 @@ use {
         context::Context,
         priority::{Priority, LEAST_PRIORITY},
         receive_from::ReceiveFrom,
+        timer_wheel::TimerWheel,
         Pid,
     },
     crate::{
-        mem::{self, accessor::Single, paging},
-        process::{status::Status, Process},
-        tss,
+        fpu,
+        mem::{
+            self,
+            accessor::Single,
+            address_space::{AddressSpace, SegmentationFault},
+            allocator::slab::{queue::Queue, SlabBox},
+        },
+        percpu,
+        process::{status::Status, Process, PID_QUEUE_NODES, PROCESSES},
+        smp::{self, MAX_CPUS},
+        sync::{IrqSpinlock, IrqSpinlockGuard},
+        syscall::SavedRegisters,
+        time, tss,
     },
-    alloc::collections::{BTreeMap, VecDeque},
+    alloc::{collections::BTreeMap, vec::Vec},
     array_init::array_init,
     conquer_once::spin::Lazy,
+    core::time::Duration,
+    log::error,
     message::Message,
-    spinning_top::{Spinlock, SpinlockGuard},
-    x86_64::{instructions::interrupts::without_interrupts, PhysAddr, VirtAddr},
+    x86_64::{
+        instructions::interrupts::without_interrupts, structures::idt::PageFaultErrorCode,
+        PhysAddr, VirtAddr,
+    },
 };
 
-static SCHEDULER: Lazy<Spinlock<Scheduler>> = Lazy::new(|| Spinlock::new(Scheduler::new()));
+/// The processes of all processors.
+///
+/// Lock this before the state of any processor in [`CPUS`], and never lock this while holding
+/// one of them.
+static PROCESS_TABLE: Lazy<IrqSpinlock<ProcessTable>> =
+    Lazy::new(|| IrqSpinlock::new(ProcessTable::new()));
 
-pub(crate) fn switch() {
-    let mut manager = lock();
+// A constant is required as an array initializer because the locks are not `Copy`.
+#[allow(clippy::declare_interior_mutable_const)]
+const UNREGISTERED: IrqSpinlock<Option<Cpu>> = IrqSpinlock::new(None);
 
-    if let Some((current_context, next_context)) = manager.try_switch() {
-        drop(manager);
+/// The scheduling state of each processor indexed by the CPU number.
+///
+/// Each processor has its own lock, so that a processor checking its sleeping processes on a
+/// timer tick does not wait for the others.
+static CPUS: [IrqSpinlock<Option<Cpu>>; MAX_CPUS] = [UNREGISTERED; MAX_CPUS];
 
-        Context::switch(current_context, next_context);
-    }
+pub(crate) fn switch() {
+    // Releasing the lock must not enable the interrupts before `Context::switch` saves the
+    // registers. Otherwise an interrupt handler may switch the processes while the next process
+    // is already recorded as running, and save the registers to its context.
+    without_interrupts(|| {
+        let contexts = lock().try_switch();
+
+        if let Some((current_context, next_context)) = contexts {
+            Context::switch(current_context, next_context);
+        }
+    });
 }
 
 pub(crate) fn send(msg: VirtAddr, to: Pid) {
-    // The kernel process calls this function, and the interrupts may be enabled at that time. If
-    // we forget to disable interrupts, a timer interrupt may happen when the kernel process holds
-    // the lock of the process scheduler, and the subsequent process fails to lock the scheduler
-    // because the previous process already locks it. Thus, we disable the interrupts.
+    // The interrupts are disabled until the process switches, so that an interrupt handler does
+    // not switch the processes between changing the state of the running process and `switch`.
     without_interrupts(|| {
         lock().send(msg, to);
+
         switch();
     });
 }
@@ -59,69 +91,189 @@ pub(crate) fn receive_from(msg_buf: VirtAddr, from: Pid) {
     });
 }
 
+/// Blocks the running process until the monotonic clock reaches `deadline`.
+///
+/// This function returns immediately if the deadline has passed.
+pub(crate) fn sleep_until(deadline: Duration) {
+    // Ditto as `send` for `without_interrupts`.
+    without_interrupts(|| {
+        lock().sleep_until(deadline);
+
+        switch();
+    });
+}
+
+/// Wakes the processes of the running processor whose deadlines have passed.
+///
+/// The timer interrupt handler calls this function on every tick.
+pub(crate) fn wake_sleepers() {
+    let expired = with_current_cpu(|cpu| cpu.timer_wheel.expire(time::now()));
+
+    if !expired.is_empty() {
+        lock().wake_sleepers(expired);
+    }
+}
+
+/// Calls `f` with the address space of the running process.
+pub(crate) fn with_current_address_space<T>(f: impl FnOnce(&mut AddressSpace) -> T) -> T {
+    f(&mut lock().running_as_mut().address_space)
+}
+
+pub(crate) fn handle_page_fault(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), SegmentationFault> {
+    let mut table = lock();
+    let running = running();
+
+    let p = table.process_as_mut(running);
+    let p = p.ok_or(SegmentationFault::InvalidAccess(addr))?;
+
+    p.address_space.handle_page_fault(addr, error_code)
+}
+
+/// Terminates the running process because of the segmentation fault.
+///
+/// The memory of the process is freed after the scheduler switches to another process.
+pub(crate) fn exit_on_segmentation_fault(fault: SegmentationFault) -> ! {
+    let mut table = lock();
+    let p = table.running_as_mut();
+
+    error!(
+        "The process {} (PID: {}) is killed. {}",
+        p.name, p.pid, fault
+    );
+
+    p.status = Status::Exited;
+
+    drop(table);
+
+    switch();
+
+    unreachable!("The exited process is scheduled again.");
+}
+
+/// Loads the FPU and SIMD state of the running process to the registers, saving the state of the
+/// previous owner.
+pub(crate) fn handle_device_not_available() {
+    lock().take_fpu();
+}
+
+/// Creates a copy of the running process, and returns the PID of the new process.
+pub(crate) fn fork(saved: &SavedRegisters) -> Pid {
+    let mut table = lock();
+
+    // The registers may hold a newer state than the saved one.
+    table.save_fpu_of_running();
+
+    let child = table.running_as_mut().fork(saved);
+    let pid = child.id();
+
+    table.add_process_as_runnable(child);
+
+    pid
+}
+
+/// Returns the name of the process whose kernel stack overflowed to `addr`.
+///
+/// This function returns [`None`] if `addr` is not in the guard page of any kernel stack, or the
+/// process table is locked by the code which overflowed.
+pub(crate) fn process_overflowing_kernel_stack(addr: VirtAddr) -> Option<&'static str> {
+    let table = PROCESS_TABLE.try_lock()?;
+
+    let mut processes = table.processes.values();
+    let p = processes.find(|p| p.kernel_stack.guard_contains(addr))?;
+
+    Some(p.name)
+}
+
+/// Returns the name of the running process without locking the process table.
 pub(crate) fn current_process_name() -> &'static str {
-    lock().current_process_name()
+    let p = percpu::running_process();
+
+    assert!(!p.is_null(), "The processor is not registered.");
+
+    // SAFETY: The running process is not freed until another process runs on this processor, and
+    // its name is never modified.
+    unsafe { (*p).name }
 }
 
 pub(super) fn add_process_as_runnable(p: Process) {
     lock().add_process_as_runnable(p);
 }
 
+/// Registers the running processor and its idle process.
 pub(super) fn init() {
-    lock().init();
+    lock().add_cpu();
 }
 
-#[no_mangle]
-fn current_kernel_stack_bottom() -> VirtAddr {
-    lock().current_kernel_stack_bottom()
-}
+/// The processes and the round-robin assignment of them to the processors.
+///
+/// A process runs only on the processor to which it is assigned when it is added, so no two
+/// processors touch the context of the same process.
+struct ProcessTable {
+    processes: BTreeMap<Pid, SlabBox<Process>>,
 
-struct Scheduler {
-    processes: BTreeMap<Pid, Process>,
+    /// The CPU numbers of the registered processors.
+    cpus: Vec<usize>,
 
-    runnable_pids: RunnablePids,
-
-    running: Pid,
+    /// The counter to assign new processes to the processors in the round-robin order.
+    next_cpu: usize,
 }
-impl Scheduler {
+impl ProcessTable {
     fn new() -> Self {
         Self {
             processes: BTreeMap::new(),
 
-            runnable_pids: RunnablePids::new(),
+            cpus: Vec::new(),
 
-            running: 0,
+            next_cpu: 0,
         }
     }
 
-    fn init(&mut self) {
-        self.add_idle_process_as_running();
-    }
+    fn add_cpu(&mut self) {
+        let cpu = smp::current();
 
-    fn add_idle_process_as_running(&mut self) {
-        let idle = Process::idle();
+        let mut idle = Process::idle();
+        idle.cpu = cpu;
 
-        assert_eq!(idle.pid, 0, "Wrong PID for the idle process.");
         assert_eq!(
             idle.status,
             Status::Running,
             "The idle process should be running."
         );
 
-        let r = self.processes.insert(idle.pid, idle);
+        let pid = idle.pid;
 
+        let r = self.processes.insert(pid, SlabBox::new(idle, &PROCESSES));
         assert!(r.is_none(), "Duplicated idle process.");
+
+        register_cpu(cpu, pid);
+        self.cpus.push(cpu);
+
+        percpu::set_running_process(self.running_as_mut());
     }
 
-    fn add_process_as_runnable(&mut self, p: Process) {
+    fn add_process_as_runnable(&mut self, mut p: Process) {
+        p.cpu = self.assign_cpu();
+
         let pid = p.id();
-        let priority = p.priority;
 
-        let r = self.processes.insert(pid, p);
+        let r = self.processes.insert(pid, SlabBox::new(p, &PROCESSES));
 
         assert!(r.is_none(), "Duplicated process with PID {}.", pid);
 
-        self.runnable_pids.push(pid, priority);
+        push_runnable(self.process_as_ref(pid).expect("No such process."));
+    }
+
+    fn assign_cpu(&mut self) -> usize {
+        assert!(!self.cpus.is_empty(), "No processor is registered.");
+
+        let i = self.next_cpu % self.cpus.len();
+
+        self.next_cpu = self.next_cpu.wrapping_add(1);
+
+        self.cpus[i]
     }
 
     fn wake(&mut self, pid: Pid) {
@@ -135,9 +287,31 @@ impl Scheduler {
 
         p.status = Status::Runnable;
 
-        let priority = p.priority;
+        push_runnable(p);
+    }
+
+    fn sleep_until(&mut self, deadline: Duration) {
+        if deadline <= time::now() {
+            return;
+        }
+
+        let p = self.running_as_mut();
+        p.status = Status::Sleeping;
+
+        let pid = p.pid;
 
-        self.runnable_pids.push(pid, priority);
+        with_current_cpu(|cpu| cpu.timer_wheel.insert(deadline, pid));
+    }
+
+    /// Wakes the processes in `expired` which are still sleeping.
+    fn wake_sleepers(&mut self, expired: Vec<Pid>) {
+        for pid in expired {
+            let sleeping = self.process_as_ref(pid).map(|p| p.status) == Some(Status::Sleeping);
+
+            if sleeping {
+                self.wake(pid);
+            }
+        }
     }
 
     fn send(&mut self, msg: VirtAddr, to: Pid) {
@@ -156,43 +330,81 @@ impl Scheduler {
         Switcher(self).try_switch()
     }
 
-    fn current_process_name(&self) -> &'static str {
-        self.running_as_ref().name
+    fn take_fpu(&mut self) {
+        fpu::enable();
+
+        let running = running();
+        let fpu_owner = with_current_cpu(|cpu| cpu.fpu_owner);
+
+        if fpu_owner == Some(running) {
+            return;
+        }
+
+        if let Some(owner) = fpu_owner.and_then(|pid| self.process_as_mut(pid)) {
+            let state = owner.fpu.as_mut().expect("The FPU owner has no save area.");
+
+            // SAFETY: `CR0.TS` is cleared above.
+            unsafe { state.save() };
+        }
+
+        let p = self.running_as_mut();
+        let state = p.fpu.get_or_insert_with(fpu::State::new);
+
+        // SAFETY: `CR0.TS` is cleared above.
+        unsafe { state.restore() };
+
+        with_current_cpu(|cpu| cpu.fpu_owner = Some(running));
     }
 
-    fn current_kernel_stack_bottom(&self) -> VirtAddr {
-        self.running_as_ref().kernel_stack_bottom_addr()
+    fn save_fpu_of_running(&mut self) {
+        if with_current_cpu(|cpu| cpu.fpu_owner) != Some(running()) {
+            return;
+        }
+
+        let p = self.running_as_mut();
+        let state = p.fpu.as_mut().expect("The FPU owner has no save area.");
+
+        // SAFETY: `CR0.TS` is cleared while the running process owns the FPU.
+        unsafe { state.save() };
+    }
+
+    /// Translates `v` in the address space of the running process.
+    fn virt_to_phys(&mut self, v: VirtAddr) -> PhysAddr {
+        let p = self.running_as_mut();
+        let phys = p.address_space.translate_for_write(v);
+
+        phys.expect("Failed to convert a virtual address to physical one.")
     }
 
     fn running_as_ref(&self) -> &Process {
-        self.process_as_ref(self.running)
+        self.process_as_ref(running())
             .expect("Running process is not stored.")
     }
 
     fn running_as_mut(&mut self) -> &mut Process {
-        self.process_as_mut(self.running)
+        self.process_as_mut(running())
             .expect("Running process is not stored.")
     }
 
     fn process_as_ref(&self, pid: Pid) -> Option<&Process> {
-        self.processes.get(&pid)
+        self.processes.get(&pid).map(|p| &**p)
     }
 
     fn process_as_mut(&mut self, pid: Pid) -> Option<&mut Process> {
-        self.processes.get_mut(&pid)
+        self.processes.get_mut(&pid).map(|p| &mut **p)
     }
 }
 
 struct Sender<'a> {
-    manager: &'a mut Scheduler,
+    manager: &'a mut ProcessTable,
     msg: PhysAddr,
     to: Pid,
 }
 impl<'a> Sender<'a> {
-    fn new(manager: &'a mut Scheduler, msg: VirtAddr, to: Pid) -> Self {
-        assert_ne!(manager.running, to, "Tried to send a message to self.");
+    fn new(manager: &'a mut ProcessTable, msg: VirtAddr, to: Pid) -> Self {
+        assert_ne!(running(), to, "Tried to send a message to self.");
 
-        let msg = virt_to_phys(msg);
+        let msg = manager.virt_to_phys(msg);
 
         Self { manager, msg, to }
     }
@@ -209,11 +421,7 @@ impl<'a> Sender<'a> {
         let p = self.manager.process_as_ref(self.to);
         let p = p.expect("The receiver does not exist.");
 
-        [
-            Some(ReceiveFrom::Id(self.manager.running)),
-            Some(ReceiveFrom::Any),
-        ]
-        .contains(&p.receive_from)
+        [Some(ReceiveFrom::Id(running())), Some(ReceiveFrom::Any)].contains(&p.receive_from)
     }
 
     fn copy_msg_and_wake(&mut self) {
@@ -229,7 +437,7 @@ impl<'a> Sender<'a> {
         let dst = dst_proc.msg_ptr;
         let dst = dst.expect("Message destination address is not specified.");
 
-        unsafe { copy_msg(self.msg, dst, self.manager.running) }
+        unsafe { copy_msg(self.msg, dst, running()) }
     }
 
     fn remove_msg_buf(&mut self) {
@@ -262,7 +470,7 @@ impl<'a> Sender<'a> {
     }
 
     fn add_self_as_trying_to_send(&mut self) {
-        let pid = self.manager.running;
+        let pid = running();
 
         let dst = self.manager.process_as_mut(self.to);
         let dst = dst.expect("The receiver does not exist.");
@@ -287,13 +495,13 @@ impl<'a> Sender<'a> {
 }
 
 struct Receiver<'a> {
-    manager: &'a mut Scheduler,
+    manager: &'a mut ProcessTable,
     msg_buf: PhysAddr,
     from: ReceiveFrom,
 }
 impl<'a> Receiver<'a> {
-    fn new_from_any(manager: &'a mut Scheduler, msg_buf: VirtAddr) -> Self {
-        let msg_buf = virt_to_phys(msg_buf);
+    fn new_from_any(manager: &'a mut ProcessTable, msg_buf: VirtAddr) -> Self {
+        let msg_buf = manager.virt_to_phys(msg_buf);
 
         Self {
             manager,
@@ -302,13 +510,10 @@ impl<'a> Receiver<'a> {
         }
     }
 
-    fn new_from(manager: &'a mut Scheduler, msg_buf: VirtAddr, from: Pid) -> Self {
-        assert_ne!(
-            manager.running, from,
-            "Tried to receive a message from self."
-        );
+    fn new_from(manager: &'a mut ProcessTable, msg_buf: VirtAddr, from: Pid) -> Self {
+        assert_ne!(running(), from, "Tried to receive a message from self.");
 
-        let msg_buf = virt_to_phys(msg_buf);
+        let msg_buf = manager.virt_to_phys(msg_buf);
 
         Self {
             manager,
@@ -407,15 +612,51 @@ impl<'a> Receiver<'a> {
     }
 }
 
-struct Switcher<'a>(&'a mut Scheduler);
+struct Switcher<'a>(&'a mut ProcessTable);
 impl Switcher<'_> {
     fn try_switch(mut self) -> Option<(*mut Context, *mut Context)> {
         #[cfg(feature = "qemu_test")]
         crate::tests::process::count_switch();
 
+        self.reap_exited_processes();
+
         let next = self.update_runnable_pids_and_return_next_pid();
 
-        (self.0.running != next).then(|| self.switch_to(next))
+        if running() == next {
+            // Another processor may have woken the running process before it sleeps.
+            self.0.running_as_mut().status = Status::Running;
+
+            None
+        } else {
+            Some(self.switch_to(next))
+        }
+    }
+
+    /// Frees the exited processes assigned to the running processor.
+    fn reap_exited_processes(&mut self) {
+        let running = running();
+        let cpu = smp::current();
+
+        let exited = self
+            .0
+            .processes
+            .values()
+            .filter(|p| p.status == Status::Exited && p.pid != running && p.cpu == cpu)
+            .map(|p| p.pid)
+            .collect::<Vec<_>>();
+
+        for pid in exited {
+            let p = self.0.processes.remove(&pid);
+            let mut p = p.expect("No such process.");
+
+            p.release_address_space();
+
+            with_current_cpu(|cpu| {
+                if cpu.fpu_owner == Some(pid) {
+                    cpu.fpu_owner = None;
+                }
+            });
+        }
     }
 
     fn update_runnable_pids_and_return_next_pid(&mut self) -> Pid {
@@ -423,7 +664,9 @@ impl Switcher<'_> {
             self.push_current_process_as_runnable();
         }
 
-        self.0.runnable_pids.pop().expect("No runnable PIDs.")
+        let next = with_current_cpu(|cpu| cpu.runnable_pids.pop());
+
+        next.expect("No runnable PIDs.")
     }
 
     fn push_current_process_as_runnable(&mut self) {
@@ -433,43 +676,40 @@ impl Switcher<'_> {
 
         let priority = process.priority;
 
-        self.0.runnable_pids.push(pid, priority);
+        with_current_cpu(|cpu| cpu.runnable_pids.push(pid, priority));
     }
 
     fn switch_to(&mut self, next: Pid) -> (*mut Context, *mut Context) {
-        unsafe {
-            self.assert_kernel_stack_is_not_smashed(next);
-        }
-
         self.switch_kernel_stack(next);
 
         if self.0.running_as_ref().status == Status::Running {
             self.0.running_as_mut().status = Status::Runnable;
         }
 
-        let current = self.0.running;
+        let current = running();
+
+        let owns_fpu = with_current_cpu(|cpu| {
+            cpu.running = next;
 
-        self.0.running = next;
+            cpu.fpu_owner == Some(next)
+        });
 
         let next_proc = self.0.process_as_mut(next);
         let next_proc = next_proc.expect("No such process.");
 
         next_proc.status = Status::Running;
 
-        (self.context(current), self.context(next))
-    }
+        percpu::set_running_process(next_proc);
 
-    /// # Safety
-    ///
-    /// Do not call this function for the process which is running and whose kernel stack is
-    /// currently used.
-    unsafe fn assert_kernel_stack_is_not_smashed(&self, pid: Pid) {
-        let p = self.0.process_as_ref(pid);
-        let p = p.expect("No such process.");
-
-        unsafe {
-            p.assert_kernel_stack_is_not_smashed();
+        // The first FPU or SIMD instruction of `next` raises `#NM` unless the registers already
+        // hold its state.
+        if owns_fpu {
+            fpu::enable();
+        } else {
+            fpu::disable();
         }
+
+        (self.context(current), self.context(next))
     }
 
     fn switch_kernel_stack(&self, next: Pid) {
@@ -477,6 +717,7 @@ impl Switcher<'_> {
         let p = p.expect("No such process.");
 
         tss::set_privilege_stack(p.kernel_stack_bottom_addr());
+        percpu::set_kernel_stack_bottom(p.kernel_stack_bottom_addr());
     }
 
     fn context(&mut self, pid: Pid) -> *mut Context {
@@ -501,20 +742,73 @@ unsafe fn copy_msg(src: PhysAddr, dst: PhysAddr, sender_slot_id: Pid) {
     dst.write_volatile(src.read_volatile());
 }
 
-fn virt_to_phys(v: VirtAddr) -> PhysAddr {
-    paging::translate_addr(v).expect("Failed to convert a virtual address to physical one.")
+fn lock() -> IrqSpinlockGuard<'static, ProcessTable> {
+    PROCESS_TABLE.lock()
+}
+
+fn running() -> Pid {
+    with_current_cpu(|cpu| cpu.running)
 }
 
-fn lock() -> SpinlockGuard<'static, Scheduler> {
-    SCHEDULER
-        .try_lock()
-        .expect("Failed to acquire the lock of `PROCESSES`.")
+/// Pushes `p` to the run queue of its processor, and makes the processor run the scheduler if it
+/// is another one, which may be halted.
+fn push_runnable(p: &Process) {
+    with_cpu(p.cpu, |cpu| cpu.runnable_pids.push(p.pid, p.priority));
+
+    if p.cpu != smp::current() {
+        smp::reschedule(p.cpu);
+    }
+}
+
+fn register_cpu(cpu: usize, idle: Pid) {
+    let mut state = CPUS[cpu].lock();
+
+    assert!(
+        state.is_none(),
+        "The processor {} is registered twice.",
+        cpu
+    );
+
+    *state = Some(Cpu::new(idle));
+}
+
+fn with_current_cpu<T>(f: impl FnOnce(&mut Cpu) -> T) -> T {
+    with_cpu(smp::current(), f)
+}
+
+fn with_cpu<T>(cpu: usize, f: impl FnOnce(&mut Cpu) -> T) -> T {
+    let mut state = CPUS[cpu].lock();
+
+    f(state.as_mut().expect("The processor is not registered."))
+}
+
+/// The scheduling state of a processor.
+struct Cpu {
+    running: Pid,
+
+    runnable_pids: RunnablePids,
+
+    /// The process whose FPU and SIMD state is in the registers.
+    fpu_owner: Option<Pid>,
+
+    /// The sleeping processes assigned to this processor.
+    timer_wheel: TimerWheel,
+}
+impl Cpu {
+    fn new(idle: Pid) -> Self {
+        Self {
+            running: idle,
+            runnable_pids: RunnablePids::new(),
+            fpu_owner: None,
+            timer_wheel: TimerWheel::new(time::now()),
+        }
+    }
 }
 
-struct RunnablePids([VecDeque<Pid>; LEAST_PRIORITY.as_usize() + 1]);
+struct RunnablePids([Queue<Pid>; LEAST_PRIORITY.as_usize() + 1]);
 impl RunnablePids {
     fn new() -> Self {
-        Self(array_init(|_| VecDeque::new()))
+        Self(array_init(|_| Queue::new(&PID_QUEUE_NODES)))
     }
 
     fn push(&mut self, pid: Pid, priority: Priority) {
@@ -522,6 +816,6 @@ impl RunnablePids {
     }
 
     fn pop(&mut self) -> Option<Pid> {
-        self.0.iter_mut().find_map(VecDeque::pop_front)
+        self.0.iter_mut().find_map(Queue::pop_front)
     }
 }
@@ -7,6 +7,13 @@ use {
 pub(super) enum Status {
     Running,
     Runnable,
-    Sending { to: Pid, message: PhysAddr },
+    Sending {
+        to: Pid,
+        message: PhysAddr,
+    },
     Receiving(ReceiveFrom),
+    /// The process waits for the timer wheel to wake it.
+    Sleeping,
+    /// The process is terminated and waits for its memory to be freed.
+    Exited,
 }
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    super::Pid,
+    crate::interrupt::timer::TICK,
+    alloc::vec::Vec,
+    array_init::array_init,
+    core::{convert::TryFrom, time::Duration},
+};
+
+const NUM_OF_SLOTS: usize = 256;
+
+/// The sleeping processes, hashed by the timer tick which passes their deadlines.
+///
+/// A slot holds the deadlines of every turn of the wheel, so expiring a slot keeps those of the
+/// later turns.
+pub(super) struct TimerWheel {
+    slots: [Vec<Sleeper>; NUM_OF_SLOTS],
+
+    /// The last tick whose slot is expired.
+    current: u64,
+}
+impl TimerWheel {
+    pub(super) fn new(now: Duration) -> Self {
+        Self {
+            slots: array_init(|_| Vec::new()),
+            current: tick_of(now),
+        }
+    }
+
+    pub(super) fn insert(&mut self, deadline: Duration, pid: Pid) {
+        // The first tick which starts after the deadline.
+        let tick = (tick_of(deadline) + 1).max(self.current + 1);
+
+        self.slots[slot_of(tick)].push(Sleeper { deadline, pid });
+    }
+
+    /// Removes the processes whose deadlines are passed at `now`, and returns their PIDs.
+    pub(super) fn expire(&mut self, now: Duration) -> Vec<Pid> {
+        let now_tick = tick_of(now);
+
+        // A turn of the wheel visits every slot, so the ticks missed more than that need no more
+        // visits.
+        let end = now_tick.min(self.current + u64::try_from(NUM_OF_SLOTS).unwrap());
+
+        let mut expired = Vec::new();
+
+        for tick in self.current + 1..=end {
+            self.slots[slot_of(tick)].retain(|s| {
+                let passed = s.deadline <= now;
+
+                if passed {
+                    expired.push(s.pid);
+                }
+
+                !passed
+            });
+        }
+
+        self.current = self.current.max(now_tick);
+
+        expired
+    }
+}
+
+struct Sleeper {
+    deadline: Duration,
+    pid: Pid,
+}
+
+fn tick_of(t: Duration) -> u64 {
+    u64::try_from(t.as_nanos() / TICK.as_nanos()).unwrap()
+}
+
+fn slot_of(tick: u64) -> usize {
+    usize::try_from(tick % u64::try_from(NUM_OF_SLOTS).unwrap()).unwrap()
+}
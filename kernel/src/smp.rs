@@ -0,0 +1,457 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Starting the application processors and identifying the running processor.
+//!
+//! The processors are numbered from 0, and the bootstrap processor is 0.
+
+use {
+    crate::{
+        interrupt::{apic::local, timer},
+        mem::{
+            self,
+            allocator::{self, stack::KernelStack},
+            paging,
+        },
+        percpu,
+    },
+    acpi::{platform::ProcessorState, AcpiTables},
+    alloc::{vec, vec::Vec},
+    conquer_once::spin::OnceCell,
+    core::{
+        arch::global_asm,
+        convert::TryFrom,
+        hint, ptr,
+        sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    log::{info, warn},
+    os_units::NumOfPages,
+    syscalls::CacheType,
+    x86_64::{
+        structures::paging::{PageTable, PageTableFlags, Size4KiB},
+        PhysAddr, VirtAddr,
+    },
+};
+
+/// The maximum number of processors which the kernel uses.
+pub(crate) const MAX_CPUS: usize = 16;
+
+/// The vector of the IPI which makes a processor run the scheduler.
+pub(crate) const RESCHEDULE_VECTOR: u8 = 0x21;
+
+const NUM_OF_STACK_PAGES: usize = 4;
+
+/// The code page and the three page tables.
+const NUM_OF_TRAMPOLINE_PAGES: usize = 4;
+
+const INIT_IPI: u32 = 0x4500;
+const STARTUP_IPI: u32 = 0x4600;
+
+/// The acknowledgement while the bootstrap processor waits for an application processor to read
+/// the arguments.
+const WAITING: u64 = 0;
+
+/// The acknowledgement after an application processor reads the arguments.
+const ACKNOWLEDGED: u64 = 1;
+
+/// The acknowledgement after the bootstrap processor gives up starting an application processor.
+///
+/// The processor halts in the trampoline if it starts after this.
+const CANCELLED: u64 = 2;
+
+/// The local APIC IDs of the processors indexed by the CPU number.
+static APIC_IDS: OnceCell<Vec<u32>> = OnceCell::uninit();
+
+/// The number of the application processors which finished the initialization.
+static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts the application processors listed in the MADT.
+///
+/// The processors beyond [`MAX_CPUS`] are not started.
+pub(crate) fn init(table: &AcpiTables<allocator::acpi::Mapper>) {
+    let mut ids = vec![local::id()];
+
+    ids.extend(application_processors(table).into_iter().take(MAX_CPUS - 1));
+
+    APIC_IDS.init_once(|| ids);
+
+    let ids = apic_ids();
+
+    if ids.len() > 1 {
+        start_all(table, &ids[1..]);
+    }
+
+    info!(
+        "{} processors are running.",
+        STARTED.load(Ordering::Acquire) + 1
+    );
+}
+
+/// Returns the CPU number of the running processor.
+pub(crate) fn current() -> usize {
+    percpu::cpu()
+}
+
+/// Makes the processor `cpu` run the scheduler.
+pub(crate) fn reschedule(cpu: usize) {
+    local::send_ipi(apic_ids()[cpu], RESCHEDULE_VECTOR.into());
+}
+
+/// Tells the bootstrap processor that the running application processor is initialized.
+pub(crate) fn mark_as_started() {
+    STARTED.fetch_add(1, Ordering::Release);
+}
+
+fn application_processors(table: &AcpiTables<allocator::acpi::Mapper>) -> Vec<u32> {
+    let info = table.platform_info().ok().and_then(|p| p.processor_info);
+
+    info.map_or_else(Vec::new, |info| {
+        info.application_processors
+            .iter()
+            .filter(|p| matches!(p.state, ProcessorState::WaitingForSipi))
+            .map(|p| p.local_apic_id)
+            .collect()
+    })
+}
+
+fn start_all(table: &AcpiTables<allocator::acpi::Mapper>, ids: &[u32]) {
+    if let Some(trampoline) = Trampoline::new() {
+        let starter = Starter { table, trampoline };
+
+        for (i, &id) in ids.iter().enumerate() {
+            let cpu = i + 1;
+
+            if !starter.start(cpu, id) {
+                warn!("The processor {} (APIC ID: {}) did not start.", cpu, id);
+            }
+        }
+    } else {
+        warn!("No memory below 1 MiB to start the application processors.");
+    }
+}
+
+struct Starter<'a> {
+    table: &'a AcpiTables<allocator::acpi::Mapper>,
+    trampoline: Trampoline,
+}
+impl Starter<'_> {
+    /// Starts the processor whose local APIC ID is `id` as the processor `cpu`, and returns `true`
+    /// if it finishes the initialization.
+    fn start(&self, cpu: usize, id: u32) -> bool {
+        let stack = KernelStack::new(NumOfPages::new(NUM_OF_STACK_PAGES));
+
+        self.trampoline.set_arguments(cpu, stack.bottom_addr());
+
+        // The processor uses the stack until the kernel halts.
+        core::mem::forget(stack);
+
+        let started = STARTED.load(Ordering::Acquire);
+
+        if !self.send_init_sipi_sipi(id) {
+            return false;
+        }
+
+        // The processor has read the arguments, and nothing can stop it from initializing itself.
+        while STARTED.load(Ordering::Acquire) == started {
+            hint::spin_loop();
+        }
+
+        true
+    }
+
+    /// Sends the INIT-SIPI-SIPI sequence to the processor whose local APIC ID is `id`, and returns
+    /// `true` if it reads the arguments.
+    fn send_init_sipi_sipi(&self, id: u32) -> bool {
+        local::send_ipi(id, INIT_IPI);
+        timer::wait_microseconds(self.table, 10_000);
+
+        for _ in 0..2 {
+            if self.trampoline.is_acknowledged() {
+                return true;
+            }
+
+            local::send_ipi(id, STARTUP_IPI | self.trampoline.vector());
+            timer::wait_microseconds(self.table, 200);
+        }
+
+        self.wait_for_acknowledgement()
+    }
+
+    fn wait_for_acknowledgement(&self) -> bool {
+        for _ in 0..1000 {
+            if self.trampoline.is_acknowledged() {
+                return true;
+            }
+
+            timer::wait_microseconds(self.table, 100);
+        }
+
+        // The processor may read the arguments after the last check.
+        !self.trampoline.cancel()
+    }
+}
+
+fn apic_ids<'a>() -> &'a [u32] {
+    APIC_IDS.get().expect("`smp::init` is not called.")
+}
+
+/// The arguments which the trampoline passes to the application processor.
+#[repr(C)]
+struct Arguments {
+    /// The PML4 of the trampoline. The real mode code loads only the lower 32 bits.
+    cr3: u64,
+    /// The address of `ap_main`.
+    entry: u64,
+    stack: u64,
+    cpu: u64,
+}
+
+/// The memory in the first megabyte where the application processors start in the real mode.
+///
+/// The first page contains the code copied from `AP_TRAMPOLINE_START`. The rest are the page
+/// tables which map the kernel and identity-map the first huge page, where the trampoline is.
+///
+/// The trampoline is never freed, as a processor which does not start in time may execute it
+/// later.
+struct Trampoline {
+    phys: PhysAddr,
+    virt: VirtAddr,
+}
+impl Trampoline {
+    fn new() -> Option<Self> {
+        let num_of_pages = NumOfPages::<Size4KiB>::new(NUM_OF_TRAMPOLINE_PAGES);
+
+        let phys = allocator::allocate_below_1mib(num_of_pages)?;
+        let virt = mem::map_pages_for_kernel(phys, num_of_pages.as_bytes(), CacheType::WriteBack);
+
+        let trampoline = Self { phys, virt };
+
+        trampoline.copy_code();
+        trampoline.build_page_tables();
+
+        Some(trampoline)
+    }
+
+    fn copy_code(&self) {
+        // SAFETY: Only the addresses are taken.
+        let (start, end) = unsafe {
+            (
+                ptr::addr_of!(AP_TRAMPOLINE_START),
+                ptr::addr_of!(AP_TRAMPOLINE_END),
+            )
+        };
+
+        let size = end as usize - start as usize;
+
+        assert!(size <= 0x1000, "The trampoline is larger than a page.");
+
+        // SAFETY: The code is in the kernel image, and the first page is mapped to `virt`.
+        unsafe {
+            ptr::copy_nonoverlapping(start, self.virt.as_mut_ptr(), size);
+        }
+    }
+
+    fn build_page_tables(&self) {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+        // SAFETY: The pages are mapped to `virt` and nobody else uses them.
+        let (pml4, pdpt, pd) = unsafe {
+            (
+                &mut *self.page(1).as_mut_ptr::<PageTable>(),
+                &mut *self.page(2).as_mut_ptr::<PageTable>(),
+                &mut *self.page(3).as_mut_ptr::<PageTable>(),
+            )
+        };
+
+        pml4.zero();
+        pdpt.zero();
+        pd.zero();
+
+        pd[0].set_addr(PhysAddr::new(0), flags | PageTableFlags::HUGE_PAGE);
+        pdpt[0].set_addr(self.frame(3), flags);
+        pml4[0].set_addr(self.frame(2), flags);
+
+        // `mem::init_ap` creates a new PML4 through the recursive entry.
+        pml4[510].set_addr(self.frame(1), flags);
+        pml4[511] = paging::level_4_table()[511].clone();
+    }
+
+    fn set_arguments(&self, cpu: usize, stack: VirtAddr) {
+        let arguments = Arguments {
+            cr3: self.frame(1).as_u64(),
+            entry: u64::try_from(crate::ap_main as usize).unwrap(),
+            stack: stack.as_u64(),
+            cpu: u64::try_from(cpu).unwrap(),
+        };
+
+        // SAFETY: The trampoline aligns the arguments to 8 bytes, and they are in the first page.
+        unsafe {
+            ptr::write_volatile(self.arguments().as_mut_ptr(), arguments);
+        }
+
+        // The processor reads the arguments only after it changes the acknowledgement from
+        // `WAITING`.
+        self.acknowledgement().store(WAITING, Ordering::Release);
+    }
+
+    fn is_acknowledged(&self) -> bool {
+        self.acknowledgement().load(Ordering::Acquire) == ACKNOWLEDGED
+    }
+
+    /// Makes the processor halt in the trampoline unless it has read the arguments, and returns
+    /// `true` if it is cancelled.
+    fn cancel(&self) -> bool {
+        let r = self.acknowledgement().compare_exchange(
+            WAITING,
+            CANCELLED,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
+
+        r.is_ok()
+    }
+
+    fn arguments(&self) -> VirtAddr {
+        // SAFETY: Only the addresses are taken.
+        let offset = unsafe {
+            ptr::addr_of!(AP_TRAMPOLINE_ARGUMENTS) as usize
+                - ptr::addr_of!(AP_TRAMPOLINE_START) as usize
+        };
+
+        self.virt + offset
+    }
+
+    /// Returns the acknowledgement which the trampoline places right after the arguments.
+    fn acknowledgement(&self) -> &AtomicU64 {
+        let addr = self.arguments() + core::mem::size_of::<Arguments>();
+
+        // SAFETY: The acknowledgement is aligned to 8 bytes, and it is in the first page, which
+        // is mapped while `self` lives.
+        unsafe { &*addr.as_ptr() }
+    }
+
+    /// Returns the vector of the Startup IPI, which is the page number of the code.
+    fn vector(&self) -> u32 {
+        u32::try_from(self.phys.as_u64() >> 12).unwrap()
+    }
+
+    fn page(&self, i: usize) -> VirtAddr {
+        self.virt + 0x1000 * i
+    }
+
+    fn frame(&self, i: u64) -> PhysAddr {
+        self.phys + 0x1000 * i
+    }
+}
+
+extern "C" {
+    static AP_TRAMPOLINE_START: u8;
+    static AP_TRAMPOLINE_ARGUMENTS: u8;
+    static AP_TRAMPOLINE_END: u8;
+}
+
+// The processor starts at `AP_TRAMPOLINE_START` in the real mode with CS set to the page of the
+// code. It enters the long mode directly with the page tables of the trampoline, and calls
+// `ap_main` with the kernel stack. Before reading the arguments, it changes the acknowledgement
+// from `WAITING` to `ACKNOWLEDGED`, or halts if the bootstrap processor has cancelled it.
+global_asm!(
+    "
+    .pushsection .rodata.ap_trampoline, \"a\"
+    .balign 16
+    .code16
+    .global AP_TRAMPOLINE_START
+AP_TRAMPOLINE_START:
+    cli
+    cld
+
+    mov ax, cs
+    mov ds, ax
+    xor ebx, ebx
+    mov bx, ax
+    shl ebx, 4
+
+    lea eax, [ebx + .Lap_gdt_offset]
+    mov dword ptr [.Lap_gdtr_offset + 2], eax
+    lea eax, [ebx + .Lap_long_mode_offset]
+    mov dword ptr [.Lap_far_pointer_offset], eax
+
+    lgdt [.Lap_gdtr_offset]
+
+    mov eax, cr4
+    or eax, 1 << 5
+    mov cr4, eax
+
+    mov eax, dword ptr [.Lap_arguments_offset]
+    mov cr3, eax
+
+    mov ecx, 0xc0000080
+    rdmsr
+    or eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    // PG, WP, NE, ET, MP and PE.
+    mov eax, 0x80010031
+    mov cr0, eax
+
+    .byte 0x66, 0xff, 0x2e
+    .word .Lap_far_pointer_offset
+
+    .code64
+.Lap_long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    xor eax, eax
+    mov fs, ax
+    mov gs, ax
+
+    mov ebx, ebx
+    mov eax, {waiting}
+    mov ecx, {acknowledged}
+    lock cmpxchg [rbx + .Lap_acknowledgement_offset], rcx
+    jne .Lap_halt
+
+    mov rsp, [rbx + .Lap_arguments_offset + 16]
+    mov rdi, [rbx + .Lap_arguments_offset + 24]
+    mov rax, [rbx + .Lap_arguments_offset + 8]
+    call rax
+    ud2
+
+.Lap_halt:
+    cli
+    hlt
+    jmp .Lap_halt
+
+    .balign 8
+.Lap_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+.Lap_gdtr:
+    .word .Lap_gdtr - .Lap_gdt - 1
+    .long 0
+.Lap_far_pointer:
+    .long 0
+    .word 0x08
+
+    .balign 8
+    .global AP_TRAMPOLINE_ARGUMENTS
+AP_TRAMPOLINE_ARGUMENTS:
+    .quad 0, 0, 0, 0
+.Lap_acknowledgement:
+    .quad {cancelled}
+    .global AP_TRAMPOLINE_END
+AP_TRAMPOLINE_END:
+
+    .set .Lap_gdt_offset, .Lap_gdt - AP_TRAMPOLINE_START
+    .set .Lap_gdtr_offset, .Lap_gdtr - AP_TRAMPOLINE_START
+    .set .Lap_long_mode_offset, .Lap_long_mode - AP_TRAMPOLINE_START
+    .set .Lap_far_pointer_offset, .Lap_far_pointer - AP_TRAMPOLINE_START
+    .set .Lap_arguments_offset, AP_TRAMPOLINE_ARGUMENTS - AP_TRAMPOLINE_START
+    .set .Lap_acknowledgement_offset, .Lap_acknowledgement - AP_TRAMPOLINE_START
+    .popsection
+    ",
+    waiting = const WAITING,
+    acknowledged = const ACKNOWLEDGED,
+    cancelled = const CANCELLED,
+);
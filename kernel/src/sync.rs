@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    crate::smp,
+    core::{
+        mem::ManuallyDrop,
+        ops::{Deref, DerefMut},
+        panic::Location,
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+    spinning_top::{Spinlock, SpinlockGuard},
+    x86_64::instructions::interrupts,
+};
+
+/// The value of [`IrqSpinlock::holder`] while nobody holds the lock.
+const NO_HOLDER: usize = usize::MAX;
+
+/// A spinlock which disables interrupts while it is held.
+///
+/// An interrupt handler running on the same processor never finds the lock held, and other
+/// processors spin until the holder releases it. Locking it again on the processor which holds
+/// it is a bug, such as a page fault while the lock is held, and causes a panic instead of a
+/// deadlock.
+///
+/// With the `lock_debug` feature, the lock also records where it is acquired, and the panic
+/// message shows it.
+pub(crate) struct IrqSpinlock<T> {
+    inner: Spinlock<T>,
+
+    /// The CPU number of the processor holding the lock, or [`NO_HOLDER`].
+    holder: AtomicUsize,
+
+    /// Where the lock is acquired. This is recorded only with the `lock_debug` feature.
+    locked_at: Spinlock<Option<&'static Location<'static>>>,
+}
+impl<T> IrqSpinlock<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self {
+            inner: Spinlock::new(value),
+            holder: AtomicUsize::new(NO_HOLDER),
+            locked_at: Spinlock::new(None),
+        }
+    }
+
+    /// # Panics
+    ///
+    /// This method panics if the running processor already holds the lock.
+    #[track_caller]
+    pub(crate) fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let enabled = interrupts::are_enabled();
+
+        interrupts::disable();
+
+        let cpu = smp::current();
+
+        if self.holder.load(Ordering::Relaxed) == cpu {
+            self.panic_on_recursion(cpu);
+        }
+
+        let guard = self.inner.lock();
+
+        self.acquired(cpu);
+
+        IrqSpinlockGuard {
+            lock: self,
+            guard: ManuallyDrop::new(guard),
+            enabled,
+        }
+    }
+
+    /// Returns [`None`] if the lock is held.
+    #[track_caller]
+    pub(crate) fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let enabled = interrupts::are_enabled();
+
+        interrupts::disable();
+
+        let guard = self.inner.try_lock();
+
+        if guard.is_none() && enabled {
+            interrupts::enable();
+        }
+
+        guard.map(|guard| {
+            self.acquired(smp::current());
+
+            IrqSpinlockGuard {
+                lock: self,
+                guard: ManuallyDrop::new(guard),
+                enabled,
+            }
+        })
+    }
+
+    /// Returns `true` if the running processor holds the lock.
+    ///
+    /// Unlike [`Spinlock::is_locked`], this returns `false` while another processor holds it.
+    pub(crate) fn is_held_by_current_cpu(&self) -> bool {
+        self.holder.load(Ordering::Relaxed) == smp::current()
+    }
+
+    /// Returns the pointer to the value without locking.
+    pub(crate) fn data_ptr(&self) -> *mut T {
+        self.inner.data_ptr()
+    }
+
+    #[track_caller]
+    fn acquired(&self, cpu: usize) {
+        self.holder.store(cpu, Ordering::Relaxed);
+
+        #[cfg(feature = "lock_debug")]
+        {
+            *self.locked_at.lock() = Some(Location::caller());
+        }
+    }
+
+    fn released(&self) {
+        #[cfg(feature = "lock_debug")]
+        {
+            *self.locked_at.lock() = None;
+        }
+
+        self.holder.store(NO_HOLDER, Ordering::Relaxed);
+    }
+
+    #[track_caller]
+    fn panic_on_recursion(&self, cpu: usize) -> ! {
+        let caller = Location::caller();
+
+        if let Some(locked_at) = *self.locked_at.lock() {
+            panic!(
+                "The processor {} locked a lock at {} which it already locked at {}.",
+                cpu, caller, locked_at
+            );
+        }
+
+        panic!(
+            "The processor {} locked a lock at {} which it already holds.",
+            cpu, caller
+        );
+    }
+}
+
+pub(crate) struct IrqSpinlockGuard<'a, T> {
+    lock: &'a IrqSpinlock<T>,
+    guard: ManuallyDrop<SpinlockGuard<'a, T>>,
+    /// Whether the interrupts were enabled before acquiring the lock.
+    enabled: bool,
+}
+impl<T> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+impl<T> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+impl<T> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.released();
+
+        // SAFETY: The guard is never used after this line.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+
+        if self.enabled {
+            interrupts::enable();
+        }
+    }
+}
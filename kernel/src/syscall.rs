@@ -1,19 +1,30 @@
 use {
     crate::{
-        gdt,
-        mem::{allocator, paging},
+        framebuffer, gdt,
+        mem::{accessor, frames_spanning, paging},
+        percpu,
         process::{self, Pid},
+        time,
+    },
+    core::{
+        arch::asm,
+        convert::{TryFrom, TryInto},
+        ffi::c_void,
+        mem,
+        panic::PanicInfo,
+        slice,
+        time::Duration,
     },
-    core::{arch::asm, convert::TryInto, ffi::c_void, panic::PanicInfo, slice},
     num_traits::FromPrimitive,
     os_units::{Bytes, NumOfPages},
+    syscalls::{CacheType, DmaConstraints, MemoryError},
     terminal::print,
     x86_64::{
         registers::{
             model_specific::{Efer, EferFlags, LStar, Msr, Star},
             rflags::RFlags,
         },
-        structures::paging::Size4KiB,
+        structures::paging::{PageSize, Size4KiB},
         PhysAddr, VirtAddr,
     },
 };
@@ -101,33 +112,34 @@ unsafe fn write_ia32_fmask(mask: RFlags) {
     }
 }
 
+/// The entry point of the system calls.
+///
+/// This function switches GS to the per-CPU area and the stack to the kernel stack of the running
+/// process, which `percpu` holds, and saves the registers which `sysretq` and `fork` need.
 #[naked]
 #[allow(clippy::too_many_lines)]
 unsafe extern "sysv64" fn prepare_syscall() {
     unsafe {
         asm!(
             "
+            swapgs
+
+            mov gs:[{user_stack}], rsp
+            mov rsp, gs:[{kernel_stack_bottom}]
+
+            push qword ptr gs:[{user_stack}]
             push rcx
             push r11
-
             push rbp
-            mov rbp, rsp
 
-            push rdi
-            push rsi
-            push rdx
-            push rax
+            push r15
+            push r14
+            push r13
+            push r12
+            push rbx
+            mov r8, rsp
 
-            call current_kernel_stack_bottom
-
-            mov rcx, rax
-
-            pop rax
-            pop rdx
-            pop rsi
-            pop rdi
-
-            mov rsp, rcx
+            sub rsp, 8
 
             mov rcx, rdx
             mov rdx, rsi
@@ -136,32 +148,93 @@ unsafe extern "sysv64" fn prepare_syscall() {
 
             call select_proper_syscall
 
-            mov rsp, rbp
-            pop rbp
+            add rsp, 8
 
+            pop rbx
+            pop r12
+            pop r13
+            pop r14
+            pop r15
+
+            pop rbp
             pop r11
             pop rcx
+            pop rsp
+
+            swapgs
 
             sysretq",
+            user_stack = const percpu::USER_STACK_OFFSET,
+            kernel_stack_bottom = const percpu::KERNEL_STACK_BOTTOM_OFFSET,
             options(noreturn)
         );
     }
 }
 
+/// The child process of `fork` starts from this function.
+///
+/// The child returns to the user space as the parent does, but with 0 as the return value.
+/// `Context::forked` passes the user stack pointer in RDX.
+#[naked]
+pub(crate) extern "sysv64" fn return_from_fork() {
+    unsafe {
+        asm!(
+            "
+            xor eax, eax
+
+            mov rsp, rdx
+
+            swapgs
+
+            sysretq",
+            options(noreturn)
+        );
+    }
+}
+
+/// The registers of the user process which `prepare_syscall` saves on the kernel stack.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct SavedRegisters {
+    pub(crate) rbx: u64,
+    pub(crate) r12: u64,
+    pub(crate) r13: u64,
+    pub(crate) r14: u64,
+    pub(crate) r15: u64,
+    pub(crate) rbp: u64,
+    /// The value of RFLAGS, which `syscall` saves in R11.
+    pub(crate) rflags: u64,
+    /// The return address, which `syscall` saves in RCX.
+    pub(crate) rip: u64,
+    pub(crate) user_stack: u64,
+}
+
 #[no_mangle]
 #[allow(clippy::too_many_arguments)]
-unsafe extern "sysv64" fn select_proper_syscall(idx: u64, a1: u64, a2: u64, a3: u64) -> u64 {
+unsafe extern "sysv64" fn select_proper_syscall(
+    idx: u64,
+    a1: u64,
+    a2: u64,
+    a3: u64,
+    saved: *const SavedRegisters,
+) -> u64 {
     if let Some(t) = FromPrimitive::from_u64(idx) {
         // SAFETY: At least the index is correct. The caller must ensure that
         // the all arguments are correctly passed.
-        unsafe { select_proper_syscall_unchecked(t, a1, a2, a3) }
+        unsafe { select_proper_syscall_unchecked(t, a1, a2, a3, saved) }
     } else {
         panic!("Unrecognized system call index: {}", idx)
     }
 }
 
 #[allow(clippy::too_many_arguments, clippy::too_many_lines)]
-unsafe fn select_proper_syscall_unchecked(ty: syscalls::Ty, a1: u64, a2: u64, a3: u64) -> u64 {
+unsafe fn select_proper_syscall_unchecked(
+    ty: syscalls::Ty,
+    a1: u64,
+    a2: u64,
+    a3: u64,
+    saved: *const SavedRegisters,
+) -> u64 {
     match ty {
         syscalls::Ty::AllocatePages => {
             sys_allocate_pages(NumOfPages::new(a1.try_into().unwrap())).as_u64()
@@ -170,7 +243,7 @@ unsafe fn select_proper_syscall_unchecked(ty: syscalls::Ty, a1: u64, a2: u64, a3
             sys_deallocate_pages(VirtAddr::new(a1), NumOfPages::new(a2.try_into().unwrap()))
         }
         syscalls::Ty::MapPages => {
-            sys_map_pages(PhysAddr::new(a1), Bytes::new(a2.try_into().unwrap())).as_u64()
+            sys_map_pages(PhysAddr::new(a1), Bytes::new(a2.try_into().unwrap()), a3).as_u64()
         }
         syscalls::Ty::UnmapPages => {
             sys_unmap_pages(VirtAddr::new(a1), Bytes::new(a2.try_into().unwrap()))
@@ -192,28 +265,143 @@ unsafe fn select_proper_syscall_unchecked(ty: syscalls::Ty, a1: u64, a2: u64, a3
         // SAFETY: The caller must ensure that `a1` is the correct pointer to the panic
         // information.
         syscalls::Ty::Panic => unsafe { sys_panic(a1 as *const PanicInfo<'_>) },
+        syscalls::Ty::MapFramebuffer => sys_map_framebuffer().as_u64(),
+        syscalls::Ty::AllocateDma => {
+            sys_allocate_dma(Bytes::new(a1.try_into().unwrap()), a2, a3).as_u64()
+        }
+        // SAFETY: `prepare_syscall` passes the pointer to the saved registers.
+        syscalls::Ty::Fork => unsafe { sys_fork(&*saved) },
+        syscalls::Ty::Sleep => sys_sleep(Duration::from_nanos(a1)),
+        syscalls::Ty::SleepUntil => sys_sleep_until(Duration::from_nanos(a1)),
+        syscalls::Ty::MonotonicTime => sys_monotonic_time(),
         _ => unreachable!("This sytem call should not be handled by the kernel itself."),
     }
 }
 
 fn sys_allocate_pages(num_of_pages: NumOfPages<Size4KiB>) -> VirtAddr {
-    allocator::allocate_pages_for_user(num_of_pages).unwrap_or_else(VirtAddr::zero)
+    process::with_current_address_space(|a| a.allocate_anonymous(num_of_pages))
+        .unwrap_or_else(VirtAddr::zero)
 }
 
 fn sys_deallocate_pages(virt: VirtAddr, pages: NumOfPages<Size4KiB>) -> u64 {
-    allocator::deallocate_pages(virt, pages);
-    0
+    let r = process::with_current_address_space(|a| a.deallocate(virt, pages));
+    result_to_status(r)
 }
 
-fn sys_map_pages(start: PhysAddr, bytes: Bytes) -> VirtAddr {
-    crate::mem::map_pages_for_user(start, bytes)
+fn sys_map_pages(start: PhysAddr, bytes: Bytes, cache: u64) -> VirtAddr {
+    let cache: Option<CacheType> = FromPrimitive::from_u64(cache);
+
+    let frames = frames_spanning(start, bytes);
+
+    let virt =
+        cache.and_then(|cache| process::with_current_address_space(|a| a.map(frames, cache)));
+
+    virt.map_or_else(VirtAddr::zero, |virt| {
+        virt + start.as_u64() % Size4KiB::SIZE
+    })
 }
 
 fn sys_unmap_pages(start: VirtAddr, bytes: Bytes) -> u64 {
-    crate::mem::unmap_pages(start, bytes);
+    let r = process::with_current_address_space(|a| a.unmap(start, bytes));
+    result_to_status(r)
+}
+
+fn sys_map_framebuffer() -> VirtAddr {
+    framebuffer::map_for_user().unwrap_or_else(VirtAddr::zero)
+}
+
+/// Returns [`VirtAddr::zero`] if the process cannot read `constraints` or write to `phys`, or
+/// `constraints` holds an invalid value.
+fn sys_allocate_dma(bytes: Bytes, constraints: u64, phys: u64) -> VirtAddr {
+    let (constraints, phys) = match (read_dma_constraints(constraints), user_addr::<u64>(phys)) {
+        (Some(constraints), Some(phys)) => (constraints, phys),
+        _ => return VirtAddr::zero(),
+    };
+
+    let r = process::with_current_address_space(|a| {
+        let (virt, p) = a.allocate_dma(bytes, constraints)?;
+
+        if let Some(dst) = a.translate_for_user_access(phys, true) {
+            Some((virt, p, dst))
+        } else {
+            let r = a.deallocate(virt, bytes.as_num_of_pages());
+            r.expect("Failed to free the DMA memory.");
+
+            None
+        }
+    });
+
+    r.map_or_else(VirtAddr::zero, |(virt, p, dst)| {
+        // SAFETY: The process can write a `u64` to `dst`.
+        let mut dst = unsafe { accessor::new::<u64>(dst) };
+        dst.write_volatile(p.as_u64());
+
+        virt
+    })
+}
+
+/// Reads [`DmaConstraints`] from the user space, validating each field.
+fn read_dma_constraints(addr: u64) -> Option<DmaConstraints> {
+    // `DmaConstraints` is `repr(C)`. Reading the fields separately prevents each read from
+    // crossing a page boundary.
+    let alignment = read_from_user::<u64>(addr)?;
+    let [below_4gib, cache] = read_from_user::<u16>(addr.checked_add(8)?)?.to_le_bytes();
+
+    Some(DmaConstraints {
+        alignment,
+        below_4gib: below_4gib != 0,
+        cache: CacheType::from_u8(cache)?,
+    })
+}
+
+/// Reads a `T` at `addr` in the user space of the running process.
+///
+/// This function returns [`None`] if the process cannot read the `T`.
+fn read_from_user<T: Copy>(addr: u64) -> Option<T> {
+    let addr = user_addr::<T>(addr)?;
+    let phys = process::with_current_address_space(|a| a.translate_for_user_access(addr, false))?;
+
+    // SAFETY: The process can read a `T` at `phys`.
+    let src = unsafe { accessor::new::<T>(phys) };
+
+    Some(src.read_volatile())
+}
+
+/// Returns `addr` as a virtual address if it is aligned for `T`, and the whole `T` lies in a
+/// single page.
+fn user_addr<T>(addr: u64) -> Option<VirtAddr> {
+    let size = u64::try_from(mem::size_of::<T>()).unwrap();
+
+    let aligned = addr % u64::try_from(mem::align_of::<T>()).unwrap() == 0;
+    let in_page = addr % Size4KiB::SIZE + size <= Size4KiB::SIZE;
+
+    if aligned && in_page {
+        VirtAddr::try_new(addr).ok()
+    } else {
+        None
+    }
+}
+
+fn sys_fork(saved: &SavedRegisters) -> u64 {
+    process::fork(saved).try_into().unwrap()
+}
+
+fn sys_sleep(duration: Duration) -> u64 {
+    let deadline = time::now().checked_add(duration);
+
+    sys_sleep_until(deadline.unwrap_or(Duration::MAX))
+}
+
+fn sys_sleep_until(deadline: Duration) -> u64 {
+    process::sleep_until(deadline);
+
     0
 }
 
+fn sys_monotonic_time() -> u64 {
+    u64::try_from(time::now().as_nanos()).unwrap_or(u64::MAX)
+}
+
 fn sys_translate_address(v: VirtAddr) -> PhysAddr {
     paging::translate_addr(v).unwrap_or_else(PhysAddr::zero)
 }
@@ -256,6 +444,13 @@ fn sys_receive_from(m: VirtAddr, from: Pid) -> u64 {
     0
 }
 
+fn result_to_status(r: Result<(), MemoryError>) -> u64 {
+    match r {
+        Ok(()) => 0,
+        Err(e) => e as u64,
+    }
+}
+
 unsafe fn sys_panic(i: *const PanicInfo<'_>) -> ! {
     let name = process::scheduler::current_process_name();
 
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    crate::{
+        mem::{self, address_space::AddressSpace, paging},
+        process::{self, Process},
+    },
+    os_units::NumOfPages,
+    x86_64::{
+        instructions::interrupts,
+        structures::paging::{Page, PhysFrame},
+    },
+};
+
+/// Writes to a page shared with a forked address space as a system call writes to a user buffer,
+/// and checks that the other address space still sees the old value.
+pub(crate) fn copy_on_write() {
+    let virt = process::with_current_address_space(|a| a.allocate_anonymous(NumOfPages::new(1)));
+    let virt = virt.expect("Failed to allocate a page.");
+
+    let page = Page::containing_address(virt);
+    let ptr = virt.as_mut_ptr::<u64>();
+
+    // SAFETY: The page is allocated just now, and a frame is mapped on the first access.
+    unsafe { ptr.write_volatile(1) };
+
+    let pml4 = Process::generate_pml4();
+
+    let pml4_frame = PhysFrame::from_start_address(pml4.phys_addr());
+    let pml4_frame = pml4_frame.expect("PML4 is not page-aligned.");
+
+    // SAFETY: The PML4 maps the kernel only.
+    let forked = process::with_current_address_space(|a| unsafe { a.fork(pml4_frame) });
+
+    // SAFETY: The page is mapped, and this write must copy it instead of writing to the shared
+    // frame.
+    unsafe { ptr.write_volatile(2) };
+
+    // SAFETY: The page is mapped.
+    assert_eq!(unsafe { ptr.read_volatile() }, 2, "The write is lost.");
+
+    assert_eq!(
+        read_first_u64(pml4_frame, page),
+        1,
+        "The shared frame is modified."
+    );
+
+    release(pml4_frame, forked);
+
+    let r = process::with_current_address_space(|a| a.deallocate(virt, NumOfPages::new(1)));
+    r.expect("Failed to free the page.");
+}
+
+fn release(pml4_frame: PhysFrame, mut address_space: AddressSpace) {
+    // A process switch would load the PML4 of this process again.
+    interrupts::without_interrupts(|| {
+        // SAFETY: The PML4 maps the kernel as the current one does.
+        unsafe { paging::switch_pml4_do(pml4_frame, || address_space.release()) };
+    });
+}
+
+/// Reads the `u64` at the start of `page` in the address space of `pml4_frame`.
+fn read_first_u64(pml4_frame: PhysFrame, page: Page) -> u64 {
+    // A process switch would load the PML4 of this process again.
+    let frame = interrupts::without_interrupts(|| {
+        // SAFETY: The PML4 maps the kernel as the current one does.
+        unsafe { paging::switch_pml4_do(pml4_frame, || paging::translate_page(page)) }
+    });
+    let (frame, _) = frame.expect("The page is not mapped.");
+
+    // SAFETY: The frame is mapped to `page`, which holds a `u64` at the start.
+    let value = unsafe { mem::accessor::new::<u64>(frame.start_address()) };
+
+    value.read_volatile()
+}
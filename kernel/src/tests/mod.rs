@@ -2,9 +2,12 @@
 
 use {crate::qemu, core::sync::atomic::Ordering};
 
+pub(crate) mod mem;
 pub(crate) mod process;
 
 pub(crate) fn main() -> ! {
+    mem::copy_on_write();
+
     while !process::SWITCH_TEST_SUCCESS.load(Ordering::Relaxed) {}
 
     qemu::exit_success();
@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The monotonic clock.
+//!
+//! The clock starts when [`init`] is called. It reads the TSC if its rate is invariant, the HPET
+//! if there is one, and counts the local APIC timer interrupts otherwise.
+
+use {
+    crate::{
+        interrupt::{hpet, timer},
+        mem::allocator,
+        smp,
+    },
+    acpi::AcpiTables,
+    conquer_once::spin::OnceCell,
+    core::{
+        arch::x86_64::{__cpuid, _rdtsc},
+        convert::TryFrom,
+        sync::atomic::{AtomicU64, Ordering},
+        time::Duration,
+    },
+    log::info,
+};
+
+/// The time to count the TSC cycles to measure its frequency, in microseconds.
+const TSC_CALIBRATION_TIME: u32 = 50_000;
+
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+
+static SOURCE: OnceCell<Source> = OnceCell::uninit();
+
+/// The number of the timer interrupts of the bootstrap processor.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Selects the source of the monotonic clock, measuring the frequency of the TSC if it is used.
+///
+/// # Panics
+///
+/// This function panics if it is called more than once.
+pub(crate) fn init(table: &AcpiTables<allocator::acpi::Mapper>) {
+    let source = if tsc_is_invariant() {
+        Source::Tsc {
+            start: rdtsc(),
+            frequency: tsc_frequency(table),
+        }
+    } else if hpet::is_available() {
+        Source::Hpet {
+            start: hpet::nanoseconds(),
+        }
+    } else {
+        Source::Ticks
+    };
+
+    info!("Clock source: {:?}", source);
+
+    SOURCE
+        .try_init_once(|| source)
+        .expect("`time::init` is called more than once.");
+}
+
+/// Returns the time elapsed since [`init`] is called.
+///
+/// # Panics
+///
+/// This function panics if [`init`] is not called.
+pub(crate) fn now() -> Duration {
+    let source = SOURCE.try_get();
+    let source = source.expect("The monotonic clock is not initialized.");
+
+    match *source {
+        Source::Tsc { start, frequency } => {
+            let cycles = u128::from(rdtsc().wrapping_sub(start));
+
+            from_nanoseconds(cycles * NANOSECONDS_PER_SECOND / u128::from(frequency))
+        }
+        Source::Hpet { start } => Duration::from_nanos(hpet::nanoseconds() - start),
+        Source::Ticks => {
+            from_nanoseconds(u128::from(TICKS.load(Ordering::Relaxed)) * timer::TICK.as_nanos())
+        }
+    }
+}
+
+/// Counts a timer interrupt. This is the clock if neither the TSC nor the HPET is usable.
+pub(crate) fn tick() {
+    if smp::current() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+
+        // Reading the HPET on every tick keeps its 32-bit counter from wrapping around unnoticed.
+        if matches!(SOURCE.try_get(), Ok(Source::Hpet { .. })) {
+            hpet::nanoseconds();
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Source {
+    /// `frequency` is the number of the cycles per second.
+    Tsc {
+        start: u64,
+        frequency: u64,
+    },
+    /// The HPET does not start from 0 when the clock starts.
+    Hpet {
+        start: u64,
+    },
+    Ticks,
+}
+
+fn tsc_frequency(table: &AcpiTables<allocator::acpi::Mapper>) -> u64 {
+    let start = rdtsc();
+
+    timer::wait_microseconds(table, TSC_CALIBRATION_TIME);
+
+    let cycles = rdtsc() - start;
+
+    cycles * 1_000_000 / u64::from(TSC_CALIBRATION_TIME)
+}
+
+fn tsc_is_invariant() -> bool {
+    const INVARIANT_TSC: u32 = 1 << 8;
+
+    // SAFETY: All x86_64 processors support the CPUID leaf 0x8000_0000.
+    let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+
+    if max_leaf < 0x8000_0007 {
+        return false;
+    }
+
+    // SAFETY: The processor supports the leaf as checked above.
+    let r = unsafe { __cpuid(0x8000_0007) };
+
+    r.edx & INVARIANT_TSC != 0
+}
+
+fn rdtsc() -> u64 {
+    // SAFETY: Reading the TSC does not violate memory safety.
+    unsafe { _rdtsc() }
+}
+
+fn from_nanoseconds(nanoseconds: u128) -> Duration {
+    Duration::from_nanos(u64::try_from(nanoseconds).unwrap_or(u64::MAX))
+}
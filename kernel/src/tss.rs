@@ -1,22 +1,62 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use {
+    crate::{
+        smp::{self, MAX_CPUS},
+        sync::IrqSpinlock,
+    },
+    array_init::array_init,
     conquer_once::spin::Lazy,
+    core::ptr,
     predefined_mmap::INTERRUPT_STACK,
-    spinning_top::Spinlock,
     x86_64::{structures::tss::TaskStateSegment, VirtAddr},
 };
 
-static TSS: Lazy<Spinlock<TaskStateSegment>> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
-    tss.privilege_stack_table[0] = *INTERRUPT_STACK;
-    Spinlock::new(tss)
+/// The index of the interrupt stack table entry used by the double fault handler.
+///
+/// A kernel stack overflow causes a double fault because the CPU cannot push the frame of the
+/// page fault to the overflowed stack. The handler runs on its own stack to report it.
+pub(crate) const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 4;
+
+static mut DOUBLE_FAULT_STACKS: [DoubleFaultStack; MAX_CPUS] =
+    [DoubleFaultStack([0; DOUBLE_FAULT_STACK_SIZE]); MAX_CPUS];
+
+/// The task state segment of each processor.
+static TSS: Lazy<[IrqSpinlock<TaskStateSegment>; MAX_CPUS]> = Lazy::new(|| {
+    array_init(|cpu| {
+        let mut tss = TaskStateSegment::new();
+
+        // The application processors set the stack when they switch to a process for the first
+        // time.
+        if cpu == 0 {
+            tss.privilege_stack_table[0] = *INTERRUPT_STACK;
+        }
+
+        tss.interrupt_stack_table[usize::from(DOUBLE_FAULT_IST_INDEX)] =
+            double_fault_stack_bottom(cpu);
+
+        IrqSpinlock::new(tss)
+    })
 });
 
-pub(crate) fn get_ptr() -> *mut TaskStateSegment {
-    TSS.data_ptr()
+pub(crate) fn get_ptr(cpu: usize) -> *mut TaskStateSegment {
+    TSS[cpu].data_ptr()
 }
 
+/// Sets the stack which the running processor uses when an interrupt happens in the user mode.
 pub(crate) fn set_privilege_stack(addr: VirtAddr) {
-    TSS.lock().privilege_stack_table[0] = addr;
+    TSS[smp::current()].lock().privilege_stack_table[0] = addr;
 }
+
+fn double_fault_stack_bottom(cpu: usize) -> VirtAddr {
+    // SAFETY: Only the address is taken. The stack is used only by the CPU.
+    let stack = unsafe { ptr::addr_of!(DOUBLE_FAULT_STACKS[cpu]) };
+
+    VirtAddr::from_ptr(stack) + DOUBLE_FAULT_STACK_SIZE
+}
+
+#[repr(C, align(16))]
+#[derive(Copy, Clone)]
+struct DoubleFaultStack([u8; DOUBLE_FAULT_STACK_SIZE]);
@@ -27,18 +27,58 @@ impl Map {
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct MemoryDescriptor {
     pub start: PhysAddr,
     pub num_pages: NumOfPages<Size4KiB>,
+    pub ty: MemoryType,
 }
 impl MemoryDescriptor {
     /// # Safety
     ///
     /// The caller must ensure that the memory region of `num_pages` pages from the physical
-    /// address `start` is available.
+    /// address `start` is used as `ty` describes.
     #[must_use]
-    pub const unsafe fn new(start: PhysAddr, num_pages: NumOfPages<Size4KiB>) -> Self {
-        Self { start, num_pages }
+    pub const unsafe fn new(
+        start: PhysAddr,
+        num_pages: NumOfPages<Size4KiB>,
+        ty: MemoryType,
+    ) -> Self {
+        Self {
+            start,
+            num_pages,
+            ty,
+        }
     }
 }
+
+/// The type of a memory region.
+///
+/// This is a subset of UEFI's memory types. The code and the data regions of the same owner are
+/// merged as the kernel does not distinguish them.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MemoryType {
+    /// Free memory.
+    Conventional,
+    /// The memory which UEFI boot services use. The kernel can use it after it stops using the
+    /// page tables created by UEFI.
+    BootServices,
+    /// The memory which the bootloader allocates. This contains the kernel image, the kernel
+    /// stack, the initrd, and the memory map.
+    Loader,
+    /// The memory which UEFI runtime services use.
+    RuntimeServices,
+    /// The memory which contains ACPI tables. The kernel can use it after parsing the tables.
+    AcpiReclaim,
+    /// The memory which the firmware reserves for ACPI.
+    AcpiNvs,
+    /// Memory-mapped I/O.
+    Mmio,
+    /// Persistent memory.
+    Persistent,
+    /// The memory in which errors are detected.
+    Unusable,
+    /// The other reserved regions.
+    Reserved,
+}
@@ -55,6 +55,7 @@ pub struct Range {
     virt: VirtAddr,
     phys: PhysAddr,
     bytes: Bytes,
+    write_combining: bool,
 }
 
 impl Range {
@@ -64,6 +65,7 @@ impl Range {
             virt: KERNEL_ADDR,
             phys: kernel.start,
             bytes: kernel.bytes,
+            write_combining: false,
         }
     }
 
@@ -73,6 +75,7 @@ impl Range {
             virt: VRAM_ADDR,
             phys: vram.phys_ptr(),
             bytes: vram.bytes(),
+            write_combining: true,
         }
     }
 
@@ -82,6 +85,7 @@ impl Range {
             virt: *STACK_LOWER,
             phys,
             bytes: NUM_OF_PAGES_STACK.as_bytes(),
+            write_combining: false,
         }
     }
 
@@ -91,6 +95,7 @@ impl Range {
             virt: INITRD_ADDR,
             phys,
             bytes,
+            write_combining: false,
         }
     }
 
@@ -108,4 +113,10 @@ impl Range {
     pub fn bytes(&self) -> Bytes {
         self.bytes
     }
+
+    /// Returns `true` if the region should be mapped as write-combining memory.
+    #[must_use]
+    pub fn is_write_combining(&self) -> bool {
+        self.write_combining
+    }
 }
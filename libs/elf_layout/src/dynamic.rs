@@ -0,0 +1,359 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The tables which the `PT_DYNAMIC` segment refers to.
+//!
+//! The section headers are not needed at run time and may be stripped, so the tables are found
+//! through the dynamic entries and the loadable segments which contain them.
+
+use {
+    super::Error,
+    alloc::vec::Vec,
+    core::{convert::TryFrom, str},
+    elfloader::ElfBinary,
+    xmas_elf::program::Type,
+};
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_PLTRELSZ: u64 = 2;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_STRSZ: u64 = 10;
+const DT_SYMENT: u64 = 11;
+const DT_PLTREL: u64 = 20;
+const DT_JMPREL: u64 = 23;
+const DT_GNU_HASH: u64 = 0x6fff_fef5;
+
+const DYN_SIZE: usize = 16;
+const RELA_SIZE: usize = 24;
+const SYM_SIZE: usize = 24;
+
+const STB_LOCAL: u8 = 0;
+
+/// The dynamic entries and the tables they refer to.
+pub(super) struct Dynamic<'a> {
+    symbols: &'a [u8],
+    strings: &'a [u8],
+    /// The relocation entries of `DT_RELA` and `DT_JMPREL` in this order.
+    relas: [&'a [u8]; 2],
+    needed: Vec<u32>,
+}
+impl<'a> Dynamic<'a> {
+    /// Reads the dynamic entries of `elf`, or returns [`None`] if it has no `PT_DYNAMIC` segment.
+    pub(super) fn new(elf: &ElfBinary<'a>) -> Result<Option<Self>, Error> {
+        let memory = Memory::new(elf)?;
+
+        let entries = match dynamic_segment(elf)? {
+            Some(entries) => entries,
+            None => return Ok(None),
+        };
+
+        let tags = Tags::new(entries)?;
+
+        Ok(Some(Self {
+            symbols: tags.symbols(&memory)?,
+            strings: tags.strings(&memory)?,
+            relas: [
+                tags.relas(&memory, DT_RELA)?,
+                tags.relas(&memory, DT_JMPREL)?,
+            ],
+            needed: tags.needed,
+        }))
+    }
+
+    pub(super) fn relas(&self) -> impl Iterator<Item = Rela> + '_ {
+        self.relas
+            .iter()
+            .flat_map(|t| t.chunks_exact(RELA_SIZE))
+            .map(Rela::new)
+    }
+
+    /// Returns the symbol `index`, or [`None`] if it is out of the symbol table.
+    pub(super) fn symbol(&self, index: u32) -> Result<Option<Symbol<'a>>, Error> {
+        let start = usize::try_from(index).unwrap().checked_mul(SYM_SIZE);
+        let entry = start.and_then(|s| self.symbols.get(s..s.checked_add(SYM_SIZE)?));
+
+        entry.map(|e| self.parse_symbol(e)).transpose()
+    }
+
+    pub(super) fn symbols(&self) -> impl Iterator<Item = Result<Symbol<'a>, Error>> + '_ {
+        self.symbols
+            .chunks_exact(SYM_SIZE)
+            .map(move |e| self.parse_symbol(e))
+    }
+
+    /// Returns the names of the `DT_NEEDED` entries.
+    pub(super) fn needed(&self) -> Result<Vec<&'a str>, Error> {
+        self.needed.iter().map(|n| self.string(*n)).collect()
+    }
+
+    fn parse_symbol(&self, entry: &[u8]) -> Result<Symbol<'a>, Error> {
+        Ok(Symbol {
+            name: self.string(read_u32(entry, 0).unwrap())?,
+            local: entry[4] >> 4 == STB_LOCAL,
+            defined: read_u16(entry, 6).unwrap() != 0,
+            value: read_u64(entry, 8).unwrap(),
+        })
+    }
+
+    fn string(&self, offset: u32) -> Result<&'a str, Error> {
+        let s = self.strings.get(usize::try_from(offset).unwrap()..);
+        let s = s.ok_or("Invalid name.")?;
+        let s = s.split(|c| *c == 0).next().unwrap();
+
+        Ok(str::from_utf8(s).map_err(|_| "Invalid name.")?)
+    }
+}
+
+/// A relocation entry of the `Elf64_Rela` format.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct Rela {
+    pub(super) offset: u64,
+    pub(super) ty: u32,
+    pub(super) symbol: u32,
+    pub(super) addend: u64,
+}
+impl Rela {
+    fn new(entry: &[u8]) -> Self {
+        let info = read_u64(entry, 8).unwrap();
+
+        Self {
+            offset: read_u64(entry, 0).unwrap(),
+            ty: u32::try_from(info & 0xffff_ffff).unwrap(),
+            symbol: u32::try_from(info >> 32).unwrap(),
+            addend: read_u64(entry, 16).unwrap(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(super) struct Symbol<'a> {
+    pub(super) name: &'a str,
+    pub(super) local: bool,
+    pub(super) defined: bool,
+    pub(super) value: u64,
+}
+
+/// The values of the dynamic entries used to find the tables.
+struct Tags {
+    /// The pairs of the tags and values except `DT_NEEDED`.
+    entries: Vec<(u64, u64)>,
+    needed: Vec<u32>,
+}
+impl Tags {
+    fn new(segment: &[u8]) -> Result<Self, Error> {
+        let mut tags = Self {
+            entries: Vec::new(),
+            needed: Vec::new(),
+        };
+
+        for entry in segment.chunks_exact(DYN_SIZE) {
+            match (read_u64(entry, 0).unwrap(), read_u64(entry, 8).unwrap()) {
+                (DT_NULL, _) => return Ok(tags),
+                (DT_NEEDED, v) => tags
+                    .needed
+                    .push(u32::try_from(v).map_err(|_| "Invalid name.")?),
+                (tag, v) => tags.entries.push((tag, v)),
+            }
+        }
+
+        Err("The dynamic table has no terminator.".into())
+    }
+
+    fn get(&self, tag: u64) -> Option<u64> {
+        self.entries
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, v)| *v)
+    }
+
+    fn value(&self, tag: u64) -> Result<u64, Error> {
+        self.get(tag).ok_or(Error::MissingDynamicTag { tag })
+    }
+
+    /// Returns the relocation table which the tag `table`, either `DT_RELA` or `DT_JMPREL`,
+    /// points to, or an empty slice if the binary does not have `table`.
+    fn relas<'a>(&self, memory: &Memory<'a>, table: u64) -> Result<&'a [u8], Error> {
+        let addr = match self.get(table) {
+            Some(addr) => addr,
+            None => return Ok(&[]),
+        };
+
+        self.expect_if_present(DT_RELAENT, RELA_SIZE)?;
+
+        let size = if table == DT_RELA {
+            DT_RELASZ
+        } else if self.value(DT_PLTREL)? == DT_RELA {
+            DT_PLTRELSZ
+        } else {
+            return Err("The PLT relocations are not of the RELA type.".into());
+        };
+
+        let bytes = self.value(size)?;
+
+        if bytes % u64::try_from(RELA_SIZE).unwrap() != 0 {
+            return Err("Invalid size of the relocation table.".into());
+        }
+
+        memory.slice(addr, bytes)
+    }
+
+    /// Returns the symbol table, whose length is taken from the hash table.
+    fn symbols<'a>(&self, memory: &Memory<'a>) -> Result<&'a [u8], Error> {
+        let addr = match self.get(DT_SYMTAB) {
+            Some(addr) => addr,
+            None => return Ok(&[]),
+        };
+
+        self.expect_if_present(DT_SYMENT, SYM_SIZE)?;
+
+        let num = if let Some(hash) = self.get(DT_HASH) {
+            read_u32(memory.data_from(hash)?, 4)
+        } else if let Some(hash) = self.get(DT_GNU_HASH) {
+            num_of_symbols_in_gnu_hash(memory.data_from(hash)?)
+        } else {
+            return Err(Error::MissingDynamicTag { tag: DT_HASH });
+        };
+        let num = num.ok_or("Invalid hash table.")?;
+
+        memory.slice(addr, u64::from(num) * u64::try_from(SYM_SIZE).unwrap())
+    }
+
+    fn strings<'a>(&self, memory: &Memory<'a>) -> Result<&'a [u8], Error> {
+        match self.get(DT_STRTAB) {
+            Some(addr) => memory.slice(addr, self.value(DT_STRSZ)?),
+            None => Ok(&[]),
+        }
+    }
+
+    fn expect_if_present(&self, tag: u64, size: usize) -> Result<(), Error> {
+        match self.get(tag) {
+            Some(v) if v != u64::try_from(size).unwrap() => {
+                Err("Unsupported size of the dynamic table entries.".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The file data of the loadable segments placed at their link-time addresses.
+struct Memory<'a> {
+    binary: &'a [u8],
+    /// The link-time addresses, file offsets and file sizes of the segments.
+    segments: Vec<(u64, u64, u64)>,
+}
+impl<'a> Memory<'a> {
+    fn new(elf: &ElfBinary<'a>) -> Result<Self, Error> {
+        let mut segments = Vec::new();
+
+        for header in elf.program_headers() {
+            if header.get_type()? == Type::Load {
+                segments.push((header.virtual_addr(), header.offset(), header.file_size()));
+            }
+        }
+
+        Ok(Self {
+            binary: elf.file.input,
+            segments,
+        })
+    }
+
+    /// Returns the file data from `addr` to the end of the segment containing it.
+    fn data_from(&self, addr: u64) -> Result<&'a [u8], Error> {
+        let data = self.segments.iter().find_map(|(start, offset, size)| {
+            let skip = addr.checked_sub(*start).filter(|s| s < size)?;
+            let data_start = usize::try_from(offset.checked_add(skip)?).ok()?;
+            let data_end = usize::try_from(offset.checked_add(*size)?).ok()?;
+
+            self.binary.get(data_start..data_end)
+        });
+
+        data.ok_or(Error::DynamicOutOfSegment { addr })
+    }
+
+    /// Returns the `bytes` bytes of the file data from `addr`.
+    fn slice(&self, addr: u64, bytes: u64) -> Result<&'a [u8], Error> {
+        if bytes == 0 {
+            return Ok(&[]);
+        }
+
+        let data = self.data_from(addr)?;
+        let data = usize::try_from(bytes).ok().and_then(|b| data.get(..b));
+
+        data.ok_or(Error::DynamicOutOfSegment { addr })
+    }
+}
+
+fn dynamic_segment<'a>(elf: &ElfBinary<'a>) -> Result<Option<&'a [u8]>, Error> {
+    for header in elf.program_headers() {
+        if header.get_type()? == Type::Dynamic {
+            let start = usize::try_from(header.offset()).ok();
+            let size = usize::try_from(header.file_size()).ok();
+
+            let range = start.and_then(|s| Some(s..s.checked_add(size?)?));
+            let data = range.and_then(|r| elf.file.input.get(r));
+
+            return data
+                .map(Some)
+                .ok_or_else(|| "Invalid dynamic segment.".into());
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns the number of the symbols which the `DT_GNU_HASH` table `table` covers.
+///
+/// The table has no field of the number, but the chain of the last bucket ends at the last
+/// symbol.
+fn num_of_symbols_in_gnu_hash(table: &[u8]) -> Option<u32> {
+    let num_of_buckets = usize::try_from(read_u32(table, 0)?).ok()?;
+    let first_symbol = read_u32(table, 4)?;
+    let bloom_size = usize::try_from(read_u32(table, 8)?).ok()?;
+
+    let buckets = table.get(bloom_size.checked_mul(8)?.checked_add(16)?..)?;
+    let chains = buckets.get(num_of_buckets.checked_mul(4)?..)?;
+
+    let last_bucket = (0..num_of_buckets)
+        .map(|i| read_u32(buckets, i * 4))
+        .try_fold(0, |max, b| Some(b?.max(max)))?;
+
+    if last_bucket < first_symbol {
+        return Some(first_symbol);
+    }
+
+    let mut i = last_bucket;
+
+    loop {
+        let chain = usize::try_from(i - first_symbol).ok()?.checked_mul(4)?;
+
+        // The lowest bit of a hash marks the end of a chain.
+        if read_u32(chains, chain)? & 1 != 0 {
+            return i.checked_add(1);
+        }
+
+        i = i.checked_add(1)?;
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let b = bytes.get(offset..offset.checked_add(2)?)?;
+
+    Some(u16::from_le_bytes(b.try_into().ok()?))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let b = bytes.get(offset..offset.checked_add(4)?)?;
+
+    Some(u32::from_le_bytes(b.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    let b = bytes.get(offset..offset.checked_add(8)?)?;
+
+    Some(u64::from_le_bytes(b.try_into().ok()?))
+}
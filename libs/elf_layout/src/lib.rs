@@ -0,0 +1,1347 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+mod dynamic;
+
+use {
+    alloc::vec::Vec,
+    core::{convert::TryFrom, fmt, ops::Range},
+    dynamic::{Dynamic, Rela},
+    elfloader::{ElfBinary, ElfLoaderErr, ProgramHeader},
+    xmas_elf::{header, program::Type, ElfFile},
+};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+
+/// The loadable segments of an ELF binary and the pages they occupy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// The offset added to the link-time addresses. It is always 0 for non-position-independent
+    /// executables.
+    pub base: u64,
+    /// The loadable segments sorted by their start addresses.
+    pub segments: Vec<Segment>,
+    /// The pages which the segments occupy, sorted by their start addresses.
+    ///
+    /// The regions do not overlap with each other. A page shared by multiple segments forms a
+    /// region by itself, and its permissions are the union of those of the segments.
+    pub regions: Vec<Region>,
+    /// The initialization image of the thread-local storage.
+    pub tls: Option<Tls>,
+}
+
+/// A loadable segment.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub start: u64,
+    /// The offset of the file data from the start of the binary.
+    pub offset: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+impl Layout {
+    /// Returns the writes which apply the relocation entries of `binary` to the loaded segments.
+    ///
+    /// A symbol which is not local to `binary` is looked up with `resolve` first so that the
+    /// other loaded objects can interpose it. `resolve` returns the run-time address of the symbol
+    /// if it finds one. If it does not, the definition in `binary` itself is used.
+    ///
+    /// This method returns no relocations for non-position-independent executables as they are
+    /// loaded at their link-time addresses.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if `binary` is not a valid ELF file, the dynamic entries lack
+    /// the tables of the relocations or symbols, or any relocation entry has an unsupported type,
+    /// refers to an undefined symbol, or modifies memory out of the writable segments.
+    pub fn relocations(
+        &self,
+        binary: &[u8],
+        resolve: impl Fn(&str) -> Option<u64>,
+    ) -> Result<Vec<Relocation>, Error> {
+        let elf = ElfBinary::new(binary)?;
+
+        if !is_shared_object(&elf.file) {
+            return Ok(Vec::new());
+        }
+
+        let dynamic = match Dynamic::new(&elf)? {
+            Some(dynamic) => dynamic,
+            None => return Ok(Vec::new()),
+        };
+
+        let symbols = Symbols {
+            dynamic: &dynamic,
+            resolve: &resolve,
+        };
+
+        dynamic
+            .relas()
+            .filter_map(|entry| self.relocation(entry, &symbols).transpose())
+            .collect()
+    }
+
+    /// Returns the parts of the file data which fill `region`.
+    ///
+    /// The rest of the region must be filled with zeros.
+    #[must_use]
+    pub fn file_data(&self, region: &Region) -> Vec<FileData> {
+        self.segments
+            .iter()
+            .filter_map(|s| {
+                let start = s.start.max(region.start);
+                let end = (s.start + s.file_size).min(region.end);
+
+                (start < end).then(|| FileData {
+                    addr: start,
+                    offset: s.offset + (start - s.start),
+                    size: end - start,
+                })
+            })
+            .collect()
+    }
+
+    fn relocation(
+        &self,
+        entry: Rela,
+        symbols: &Symbols<'_, '_>,
+    ) -> Result<Option<Relocation>, Error> {
+        let addend = entry.addend;
+
+        let value = match entry.ty {
+            R_X86_64_NONE => return Ok(None),
+            R_X86_64_64 => self.symbol_value(entry, symbols)?.wrapping_add(addend),
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => self.symbol_value(entry, symbols)?,
+            R_X86_64_RELATIVE => self.base.wrapping_add(addend),
+            ty => return Err(Error::UnsupportedRelocation { ty }),
+        };
+
+        let offset = entry.offset;
+        let addr = self.base.wrapping_add(offset);
+
+        // Relocations never modify the read-only pages so that processes can share them.
+        let in_segment = self
+            .segments
+            .iter()
+            .any(|s| s.writable && s.start <= addr && addr.saturating_add(8) <= s.end());
+
+        if in_segment {
+            Ok(Some(Relocation { addr, value }))
+        } else {
+            Err(Error::RelocationOutOfSegment { offset })
+        }
+    }
+
+    fn symbol_value(&self, entry: Rela, symbols: &Symbols<'_, '_>) -> Result<u64, Error> {
+        let index = entry.symbol;
+        let symbol = symbols.dynamic.symbol(index)?;
+        let symbol = symbol.ok_or(Error::UndefinedSymbol { index })?;
+
+        if !symbol.local {
+            if let Some(addr) = (symbols.resolve)(symbol.name) {
+                return Ok(addr);
+            }
+        }
+
+        if symbol.defined {
+            Ok(self.base.wrapping_add(symbol.value))
+        } else {
+            Err(Error::UndefinedSymbol { index })
+        }
+    }
+}
+
+impl Segment {
+    #[must_use]
+    pub fn end(&self) -> u64 {
+        self.start + self.mem_size
+    }
+
+    fn page_range(&self) -> Range<u64> {
+        align_down(self.start)..align_up(self.end())
+    }
+}
+
+/// The initialization image of the thread-local storage, which is inside one of the segments.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tls {
+    pub start: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    /// The alignment of the storage. It is a power of two and does not exceed the page size.
+    pub align: u64,
+}
+impl Tls {
+    /// Returns the offset of the thread pointer from the start of a thread-local storage block.
+    ///
+    /// The storage ends at the thread pointer, and the thread control block follows it. The
+    /// block must be aligned to [`Tls::align`].
+    #[must_use]
+    pub fn thread_pointer_offset(&self) -> u64 {
+        let align = self.align.max(8);
+
+        self.mem_size.saturating_add(align - 1) & !(align - 1)
+    }
+
+    /// Returns the number of bytes of a thread-local storage block including the thread control
+    /// block, whose first word points to itself.
+    #[must_use]
+    pub fn block_size(&self) -> u64 {
+        self.thread_pointer_offset().saturating_add(8)
+    }
+}
+
+/// The `size` bytes from `offset` of the binary which are copied to `addr`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FileData {
+    pub addr: u64,
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// A write of `value` to the 8 bytes from `addr`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Relocation {
+    pub addr: u64,
+    pub value: u64,
+}
+
+/// Page-aligned memory which holds the segments.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+impl Region {
+    fn merge_permissions(&mut self, segment: &Segment) {
+        self.writable |= segment.writable;
+        self.executable |= segment.executable;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The binary is not a valid ELF file.
+    Parse(ElfLoaderErr),
+    /// The segment from `start` is not inside the allowed range.
+    OutOfRange { start: u64 },
+    /// The segments from `first` and `second` overlap.
+    Overlap { first: u64, second: u64 },
+    /// The file size of the segment from `start` exceeds its memory size.
+    FileSizeTooLarge { start: u64 },
+    /// The data of the segment from `start` is out of the binary.
+    DataOutOfFile { start: u64 },
+    /// The binary is not position-independent but a non-zero base is specified.
+    NotPositionIndependent,
+    /// The relocation type `ty` is not supported.
+    UnsupportedRelocation { ty: u32 },
+    /// The relocation refers to the symbol `index` which is not defined in the binary.
+    UndefinedSymbol { index: u32 },
+    /// The relocation at the link-time address `offset` modifies memory out of the writable
+    /// segments.
+    RelocationOutOfSegment { offset: u64 },
+    /// The initialization image of the thread-local storage from `start` is not inside a
+    /// segment.
+    TlsOutOfSegment { start: u64 },
+    /// The alignment of the thread-local storage is not a power of two or exceeds the page size.
+    TlsAlignment { align: u64 },
+    /// The dynamic entry `tag` which locates a table is missing.
+    MissingDynamicTag { tag: u64 },
+    /// The table at the link-time address `addr` which a dynamic entry refers to is not inside
+    /// the file data of a segment.
+    DynamicOutOfSegment { addr: u64 },
+}
+impl From<ElfLoaderErr> for Error {
+    fn from(e: ElfLoaderErr) -> Self {
+        Self::Parse(e)
+    }
+}
+impl From<&'static str> for Error {
+    fn from(e: &'static str) -> Self {
+        Self::Parse(e.into())
+    }
+}
+impl fmt::Display for Error {
+    #[allow(clippy::too_many_lines)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(e) => write!(f, "{}", e),
+            Self::OutOfRange { start } => {
+                write!(f, "The segment at {:#x} is out of the user space.", start)
+            }
+            Self::Overlap { first, second } => {
+                write!(f, "The segments at {:#x} and {:#x} overlap.", first, second)
+            }
+            Self::FileSizeTooLarge { start } => write!(
+                f,
+                "The file size of the segment at {:#x} exceeds its memory size.",
+                start
+            ),
+            Self::DataOutOfFile { start } => write!(
+                f,
+                "The data of the segment at {:#x} is out of the file.",
+                start
+            ),
+            Self::NotPositionIndependent => {
+                write!(f, "The binary is not position-independent.")
+            }
+            Self::UnsupportedRelocation { ty } => {
+                write!(f, "The relocation type {} is not supported.", ty)
+            }
+            Self::UndefinedSymbol { index } => {
+                write!(f, "The symbol {} is not defined in the binary.", index)
+            }
+            Self::RelocationOutOfSegment { offset } => write!(
+                f,
+                "The relocation at {:#x} modifies memory out of the writable segments.",
+                offset
+            ),
+            Self::TlsOutOfSegment { start } => {
+                write!(f, "The TLS image at {:#x} is not inside a segment.", start)
+            }
+            Self::TlsAlignment { align } => {
+                write!(f, "The TLS alignment {:#x} is not supported.", align)
+            }
+            Self::MissingDynamicTag { tag } => {
+                write!(f, "The dynamic entry {:#x} is missing.", tag)
+            }
+            Self::DynamicOutOfSegment { addr } => write!(
+                f,
+                "The dynamic table at {:#x} is not inside a segment.",
+                addr
+            ),
+        }
+    }
+}
+
+/// Returns the names of the shared objects which `binary` depends on, in the order of the
+/// `DT_NEEDED` entries.
+///
+/// # Errors
+///
+/// This function returns an error if `binary` is not a valid ELF file or its dynamic entries are
+/// invalid.
+pub fn needed(binary: &[u8]) -> Result<Vec<&str>, Error> {
+    let elf = ElfBinary::new(binary)?;
+
+    Dynamic::new(&elf)?.map_or_else(|| Ok(Vec::new()), |d| d.needed())
+}
+
+/// Returns the link-time address of the global symbol `name` which `binary` defines.
+///
+/// # Errors
+///
+/// This function returns an error if `binary` is not a valid ELF file or its dynamic entries are
+/// invalid.
+pub fn symbol(binary: &[u8], name: &str) -> Result<Option<u64>, Error> {
+    let elf = ElfBinary::new(binary)?;
+
+    let dynamic = match Dynamic::new(&elf)? {
+        Some(dynamic) => dynamic,
+        None => return Ok(None),
+    };
+
+    for s in dynamic.symbols() {
+        let s = s?;
+
+        if s.defined && !s.local && s.name == name {
+            return Ok(Some(s.value));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns the page-aligned link-time address range of the loadable segments if `binary` is a
+/// position-independent executable, or [`None`] if it must be loaded at its link-time addresses.
+///
+/// # Errors
+///
+/// This function returns an error if `binary` is not a valid ELF file.
+pub fn relocatable_range(binary: &[u8]) -> Result<Option<Range<u64>>, Error> {
+    let elf = ElfBinary::new(binary)?;
+
+    if !is_shared_object(&elf.file) {
+        return Ok(None);
+    }
+
+    let mut range: Option<Range<u64>> = None;
+
+    for header in elf.program_headers() {
+        if header.get_type()? == Type::Load && header.mem_size() > 0 {
+            let start = align_down(header.virtual_addr());
+            let end = align_up(header.virtual_addr().saturating_add(header.mem_size()));
+
+            range = Some(range.map_or(start..end, |r| r.start.min(start)..r.end.max(end)));
+        }
+    }
+
+    Ok(Some(range.unwrap_or(0..0)))
+}
+
+/// Validates the loadable segments of `binary` placed `base` bytes above their link-time
+/// addresses, and returns the layout of them.
+///
+/// # Errors
+///
+/// This function returns an error if `binary` is not a valid ELF file, `base` is not 0 for a
+/// non-position-independent executable, any segment is not inside `allowed`, any segments
+/// overlap, or the file data of any segment is inconsistent.
+pub fn layout(binary: &[u8], base: u64, allowed: Range<u64>) -> Result<Layout, Error> {
+    let elf = ElfBinary::new(binary)?;
+
+    if base != 0 && !is_shared_object(&elf.file) {
+        return Err(Error::NotPositionIndependent);
+    }
+
+    let (segments, tls) = segments_and_tls(&elf, base, &allowed)?;
+
+    check_overlaps(&segments)?;
+
+    if let Some(t) = &tls {
+        check_tls_in_segment(t, &segments)?;
+    }
+
+    let regions = regions(&segments);
+
+    Ok(Layout {
+        base,
+        segments,
+        regions,
+        tls,
+    })
+}
+
+/// Returns the loadable segments sorted by their start addresses, and the thread-local storage
+/// image.
+fn segments_and_tls(
+    elf: &ElfBinary<'_>,
+    base: u64,
+    allowed: &Range<u64>,
+) -> Result<(Vec<Segment>, Option<Tls>), Error> {
+    let mut segments = Vec::new();
+    let mut tls = None;
+
+    for header in elf.program_headers() {
+        match header.get_type()? {
+            Type::Load => {
+                let s = segment(&header, base, allowed)?;
+                segments.push(check_file_data(s, elf.file.input)?);
+            }
+            Type::Tls => tls = Some(tls_image(&header, base)?),
+            _ => {}
+        }
+    }
+
+    segments.sort_unstable_by_key(|s| s.start);
+
+    Ok((segments, tls))
+}
+
+/// `segments` must be sorted by their start addresses.
+fn check_overlaps(segments: &[Segment]) -> Result<(), Error> {
+    for pair in segments.windows(2) {
+        if pair[0].end() > pair[1].start {
+            return Err(Error::Overlap {
+                first: pair[0].start,
+                second: pair[1].start,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+fn check_tls_in_segment(tls: &Tls, segments: &[Segment]) -> Result<(), Error> {
+    let in_segment = segments
+        .iter()
+        .any(|s| s.start <= tls.start && tls.start + tls.file_size <= s.start + s.file_size);
+
+    if in_segment {
+        Ok(())
+    } else {
+        Err(Error::TlsOutOfSegment { start: tls.start })
+    }
+}
+
+fn tls_image(header: &ProgramHeader<'_>, base: u64) -> Result<Tls, Error> {
+    let start = header.virtual_addr().wrapping_add(base);
+    let file_size = header.file_size();
+    let mem_size = header.mem_size();
+    let align = header.align().max(1);
+
+    if file_size > mem_size {
+        return Err(Error::FileSizeTooLarge { start });
+    }
+
+    if !align.is_power_of_two() || align > PAGE_SIZE {
+        return Err(Error::TlsAlignment { align });
+    }
+
+    if start.checked_add(file_size).is_none() {
+        return Err(Error::TlsOutOfSegment { start });
+    }
+
+    Ok(Tls {
+        start,
+        file_size,
+        mem_size,
+        align,
+    })
+}
+
+fn segment(header: &ProgramHeader<'_>, base: u64, allowed: &Range<u64>) -> Result<Segment, Error> {
+    let vaddr = header.virtual_addr();
+    let start = vaddr.checked_add(base);
+    let start = start.ok_or(Error::OutOfRange { start: vaddr })?;
+
+    let end = start.checked_add(header.mem_size());
+    let end = end.ok_or(Error::OutOfRange { start })?;
+
+    if start < allowed.start || align_up(end) > allowed.end {
+        return Err(Error::OutOfRange { start });
+    }
+
+    Ok(Segment {
+        start,
+        offset: header.offset(),
+        file_size: header.file_size(),
+        mem_size: header.mem_size(),
+        writable: header.flags().is_write(),
+        executable: header.flags().is_execute(),
+    })
+}
+
+fn check_file_data(segment: Segment, binary: &[u8]) -> Result<Segment, Error> {
+    let start = segment.start;
+
+    if segment.file_size > segment.mem_size {
+        return Err(Error::FileSizeTooLarge { start });
+    }
+
+    let data_end = segment.offset.checked_add(segment.file_size);
+
+    if data_end.map_or(true, |e| e > u64::try_from(binary.len()).unwrap()) {
+        Err(Error::DataOutOfFile { start })
+    } else {
+        Ok(segment)
+    }
+}
+
+fn regions(segments: &[Segment]) -> Vec<Region> {
+    let mut regions: Vec<Region> = Vec::new();
+
+    for segment in segments.iter().filter(|s| s.mem_size > 0) {
+        let mut pages = segment.page_range();
+
+        if regions.last().map_or(false, |last| pages.start < last.end) {
+            pages.start = share_last_page(&mut regions, segment);
+        }
+
+        if pages.start < pages.end {
+            regions.push(Region {
+                start: pages.start,
+                end: pages.end,
+                writable: segment.writable,
+                executable: segment.executable,
+            });
+        }
+    }
+
+    regions
+}
+
+/// Makes the last page of the last region, which `segment` also occupies, a region by itself, and
+/// returns the end of it.
+fn share_last_page(regions: &mut Vec<Region>, segment: &Segment) -> u64 {
+    let last = regions.last_mut().unwrap();
+
+    if last.end - last.start > PAGE_SIZE {
+        last.end -= PAGE_SIZE;
+
+        let mut shared = *last;
+        shared.start = last.end;
+        shared.end = last.end + PAGE_SIZE;
+        regions.push(shared);
+    }
+
+    let shared = regions.last_mut().unwrap();
+    shared.merge_permissions(segment);
+
+    shared.end
+}
+
+struct Symbols<'a, 'b> {
+    dynamic: &'b Dynamic<'a>,
+    resolve: &'b dyn Fn(&str) -> Option<u64>,
+}
+
+fn is_shared_object(file: &ElfFile<'_>) -> bool {
+    file.header.pt2.type_().as_type() == header::Type::SharedObject
+}
+
+fn align_down(addr: u64) -> u64 {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn align_up(addr: u64) -> u64 {
+    align_down(addr.saturating_add(PAGE_SIZE - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{
+            layout, needed, relocatable_range, symbol, Error, FileData, Region, Relocation,
+            Segment, Tls,
+        },
+        core::ops::Range,
+    };
+
+    const USER: Range<u64> = 0x1000..0x0000_8000_0000_0000;
+
+    const PF_X: u32 = 1;
+    const PF_W: u32 = 2;
+    const PF_R: u32 = 4;
+
+    const PT_LOAD: u32 = 1;
+    const PT_DYNAMIC: u32 = 2;
+    const PT_TLS: u32 = 7;
+
+    const DT_NEEDED: u64 = 1;
+    const DT_PLTRELSZ: u64 = 2;
+    const DT_HASH: u64 = 4;
+    const DT_STRTAB: u64 = 5;
+    const DT_SYMTAB: u64 = 6;
+    const DT_RELA: u64 = 7;
+    const DT_RELASZ: u64 = 8;
+    const DT_STRSZ: u64 = 10;
+    const DT_PLTREL: u64 = 20;
+    const DT_JMPREL: u64 = 23;
+    const DT_GNU_HASH: u64 = 0x6fff_fef5;
+
+    /// The link-time address of the segment which holds the dynamic entries and tables.
+    const DYNAMIC_VADDR: u64 = 0x2000;
+
+    const STB_GLOBAL: u8 = 1;
+
+    const ET_EXEC: u16 = 2;
+    const ET_DYN: u16 = 3;
+
+    const R_X86_64_64: u32 = 1;
+    const R_X86_64_GLOB_DAT: u32 = 6;
+    const R_X86_64_JUMP_SLOT: u32 = 7;
+    const R_X86_64_RELATIVE: u32 = 8;
+    const R_X86_64_COPY: u32 = 5;
+
+    #[derive(Copy, Clone)]
+    struct Header {
+        ty: u32,
+        vaddr: u64,
+        file_size: u64,
+        mem_size: u64,
+        flags: u32,
+        align: u64,
+    }
+
+    struct Rela {
+        offset: u64,
+        ty: u32,
+        symbol: u32,
+        addend: i64,
+    }
+
+    /// A global symbol. It is undefined if the section index is 0.
+    struct Symbol<'a> {
+        name: &'a str,
+        value: u64,
+        shndx: u16,
+    }
+
+    struct Dynamic<'a> {
+        relas: &'a [Rela],
+        /// The relocations of `DT_JMPREL`. The entry is omitted if this is empty.
+        plt_relas: &'a [Rela],
+        symbols: &'a [Symbol<'a>],
+        needed: &'a [&'a str],
+        hash: HashTable,
+    }
+
+    #[derive(Copy, Clone)]
+    enum HashTable {
+        SysV,
+        Gnu,
+        Missing,
+    }
+
+    /// Builds a minimal x86_64 executable which has `headers` as the loadable segments.
+    ///
+    /// The data of all segments starts right after the program headers.
+    fn fixture(headers: &[Header]) -> Vec<u8> {
+        let mut elf = elf_header(ET_EXEC, headers);
+        append_segment_data(&mut elf, headers);
+
+        elf
+    }
+
+    /// Builds a minimal position-independent executable which has no section headers.
+    ///
+    /// In addition to `headers`, a read-only segment at [`DYNAMIC_VADDR`] holds the dynamic
+    /// entries followed by the relocation, symbol, hash and string tables, and a `PT_DYNAMIC`
+    /// segment points to the entries.
+    fn pie_fixture(headers: &[Header], dynamic: &Dynamic<'_>) -> Vec<u8> {
+        let (tables, table_tags, needed) = dynamic_tables(dynamic);
+
+        let mut entries: Vec<(u64, u64)> = needed.into_iter().map(|n| (DT_NEEDED, n)).collect();
+        entries.extend(table_tags);
+        entries.push((0, 0)); // DT_NULL
+
+        let entries_size = 16 * u64::try_from(entries.len()).unwrap();
+
+        let mut data = Vec::new();
+        for (tag, value) in entries {
+            // The table addresses are relative to the end of the entries.
+            let value = match tag {
+                DT_HASH | DT_GNU_HASH | DT_STRTAB | DT_SYMTAB | DT_RELA | DT_JMPREL => {
+                    DYNAMIC_VADDR + entries_size + value
+                }
+                _ => value,
+            };
+
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&tables);
+
+        let size = u64::try_from(data.len()).unwrap();
+
+        let mut all_headers = headers.to_vec();
+        all_headers.push(header(DYNAMIC_VADDR, size, size, PF_R));
+        all_headers.push(Header {
+            ty: PT_DYNAMIC,
+            vaddr: DYNAMIC_VADDR,
+            file_size: entries_size,
+            mem_size: entries_size,
+            flags: PF_R,
+            align: 8,
+        });
+
+        let mut elf = elf_header(ET_DYN, &all_headers);
+        append_segment_data(&mut elf, headers);
+        elf.resize((elf.len() + 7) & !7, 0);
+
+        let offset = u64::try_from(elf.len()).unwrap();
+        for i in headers.len()..all_headers.len() {
+            let phdr = 64 + 56 * i;
+            elf[phdr + 8..phdr + 16].copy_from_slice(&offset.to_le_bytes());
+        }
+
+        elf.extend_from_slice(&data);
+
+        elf
+    }
+
+    /// Returns the tables, the dynamic entries locating them with the addresses relative to the
+    /// start of the tables, and the string offsets of the needed objects.
+    fn dynamic_tables(dynamic: &Dynamic<'_>) -> (Vec<u8>, Vec<(u64, u64)>, Vec<u64>) {
+        let mut dynstr = vec![0];
+        let mut add_string = |s: &str| {
+            let offset = dynstr.len();
+            dynstr.extend_from_slice(s.as_bytes());
+            dynstr.push(0);
+            u32::try_from(offset).unwrap()
+        };
+
+        let needed = dynamic
+            .needed
+            .iter()
+            .map(|n| u64::from(add_string(n)))
+            .collect();
+        let names: Vec<u32> = dynamic.symbols.iter().map(|s| add_string(s.name)).collect();
+
+        let mut tables = Vec::new();
+        let mut tags = Vec::new();
+        let offset_of = |tables: &[u8]| u64::try_from(tables.len()).unwrap();
+
+        tags.push((DT_RELA, offset_of(&tables)));
+        append_relas(&mut tables, dynamic.relas);
+        tags.push((DT_RELASZ, offset_of(&tables) - tags[0].1));
+
+        if !dynamic.plt_relas.is_empty() {
+            let start = offset_of(&tables);
+            append_relas(&mut tables, dynamic.plt_relas);
+            tags.push((DT_JMPREL, start));
+            tags.push((DT_PLTRELSZ, offset_of(&tables) - start));
+            tags.push((DT_PLTREL, DT_RELA));
+        }
+
+        tags.push((DT_SYMTAB, offset_of(&tables)));
+        tables.extend_from_slice(&[0; 24]);
+        for (s, name) in dynamic.symbols.iter().zip(names) {
+            tables.extend_from_slice(&name.to_le_bytes());
+            tables.push(STB_GLOBAL << 4);
+            tables.push(0);
+            tables.extend_from_slice(&s.shndx.to_le_bytes());
+            tables.extend_from_slice(&s.value.to_le_bytes());
+            tables.extend_from_slice(&0_u64.to_le_bytes());
+        }
+
+        let num_of_symbols = u32::try_from(dynamic.symbols.len()).unwrap() + 1;
+        let hash: Vec<u32> = match dynamic.hash {
+            // One bucket, and the chain of every symbol.
+            HashTable::SysV => [1, num_of_symbols, 0]
+                .into_iter()
+                .chain((0..num_of_symbols).map(|_| 0))
+                .collect(),
+            // One bucket and a zero Bloom filter word. The chain of the bucket starts at the
+            // symbol 1, and the hash of the last symbol ends it.
+            HashTable::Gnu => [1, 1, 1, 0, 0, 0, 1]
+                .into_iter()
+                .chain((1..num_of_symbols).map(|i| u32::from(i == num_of_symbols - 1)))
+                .collect(),
+            HashTable::Missing => Vec::new(),
+        };
+
+        match dynamic.hash {
+            HashTable::SysV => tags.push((DT_HASH, offset_of(&tables))),
+            HashTable::Gnu => tags.push((DT_GNU_HASH, offset_of(&tables))),
+            HashTable::Missing => {}
+        }
+        for h in hash {
+            tables.extend_from_slice(&h.to_le_bytes());
+        }
+
+        tags.push((DT_STRTAB, offset_of(&tables)));
+        tags.push((DT_STRSZ, u64::try_from(dynstr.len()).unwrap()));
+        tables.extend_from_slice(&dynstr);
+
+        (tables, tags, needed)
+    }
+
+    fn append_relas(tables: &mut Vec<u8>, relas: &[Rela]) {
+        for r in relas {
+            tables.extend_from_slice(&r.offset.to_le_bytes());
+            tables.extend_from_slice(&(u64::from(r.symbol) << 32 | u64::from(r.ty)).to_le_bytes());
+            tables.extend_from_slice(&r.addend.to_le_bytes());
+        }
+    }
+
+    fn elf_header(ty: u16, headers: &[Header]) -> Vec<u8> {
+        const EHDR_SIZE: u16 = 64;
+        const PHDR_SIZE: u16 = 56;
+
+        let phnum = u16::try_from(headers.len()).unwrap();
+        let data_offset = u64::from(EHDR_SIZE + PHDR_SIZE * phnum);
+
+        let mut elf = Vec::new();
+
+        elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        elf.extend_from_slice(&[0; 8]);
+        elf.extend_from_slice(&ty.to_le_bytes());
+        elf.extend_from_slice(&0x3e_u16.to_le_bytes()); // EM_X86_64
+        elf.extend_from_slice(&1_u32.to_le_bytes());
+        elf.extend_from_slice(&headers.first().map_or(0, |h| h.vaddr).to_le_bytes());
+        elf.extend_from_slice(&u64::from(EHDR_SIZE).to_le_bytes());
+        elf.extend_from_slice(&0_u64.to_le_bytes());
+        elf.extend_from_slice(&0_u32.to_le_bytes());
+        elf.extend_from_slice(&EHDR_SIZE.to_le_bytes());
+        elf.extend_from_slice(&PHDR_SIZE.to_le_bytes());
+        elf.extend_from_slice(&phnum.to_le_bytes());
+        elf.extend_from_slice(&[0; 6]);
+
+        for h in headers {
+            elf.extend_from_slice(&h.ty.to_le_bytes());
+            elf.extend_from_slice(&h.flags.to_le_bytes());
+            elf.extend_from_slice(&data_offset.to_le_bytes());
+            elf.extend_from_slice(&h.vaddr.to_le_bytes());
+            elf.extend_from_slice(&h.vaddr.to_le_bytes());
+            elf.extend_from_slice(&h.file_size.to_le_bytes());
+            elf.extend_from_slice(&h.mem_size.to_le_bytes());
+            elf.extend_from_slice(&h.align.to_le_bytes());
+        }
+
+        elf
+    }
+
+    fn append_segment_data(elf: &mut Vec<u8>, headers: &[Header]) {
+        let max_file_size = headers.iter().map(|h| h.file_size).max().unwrap_or(0);
+        elf.resize(elf.len() + usize::try_from(max_file_size).unwrap(), 0xcc);
+    }
+
+    fn header(vaddr: u64, file_size: u64, mem_size: u64, flags: u32) -> Header {
+        Header {
+            ty: PT_LOAD,
+            vaddr,
+            file_size,
+            mem_size,
+            flags,
+            align: 0x1000,
+        }
+    }
+
+    fn tls_header(vaddr: u64, file_size: u64, mem_size: u64, align: u64) -> Header {
+        Header {
+            ty: PT_TLS,
+            vaddr,
+            file_size,
+            mem_size,
+            flags: PF_R,
+            align,
+        }
+    }
+
+    fn rela(offset: u64, ty: u32, symbol: u32, addend: i64) -> Rela {
+        Rela {
+            offset,
+            ty,
+            symbol,
+            addend,
+        }
+    }
+
+    fn pie() -> Vec<u8> {
+        pie_fixture(
+            &[
+                header(0, 0x10, 0x10, PF_R | PF_X),
+                header(0x1000, 0x20, 0x20, PF_R | PF_W),
+            ],
+            &Dynamic {
+                relas: &[
+                    rela(0x1000, R_X86_64_RELATIVE, 0, 0x8),
+                    rela(0x1008, R_X86_64_GLOB_DAT, 1, 0),
+                    rela(0x1010, R_X86_64_64, 1, -4),
+                    rela(0x1018, R_X86_64_GLOB_DAT, 2, 0),
+                ],
+                plt_relas: &[],
+                symbols: &[defined("f", 0x4), undefined("g")],
+                needed: &["libralib.so"],
+                hash: HashTable::SysV,
+            },
+        )
+    }
+
+    fn defined(name: &str, value: u64) -> Symbol<'_> {
+        Symbol {
+            name,
+            value,
+            shndx: 1,
+        }
+    }
+
+    fn undefined(name: &str) -> Symbol<'_> {
+        Symbol {
+            name,
+            value: 0,
+            shndx: 0,
+        }
+    }
+
+    fn no_symbols(_: &str) -> Option<u64> {
+        None
+    }
+
+    fn region(start: u64, end: u64, writable: bool, executable: bool) -> Region {
+        Region {
+            start,
+            end,
+            writable,
+            executable,
+        }
+    }
+
+    #[test]
+    fn text_and_bss() {
+        let elf = fixture(&[
+            header(0x40_0000, 0x10, 0x10, PF_R | PF_X),
+            header(0x40_1000, 0x8, 0x2000, PF_R | PF_W),
+        ]);
+
+        let l = layout(&elf, 0, USER).unwrap();
+
+        assert_eq!(
+            l.segments[1],
+            Segment {
+                start: 0x40_1000,
+                offset: 0xb0,
+                file_size: 0x8,
+                mem_size: 0x2000,
+                writable: true,
+                executable: false,
+            }
+        );
+        assert_eq!(
+            l.regions,
+            [
+                region(0x40_0000, 0x40_1000, false, true),
+                region(0x40_1000, 0x40_3000, true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_page_has_union_of_permissions() {
+        let elf = fixture(&[
+            header(0x40_0000, 0x1800, 0x1800, PF_R | PF_X),
+            header(0x40_1800, 0x10, 0x1000, PF_R | PF_W),
+        ]);
+
+        let l = layout(&elf, 0, USER).unwrap();
+
+        assert_eq!(
+            l.regions,
+            [
+                region(0x40_0000, 0x40_1000, false, true),
+                region(0x40_1000, 0x40_2000, true, true),
+                region(0x40_2000, 0x40_3000, true, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_overlapping_segments() {
+        let elf = fixture(&[
+            header(0x40_0000, 0x10, 0x2000, PF_R),
+            header(0x40_1000, 0x10, 0x10, PF_R),
+        ]);
+
+        assert_eq!(
+            layout(&elf, 0, USER),
+            Err(Error::Overlap {
+                first: 0x40_0000,
+                second: 0x40_1000
+            })
+        );
+    }
+
+    #[test]
+    fn reject_kernel_half() {
+        let elf = fixture(&[header(0xffff_ffff_8000_0000, 0x10, 0x10, PF_R | PF_X)]);
+
+        assert_eq!(
+            layout(&elf, 0, USER),
+            Err(Error::OutOfRange {
+                start: 0xffff_ffff_8000_0000
+            })
+        );
+    }
+
+    #[test]
+    fn reject_segment_crossing_user_end() {
+        let elf = fixture(&[header(0x7fff_ffff_f000, 0x10, 0x2000, PF_R)]);
+
+        assert_eq!(
+            layout(&elf, 0, USER),
+            Err(Error::OutOfRange {
+                start: 0x7fff_ffff_f000
+            })
+        );
+    }
+
+    #[test]
+    fn reject_too_large_file_size() {
+        let elf = fixture(&[header(0x40_0000, 0x20, 0x10, PF_R)]);
+
+        assert_eq!(
+            layout(&elf, 0, USER),
+            Err(Error::FileSizeTooLarge { start: 0x40_0000 })
+        );
+    }
+
+    #[test]
+    fn reject_data_out_of_file() {
+        let mut elf = fixture(&[header(0x40_0000, 0x100, 0x100, PF_R)]);
+        elf.truncate(elf.len() - 1);
+
+        assert_eq!(
+            layout(&elf, 0, USER),
+            Err(Error::DataOutOfFile { start: 0x40_0000 })
+        );
+    }
+
+    #[test]
+    fn reject_non_elf() {
+        assert!(matches!(
+            layout(b"not an ELF file", 0, USER),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn executable_is_not_relocatable() {
+        let elf = fixture(&[header(0x40_0000, 0x10, 0x10, PF_R | PF_X)]);
+
+        assert_eq!(relocatable_range(&elf), Ok(None));
+        assert_eq!(
+            layout(&elf, 0x1000, USER),
+            Err(Error::NotPositionIndependent)
+        );
+        assert_eq!(
+            layout(&elf, 0, USER).unwrap().relocations(&elf, no_symbols),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn place_pie_at_base() {
+        let elf = pie();
+
+        assert_eq!(relocatable_range(&elf), Ok(Some(0..0x3000)));
+
+        let l = layout(&elf, 0x10_0000, USER).unwrap();
+
+        assert_eq!(
+            l.regions,
+            [
+                region(0x10_0000, 0x10_1000, false, true),
+                region(0x10_1000, 0x10_2000, true, false),
+                region(0x10_2000, 0x10_3000, false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn relocate_pie() {
+        let elf = pie();
+        let l = layout(&elf, 0x10_0000, USER).unwrap();
+
+        let resolve = |name: &str| (name == "g").then(|| 0x20_0000);
+
+        assert_eq!(
+            l.relocations(&elf, resolve),
+            Ok(vec![
+                Relocation {
+                    addr: 0x10_1000,
+                    value: 0x10_0008
+                },
+                Relocation {
+                    addr: 0x10_1008,
+                    value: 0x10_0004
+                },
+                Relocation {
+                    addr: 0x10_1010,
+                    value: 0x10_0000
+                },
+                Relocation {
+                    addr: 0x10_1018,
+                    value: 0x20_0000
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn other_object_interposes_symbol() {
+        let elf = pie();
+        let l = layout(&elf, 0x10_0000, USER).unwrap();
+
+        let resolve = |_: &str| Some(0x30_0000);
+
+        assert_eq!(
+            l.relocations(&elf, resolve).unwrap()[1],
+            Relocation {
+                addr: 0x10_1008,
+                value: 0x30_0000
+            }
+        );
+    }
+
+    #[test]
+    fn dynamic_section() {
+        let elf = pie();
+
+        assert_eq!(needed(&elf), Ok(vec!["libralib.so"]));
+        assert_eq!(symbol(&elf, "f"), Ok(Some(0x4)));
+        assert_eq!(symbol(&elf, "g"), Ok(None));
+        assert_eq!(symbol(&elf, "h"), Ok(None));
+    }
+
+    #[test]
+    fn plt_relocations_and_gnu_hash() {
+        let elf = pie_fixture(
+            &[
+                header(0, 0x10, 0x10, PF_R | PF_X),
+                header(0x1000, 0x10, 0x10, PF_R | PF_W),
+            ],
+            &Dynamic {
+                relas: &[rela(0x1000, R_X86_64_RELATIVE, 0, 0x8)],
+                plt_relas: &[rela(0x1008, R_X86_64_JUMP_SLOT, 2, 0)],
+                symbols: &[undefined("f"), defined("g", 0xc)],
+                needed: &[],
+                hash: HashTable::Gnu,
+            },
+        );
+        let l = layout(&elf, 0x10_0000, USER).unwrap();
+
+        assert_eq!(symbol(&elf, "g"), Ok(Some(0xc)));
+        assert_eq!(
+            l.relocations(&elf, no_symbols),
+            Ok(vec![
+                Relocation {
+                    addr: 0x10_1000,
+                    value: 0x10_0008
+                },
+                Relocation {
+                    addr: 0x10_1008,
+                    value: 0x10_000c
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn reject_symbol_table_without_hash_table() {
+        let elf = pie_fixture(
+            &[header(0x1000, 0x10, 0x10, PF_R | PF_W)],
+            &Dynamic {
+                relas: &[rela(0x1000, R_X86_64_GLOB_DAT, 1, 0)],
+                plt_relas: &[],
+                symbols: &[defined("f", 0)],
+                needed: &[],
+                hash: HashTable::Missing,
+            },
+        );
+        let l = layout(&elf, 0, USER).unwrap();
+
+        assert_eq!(
+            l.relocations(&elf, no_symbols),
+            Err(Error::MissingDynamicTag { tag: DT_HASH })
+        );
+    }
+
+    #[test]
+    fn file_data_of_shared_page() {
+        let elf = fixture(&[
+            header(0x40_0000, 0x1800, 0x1800, PF_R | PF_X),
+            header(0x40_1800, 0x10, 0x1000, PF_R | PF_W),
+        ]);
+
+        let l = layout(&elf, 0, USER).unwrap();
+        let offset = l.segments[0].offset;
+
+        assert_eq!(
+            l.file_data(&l.regions[1]),
+            [
+                FileData {
+                    addr: 0x40_1000,
+                    offset: offset + 0x1000,
+                    size: 0x800
+                },
+                FileData {
+                    addr: 0x40_1800,
+                    offset,
+                    size: 0x10
+                },
+            ]
+        );
+        assert_eq!(l.file_data(&l.regions[2]), []);
+    }
+
+    #[test]
+    fn reject_bad_relocations() {
+        let headers = [
+            header(0, 0x10, 0x10, PF_R | PF_X),
+            header(0x1000, 0x10, 0x10, PF_R | PF_W),
+        ];
+        let symbols = [defined("f", 0), undefined("g")];
+
+        let cases = [
+            (
+                rela(0x1000, R_X86_64_COPY, 1, 0),
+                Error::UnsupportedRelocation { ty: R_X86_64_COPY },
+            ),
+            (
+                rela(0x1000, R_X86_64_GLOB_DAT, 2, 0),
+                Error::UndefinedSymbol { index: 2 },
+            ),
+            (
+                rela(0x1000, R_X86_64_GLOB_DAT, 3, 0),
+                Error::UndefinedSymbol { index: 3 },
+            ),
+            (
+                rela(0x100c, R_X86_64_RELATIVE, 0, 0),
+                Error::RelocationOutOfSegment { offset: 0x100c },
+            ),
+            (
+                rela(0x8, R_X86_64_RELATIVE, 0, 0),
+                Error::RelocationOutOfSegment { offset: 0x8 },
+            ),
+        ];
+
+        for (r, e) in cases {
+            let elf = pie_fixture(
+                &headers,
+                &Dynamic {
+                    relas: &[r],
+                    plt_relas: &[],
+                    symbols: &symbols,
+                    needed: &[],
+                    hash: HashTable::SysV,
+                },
+            );
+            let l = layout(&elf, 0x1000, USER).unwrap();
+
+            assert_eq!(l.relocations(&elf, no_symbols), Err(e));
+        }
+    }
+
+    #[test]
+    fn tls_block() {
+        let elf = fixture(&[
+            header(0x40_0000, 0x100, 0x200, PF_R | PF_W),
+            tls_header(0x40_0010, 0x8, 0x18, 0x10),
+        ]);
+
+        let tls = layout(&elf, 0, USER).unwrap().tls.unwrap();
+
+        assert_eq!(
+            tls,
+            Tls {
+                start: 0x40_0010,
+                file_size: 0x8,
+                mem_size: 0x18,
+                align: 0x10,
+            }
+        );
+        assert_eq!(tls.thread_pointer_offset(), 0x20);
+        assert_eq!(tls.block_size(), 0x28);
+    }
+
+    #[test]
+    fn reject_bad_tls() {
+        let segment = || header(0x40_0000, 0x100, 0x200, PF_R | PF_W);
+
+        let cases = [
+            (
+                tls_header(0x40_00f8, 0x10, 0x10, 8),
+                Error::TlsOutOfSegment { start: 0x40_00f8 },
+            ),
+            (
+                tls_header(0x40_0000, 0x8, 0x8, 0x18),
+                Error::TlsAlignment { align: 0x18 },
+            ),
+            (
+                tls_header(0x40_0000, 0x8, 0x8, 0x2000),
+                Error::TlsAlignment { align: 0x2000 },
+            ),
+        ];
+
+        for (tls, e) in cases {
+            let elf = fixture(&[segment(), tls]);
+
+            assert_eq!(layout(&elf, 0, USER), Err(e));
+        }
+    }
+}
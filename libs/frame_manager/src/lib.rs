@@ -5,114 +5,323 @@
 extern crate alloc;
 
 use {
-    alloc::vec::Vec,
-    boot_info::mem::MemoryDescriptor,
-    core::fmt,
-    os_units::NumOfPages,
+    alloc::{
+        collections::{BTreeMap, BTreeSet},
+        vec::Vec,
+    },
+    boot_info::mem::{MemoryDescriptor, MemoryType},
+    core::{convert::TryFrom, mem},
+    os_units::{Bytes, NumOfPages},
     x86_64::{
-        structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB},
+        structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB},
         PhysAddr,
     },
 };
 
+/// The maximum order of blocks. A block of this order contains 2^18 pages.
+pub const MAX_ORDER: usize = 18;
+
+/// A buddy allocator of physical frames.
+///
+/// A block of the order `n` contains `2^n` frames, and its start address is aligned to the size
+/// of the block.
 #[derive(PartialEq, Eq, Debug)]
-pub struct FrameManager(Vec<Frames>);
+pub struct FrameManager {
+    free_blocks: [BTreeSet<PhysAddr>; MAX_ORDER + 1],
+    allocated: BTreeMap<PhysAddr, NumOfPages<Size4KiB>>,
+    /// The number of additional references to the shared allocations.
+    shared: BTreeMap<PhysAddr, usize>,
+    reclaimable: Vec<MemoryDescriptor>,
+    total_pages: usize,
+    free_pages: usize,
+}
 impl FrameManager {
     #[must_use]
     pub const fn new() -> Self {
-        Self(Vec::new())
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: BTreeSet<PhysAddr> = BTreeSet::new();
+
+        Self {
+            free_blocks: [EMPTY; MAX_ORDER + 1],
+            allocated: BTreeMap::new(),
+            shared: BTreeMap::new(),
+            reclaimable: Vec::new(),
+            total_pages: 0,
+            free_pages: 0,
+        }
     }
 
+    /// Adds the conventional memory to the pool of frames.
+    ///
+    /// The boot services memory and the ACPI reclaim memory are recorded but not added. Call
+    /// [`FrameManager::reclaim`] to add them after the kernel stops using them.
     pub fn init(&mut self, mem_map: &[MemoryDescriptor]) {
         for descriptor in mem_map {
             self.init_for_descriptor(descriptor);
         }
     }
 
-    fn init_for_descriptor(&mut self, descriptor: &MemoryDescriptor) {
-        let frames = Frames::new_for_available(descriptor.start, descriptor.num_pages);
+    /// Adds the recorded memory regions of the type `ty` to the pool of frames.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `ty` is neither [`MemoryType::BootServices`] nor
+    /// [`MemoryType::AcpiReclaim`].
+    pub fn reclaim(&mut self, ty: MemoryType) {
+        assert!(
+            Self::is_reclaimable(ty),
+            "The memory of the type {ty:?} is not reclaimable."
+        );
 
-        self.0.push(frames);
-    }
-}
-impl FrameManager {
-    pub fn alloc(&mut self, num_of_pages: NumOfPages<Size4KiB>) -> Option<PhysAddr> {
-        for i in 0..self.0.len() {
-            if self.0[i].is_available_for_allocating(num_of_pages) {
-                return Some(self.alloc_from_frames_at(i, num_of_pages));
-            }
-        }
+        let (reclaimed, remaining): (Vec<_>, Vec<_>) = mem::take(&mut self.reclaimable)
+            .into_iter()
+            .partition(|d| d.ty == ty);
 
-        None
+        self.reclaimable = remaining;
+
+        for descriptor in reclaimed {
+            self.add_available(descriptor.start, descriptor.num_pages);
+        }
     }
 
-    fn alloc_from_frames_at(&mut self, i: usize, n: NumOfPages<Size4KiB>) -> PhysAddr {
-        if self.0[i].is_splittable(n) {
-            self.split_frames(i, n);
+    #[must_use]
+    pub fn stats(&self) -> Stats {
+        Stats {
+            total: NumOfPages::new(self.total_pages),
+            free: NumOfPages::new(self.free_pages),
         }
+    }
 
-        self.0[i].available = false;
-        self.0[i].start
+    fn init_for_descriptor(&mut self, descriptor: &MemoryDescriptor) {
+        if descriptor.ty == MemoryType::Conventional {
+            self.add_available(descriptor.start, descriptor.num_pages);
+        } else if Self::is_reclaimable(descriptor.ty) {
+            self.reclaimable.push(*descriptor);
+        }
     }
 
-    fn split_frames(&mut self, i: usize, num_of_pages: NumOfPages<Size4KiB>) {
-        assert!(self.0[i].available, "Frames are not available.");
-        assert!(
-            self.0[i].num_of_pages > num_of_pages,
-            "Insufficient number of frames."
-        );
+    fn add_available(&mut self, start: PhysAddr, num_of_pages: NumOfPages<Size4KiB>) {
+        self.free_range(start, num_of_pages);
 
-        self.split_frames_unchecked(i, num_of_pages);
+        self.total_pages += num_of_pages.as_usize();
+        self.free_pages += num_of_pages.as_usize();
     }
 
-    fn split_frames_unchecked(&mut self, i: usize, requested: NumOfPages<Size4KiB>) {
-        let new_frames_start = self.0[i].start + requested.as_bytes().as_usize();
-        let new_frames_num = self.0[i].num_of_pages - requested;
-        let new_frames = Frames::new_for_available(new_frames_start, new_frames_num);
+    fn is_reclaimable(ty: MemoryType) -> bool {
+        matches!(ty, MemoryType::BootServices | MemoryType::AcpiReclaim)
+    }
+}
+impl FrameManager {
+    pub fn alloc(&mut self, num_of_pages: NumOfPages<Size4KiB>) -> Option<PhysAddr> {
+        self.alloc_aligned(num_of_pages, Bytes::new(page_size()))
+    }
+
+    /// Allocates `num_of_pages` contiguous frames whose start address is aligned to `alignment`.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `alignment` is not a power of two, or `num_of_pages` is zero.
+    pub fn alloc_aligned(
+        &mut self,
+        num_of_pages: NumOfPages<Size4KiB>,
+        alignment: Bytes,
+    ) -> Option<PhysAddr> {
+        self.alloc_within(num_of_pages, alignment, None)
+    }
+
+    /// Allocates `num_of_pages` contiguous frames whose start address is aligned to `alignment`,
+    /// and whose end address is less than or equal to `limit`.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `alignment` is not a power of two, or `num_of_pages` is zero.
+    #[allow(clippy::too_many_arguments)]
+    pub fn alloc_below(
+        &mut self,
+        num_of_pages: NumOfPages<Size4KiB>,
+        alignment: Bytes,
+        limit: PhysAddr,
+    ) -> Option<PhysAddr> {
+        self.alloc_within(num_of_pages, alignment, Some(limit))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn alloc_within(
+        &mut self,
+        num_of_pages: NumOfPages<Size4KiB>,
+        alignment: Bytes,
+        limit: Option<PhysAddr>,
+    ) -> Option<PhysAddr> {
+        assert!(
+            alignment.as_usize().is_power_of_two(),
+            "The alignment must be a power of two."
+        );
+        assert_ne!(num_of_pages.as_usize(), 0, "Tried to allocate zero frames.");
+
+        let order_for_size = order_containing(num_of_pages.as_usize());
+        let order_for_alignment = order_of(alignment.as_usize().max(page_size()) / page_size());
+        let order = order_for_size.max(order_for_alignment);
+
+        let addr = self.pop_block(order, |addr| {
+            limit.is_none_or(|l| addr + num_of_pages.as_bytes().as_usize() <= l)
+        })?;
+
+        // Return the unused tail of the block.
+        let end = addr + num_of_pages.as_bytes().as_usize();
+        self.free_range(end, NumOfPages::new(block_pages(order)) - num_of_pages);
+
+        self.allocated.insert(addr, num_of_pages);
+        self.free_pages -= num_of_pages.as_usize();
+
+        Some(addr)
+    }
+
+    /// Removes the free block of the smallest order which is greater than or equal to `order`
+    /// and whose start address satisfies `acceptable`, and splits it into the blocks of `order`.
+    ///
+    /// Only the block with the lowest address is checked in each order.
+    fn pop_block(
+        &mut self,
+        order: usize,
+        acceptable: impl Fn(PhysAddr) -> bool,
+    ) -> Option<PhysAddr> {
+        let (found, addr) = (order..=MAX_ORDER).find_map(|o| {
+            let addr = *self.free_blocks[o].iter().next()?;
+            acceptable(addr).then_some((o, addr))
+        })?;
+
+        self.free_blocks[found].remove(&addr);
+
+        // Split the block until its order becomes the requested one.
+        for o in (order..found).rev() {
+            let buddy = addr + block_bytes(o);
+            self.free_blocks[o].insert(buddy);
+        }
 
-        self.0[i].num_of_pages = requested;
-        self.0.insert(i + 1, new_frames);
+        Some(addr)
     }
 }
 impl FrameManager {
-    pub fn free(&mut self, addr: PhysAddr) {
-        for i in 0..self.0.len() {
-            if self.0[i].start == addr && !self.0[i].available {
-                return self.free_memory_for_frames_at(i);
+    /// Frees the frames allocated by [`FrameManager::alloc`] or [`FrameManager::alloc_aligned`].
+    ///
+    /// If the allocation is shared, this method only drops one reference to it. The frames are
+    /// freed when the last reference is dropped.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if `addr` is not the start address of an allocation,
+    /// or `num_of_pages` differs from the allocated number of frames.
+    pub fn free(
+        &mut self,
+        addr: PhysAddr,
+        num_of_pages: NumOfPages<Size4KiB>,
+    ) -> Result<(), Error> {
+        self.check_allocation(addr, num_of_pages)?;
+
+        if let Some(n) = self.shared.get_mut(&addr) {
+            *n -= 1;
+
+            if *n == 0 {
+                self.shared.remove(&addr);
             }
+
+            return Ok(());
         }
-    }
 
-    fn free_memory_for_frames_at(&mut self, i: usize) {
-        self.0[i].available = true;
-        self.merge_before_and_after_frames(i);
+        self.allocated.remove(&addr);
+        self.free_range(addr, num_of_pages);
+        self.free_pages += num_of_pages.as_usize();
+
+        Ok(())
+    }
+
+    fn check_allocation(
+        &self,
+        addr: PhysAddr,
+        num_of_pages: NumOfPages<Size4KiB>,
+    ) -> Result<(), Error> {
+        match self.allocated.get(&addr) {
+            Some(&n) if n == num_of_pages => Ok(()),
+            Some(&n) => Err(Error::SizeMismatch {
+                addr,
+                allocated: n,
+                requested: num_of_pages,
+            }),
+            None if self.is_free(addr) => Err(Error::DoubleFree(addr)),
+            None => Err(Error::InvalidAddress(addr)),
+        }
     }
 
-    fn merge_before_and_after_frames(&mut self, i: usize) {
-        if self.mergeable_to_next_frames(i) {
-            self.merge_to_next_frames(i);
+    /// Adds a reference to the allocation starting from `addr`.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if `addr` is not the start address of an allocation.
+    pub fn add_reference(&mut self, addr: PhysAddr) -> Result<(), Error> {
+        if !self.allocated.contains_key(&addr) {
+            return Err(Error::InvalidAddress(addr));
         }
 
-        if i > 0 && self.mergeable_to_next_frames(i - 1) {
-            self.merge_to_next_frames(i - 1);
+        *self.shared.entry(addr).or_default() += 1;
+
+        Ok(())
+    }
+
+    /// Returns the number of references to the allocation starting from `addr`, or zero if `addr`
+    /// is not the start address of an allocation.
+    #[must_use]
+    pub fn reference_count(&self, addr: PhysAddr) -> usize {
+        if self.allocated.contains_key(&addr) {
+            1 + self.shared.get(&addr).copied().unwrap_or_default()
+        } else {
+            0
         }
     }
 
-    fn mergeable_to_next_frames(&self, i: usize) -> bool {
-        if i >= self.0.len() - 1 {
-            return false;
+    fn free_range(&mut self, start: PhysAddr, num_of_pages: NumOfPages<Size4KiB>) {
+        let mut addr = start;
+        let mut remaining = num_of_pages.as_usize();
+
+        while remaining > 0 {
+            let order = max_order_at(addr).min(order_of(remaining));
+
+            self.free_block(addr, order);
+
+            addr += block_bytes(order);
+            remaining -= block_pages(order);
         }
+    }
+
+    fn free_block(&mut self, addr: PhysAddr, order: usize) {
+        let mut addr = addr;
+        let mut order = order;
+
+        while order < MAX_ORDER {
+            let buddy = PhysAddr::new(addr.as_u64() ^ block_bytes(order));
+
+            if !self.free_blocks[order].remove(&buddy) {
+                break;
+            }
 
-        let node = &self.0[i];
-        let next = &self.0[i + 1];
+            addr = addr.min(buddy);
+            order += 1;
+        }
 
-        node.is_mergeable(next)
+        self.free_blocks[order].insert(addr);
     }
 
-    fn merge_to_next_frames(&mut self, i: usize) {
-        let n = self.0[i + 1].num_of_pages;
-        self.0[i].num_of_pages += n;
-        self.0.remove(i + 1);
+    fn is_free(&self, addr: PhysAddr) -> bool {
+        self.free_blocks.iter().enumerate().any(|(order, blocks)| {
+            blocks
+                .range(..=addr)
+                .next_back()
+                .is_some_and(|&start| addr < start + block_bytes(order))
+        })
+    }
+}
+impl Default for FrameManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
 unsafe impl FrameAllocator<Size4KiB> for FrameManager {
@@ -124,107 +333,113 @@ unsafe impl FrameAllocator<Size4KiB> for FrameManager {
 impl FrameDeallocator<Size4KiB> for FrameManager {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
         let addr = frame.start_address();
-        self.free(addr);
+        self.free(addr, NumOfPages::new(1))
+            .expect("Failed to free a frame.");
     }
 }
 
-#[derive(PartialEq, Eq)]
-struct Frames {
-    start: PhysAddr,
-    num_of_pages: NumOfPages<Size4KiB>,
-    available: bool,
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Stats {
+    pub total: NumOfPages<Size4KiB>,
+    pub free: NumOfPages<Size4KiB>,
 }
-impl Frames {
-    fn new_for_available(start: PhysAddr, num_of_pages: NumOfPages<Size4KiB>) -> Self {
-        Self {
-            start,
-            num_of_pages,
-            available: true,
-        }
+impl Stats {
+    #[must_use]
+    pub fn used(&self) -> NumOfPages<Size4KiB> {
+        self.total - self.free
     }
+}
 
-    #[cfg(test)]
-    fn new_for_used(start: PhysAddr, num_of_pages: NumOfPages<Size4KiB>) -> Self {
-        Self {
-            start,
-            num_of_pages,
-            available: false,
-        }
-    }
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The frames starting from the address are already freed.
+    DoubleFree(PhysAddr),
+    /// The address is not the start address of an allocation.
+    InvalidAddress(PhysAddr),
+    /// The number of frames to free differs from the allocated one.
+    SizeMismatch {
+        addr: PhysAddr,
+        allocated: NumOfPages<Size4KiB>,
+        requested: NumOfPages<Size4KiB>,
+    },
+}
 
-    fn is_splittable(&self, requested: NumOfPages<Size4KiB>) -> bool {
-        self.num_of_pages > requested
-    }
+fn page_size() -> usize {
+    usize::try_from(Size4KiB::SIZE).unwrap()
+}
 
-    fn is_available_for_allocating(&self, request_num_of_pages: NumOfPages<Size4KiB>) -> bool {
-        self.num_of_pages >= request_num_of_pages && self.available
-    }
+fn block_pages(order: usize) -> usize {
+    1 << order
+}
 
-    fn is_mergeable(&self, other: &Self) -> bool {
-        self.available && other.available && self.is_consecutive(other)
-    }
+fn block_bytes(order: usize) -> u64 {
+    Size4KiB::SIZE << order
+}
 
-    fn is_consecutive(&self, other: &Self) -> bool {
-        self.end() == other.start
-    }
+/// Returns the largest order whose block can start from `addr`.
+fn max_order_at(addr: PhysAddr) -> usize {
+    let frame_number = addr.as_u64() / Size4KiB::SIZE;
 
-    fn end(&self) -> PhysAddr {
-        self.start + self.num_of_pages.as_bytes().as_usize()
+    if frame_number == 0 {
+        MAX_ORDER
+    } else {
+        usize::try_from(frame_number.trailing_zeros())
+            .unwrap()
+            .min(MAX_ORDER)
     }
 }
-impl fmt::Debug for Frames {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let suffix = if self.available { "Available" } else { "Used" };
-        write!(
-            f,
-            "Frames::<{}>({:?} .. {:?})",
-            suffix,
-            self.start,
-            self.end()
-        )
-    }
+
+/// Returns the largest order whose block contains at most `num_of_pages` frames.
+fn order_of(num_of_pages: usize) -> usize {
+    let order = usize::BITS - 1 - num_of_pages.leading_zeros();
+    usize::try_from(order).unwrap().min(MAX_ORDER)
+}
+
+/// Returns the smallest order whose block contains at least `num_of_pages` frames.
+fn order_containing(num_of_pages: usize) -> usize {
+    let order = usize::BITS - (num_of_pages - 1).leading_zeros();
+    usize::try_from(order).unwrap()
 }
 
 #[cfg(test)]
 mod tests {
     use {
-        super::{FrameManager, Frames},
-        os_units::NumOfPages,
+        super::{Error, FrameManager},
+        boot_info::mem::{MemoryDescriptor, MemoryType},
+        os_units::{Bytes, NumOfPages},
         x86_64::PhysAddr,
     };
 
-    macro_rules! frames {
-        (A $start:expr => $end:expr) => {
-            Frames::new_for_available(
-                PhysAddr::new($start),
-                os_units::Bytes::new($end - $start).as_num_of_pages(),
-            )
-        };
-        (U $start:expr => $end:expr) => {
-            Frames::new_for_used(
-                PhysAddr::new($start),
-                os_units::Bytes::new($end - $start).as_num_of_pages(),
+    macro_rules! manager {
+        ($($start:expr => $end:expr),*$(,)*) => {{
+            let mut f = FrameManager::new();
+            f.init(&[
+                $(descriptor($start, $end, MemoryType::Conventional)),*
+            ]);
+            f
+        }};
+    }
+
+    fn descriptor(start: u64, end: u64, ty: MemoryType) -> MemoryDescriptor {
+        unsafe {
+            MemoryDescriptor::new(
+                PhysAddr::new(start),
+                Bytes::new((end - start).try_into().unwrap()).as_num_of_pages(),
+                ty,
             )
-        };
+        }
     }
 
-    macro_rules! manager {
-        ($($is_available:ident $start:expr => $end:expr),*$(,)*) => {
-            FrameManager(vec![
-                $(frames!($is_available $start => $end)),*
-            ]
-            )
-        };
+    fn free_pages(f: &FrameManager) -> usize {
+        f.stats().free.as_usize()
     }
 
     #[test]
     fn fail_to_allocate() {
         let mut f = manager!(
-            A 0 => 0x1000,
-            A 0x2000 => 0xc000,
-            U 0xc000 => 0x10000,
-            U 0x10000 => 0x13000,
-            A 0x13000 => 0x15000,
+            0 => 0x1000,
+            0x2000 => 0xc000,
+            0x13000 => 0x15000,
         );
 
         let a = f.alloc(NumOfPages::new(200));
@@ -233,97 +448,246 @@ mod tests {
 
     #[test]
     fn allocate_not_power_of_two() {
-        let mut f = manager!(
-            A 0 => 0x1000,
-            A 0x2000 => 0xc000,
-            U 0xc000 => 0x10000,
-        );
+        let mut f = manager!(0 => 0x10000);
 
         let a = f.alloc(NumOfPages::new(3));
+        assert_eq!(a, Some(PhysAddr::zero()));
 
-        assert_eq!(a, Some(PhysAddr::new(0x2000)));
-        assert_eq!(
-            f,
-            manager!(
-                A 0 => 0x1000,
-                U 0x2000 => 0x5000,
-                A 0x5000 => 0xc000,
-                U 0xc000 => 0x10000,
-            )
-        )
+        // The unused tail of the block is returned to the pool.
+        let b = f.alloc(NumOfPages::new(1));
+        assert_eq!(b, Some(PhysAddr::new(0x3000)));
+
+        assert_eq!(free_pages(&f), 12);
     }
 
     #[test]
     fn allocate_full_frames() {
-        let mut f = manager!(A 0 => 0x3000);
-        let a = f.alloc(NumOfPages::new(3));
+        let mut f = manager!(0 => 0x4000);
+        let a = f.alloc(NumOfPages::new(4));
 
         assert_eq!(a, Some(PhysAddr::zero()));
-        assert_eq!(f, manager!(U 0 => 0x3000));
+        assert_eq!(free_pages(&f), 0);
+        assert!(f.alloc(NumOfPages::new(1)).is_none());
     }
 
     #[test]
-    fn free_single_frames() {
-        let mut f = manager!(U 0 => 0x3000);
-        f.free(PhysAddr::zero());
+    fn allocate_from_lower_address() {
+        let mut f = manager!(
+            0x10000 => 0x20000,
+            0x1000 => 0x2000,
+        );
+
+        assert_eq!(f.alloc(NumOfPages::new(1)), Some(PhysAddr::new(0x1000)));
+        assert_eq!(f.alloc(NumOfPages::new(1)), Some(PhysAddr::new(0x10000)));
+    }
+
+    #[test]
+    fn allocate_aligned() {
+        let mut f = manager!(0x1000 => 0x40_0000);
+
+        let a = f.alloc_aligned(NumOfPages::new(1), Bytes::new(0x20_0000));
 
-        assert_eq!(f, manager!(A 0 => 0x3000));
+        assert_eq!(a, Some(PhysAddr::new(0x20_0000)));
     }
 
     #[test]
-    fn free_and_merge_with_before() {
+    fn allocate_aligned_multiple_pages() {
+        let mut f = manager!(0x3000 => 0x20000);
+
+        let a = f.alloc_aligned(NumOfPages::new(3), Bytes::new(0x4000));
+
+        assert_eq!(a, Some(PhysAddr::new(0x4000)));
+    }
+
+    #[test]
+    fn allocate_below_limit() {
         let mut f = manager!(
-            A 0 => 0x1000,
-            A 0x2000 => 0xc000,
-            U 0xc000 => 0x10000,
+            0x2000 => 0x4000,
+            0x1_0000_0000 => 0x1_0001_0000,
         );
 
-        f.free(PhysAddr::new(0xc000));
+        let limit = PhysAddr::new(0x1_0000_0000);
 
         assert_eq!(
-            f,
-            manager! (
-                A 0 => 0x1000,
-                A 0x2000 => 0x10000
-            )
-        )
+            f.alloc_below(NumOfPages::new(2), Bytes::new(0x1000), limit),
+            Some(PhysAddr::new(0x2000))
+        );
+        assert_eq!(
+            f.alloc_below(NumOfPages::new(1), Bytes::new(0x1000), limit),
+            None
+        );
+        assert_eq!(
+            f.alloc(NumOfPages::new(1)),
+            Some(PhysAddr::new(0x1_0000_0000))
+        );
+    }
+
+    #[test]
+    fn free_single_frames() {
+        let mut f = manager!(0 => 0x4000);
+        let a = f.alloc(NumOfPages::new(4)).unwrap();
+
+        assert_eq!(f.free(a, NumOfPages::new(4)), Ok(()));
+        assert_eq!(free_pages(&f), 4);
     }
 
     #[test]
-    fn free_and_merge_with_after() {
+    fn free_and_merge_all() {
+        let mut f = manager!(0 => 0x10000);
+
+        let a = f.alloc(NumOfPages::new(1)).unwrap();
+        let b = f.alloc(NumOfPages::new(3)).unwrap();
+        let c = f.alloc(NumOfPages::new(5)).unwrap();
+
+        f.free(b, NumOfPages::new(3)).unwrap();
+        f.free(a, NumOfPages::new(1)).unwrap();
+        f.free(c, NumOfPages::new(5)).unwrap();
+
+        assert_eq!(f, manager!(0 => 0x10000));
+        assert_eq!(f.alloc(NumOfPages::new(16)), Some(PhysAddr::zero()));
+    }
+
+    #[test]
+    fn merge_adjacent_regions() {
         let mut f = manager!(
-            U 0 => 0x3000,
-            A 0x3000 => 0x5000,
+            0 => 0x3000,
+            0x3000 => 0x8000,
         );
 
-        f.free(PhysAddr::zero());
+        assert_eq!(f.alloc(NumOfPages::new(8)), Some(PhysAddr::zero()));
+    }
+
+    #[test]
+    fn detect_double_free() {
+        let mut f = manager!(0 => 0x4000);
+        let a = f.alloc(NumOfPages::new(2)).unwrap();
 
+        f.free(a, NumOfPages::new(2)).unwrap();
+
+        assert_eq!(f.free(a, NumOfPages::new(2)), Err(Error::DoubleFree(a)));
+        assert_eq!(free_pages(&f), 4);
+    }
+
+    #[test]
+    fn detect_invalid_free() {
+        let mut f = manager!(0 => 0x4000);
+        let a = f.alloc(NumOfPages::new(4)).unwrap();
+
+        let middle = a + 0x1000_u64;
         assert_eq!(
-            f,
-            manager!(
-                A 0 => 0x5000,
-            )
-        )
+            f.free(middle, NumOfPages::new(1)),
+            Err(Error::InvalidAddress(middle))
+        );
+
+        let outside = PhysAddr::new(0x10_0000);
+        assert_eq!(
+            f.free(outside, NumOfPages::new(1)),
+            Err(Error::InvalidAddress(outside))
+        );
+
+        assert_eq!(free_pages(&f), 0);
     }
 
     #[test]
-    fn free_and_merge_with_before_and_after() {
-        let mut f = manager!(
-            A 0 => 0x3000,
-            U 0x3000 => 0x5000,
-            A 0x5000 => 0x10000,
+    fn detect_size_mismatch() {
+        let mut f = manager!(0 => 0x4000);
+        let a = f.alloc(NumOfPages::new(2)).unwrap();
+
+        assert_eq!(
+            f.free(a, NumOfPages::new(1)),
+            Err(Error::SizeMismatch {
+                addr: a,
+                allocated: NumOfPages::new(2),
+                requested: NumOfPages::new(1),
+            })
         );
+    }
+
+    #[test]
+    fn free_shared_frames() {
+        let mut f = manager!(0 => 0x2000);
+        let a = f.alloc(NumOfPages::new(1)).unwrap();
+
+        f.add_reference(a).unwrap();
+        f.add_reference(a).unwrap();
+
+        assert_eq!(f.reference_count(a), 3);
+
+        f.free(a, NumOfPages::new(1)).unwrap();
+        f.free(a, NumOfPages::new(1)).unwrap();
+
+        assert_eq!(f.reference_count(a), 1);
+        assert_eq!(free_pages(&f), 1);
+
+        f.free(a, NumOfPages::new(1)).unwrap();
+
+        assert_eq!(f.reference_count(a), 0);
+        assert_eq!(free_pages(&f), 2);
+    }
+
+    #[test]
+    fn add_reference_to_unallocated_frame() {
+        let mut f = manager!(0 => 0x2000);
+        let a = PhysAddr::new(0x1000);
+
+        assert_eq!(f.add_reference(a), Err(Error::InvalidAddress(a)));
+    }
+
+    #[test]
+    fn stats() {
+        let mut f = manager!(0 => 0x10000, 0x20000 => 0x24000);
+
+        f.alloc(NumOfPages::new(3)).unwrap();
+
+        let s = f.stats();
+
+        assert_eq!(s.total.as_usize(), 20);
+        assert_eq!(s.free.as_usize(), 17);
+        assert_eq!(s.used().as_usize(), 3);
+    }
+
+    #[test]
+    fn init_only_with_conventional_memory() {
+        let mut f = FrameManager::new();
+        f.init(&[
+            descriptor(0, 0x1000, MemoryType::Reserved),
+            descriptor(0x1000, 0x3000, MemoryType::Conventional),
+            descriptor(0x3000, 0x5000, MemoryType::BootServices),
+            descriptor(0x5000, 0x6000, MemoryType::AcpiReclaim),
+            descriptor(0x6000, 0x8000, MemoryType::Conventional),
+            descriptor(0x8000, 0x9000, MemoryType::Mmio),
+        ]);
+
+        assert_eq!(free_pages(&f), 4);
+        assert_eq!(f.alloc(NumOfPages::new(1)), Some(PhysAddr::new(0x1000)));
+    }
+
+    #[test]
+    fn reclaim_and_merge() {
+        let mut f = FrameManager::new();
+        f.init(&[
+            descriptor(0, 0x3000, MemoryType::Conventional),
+            descriptor(0x3000, 0x5000, MemoryType::BootServices),
+            descriptor(0x5000, 0x6000, MemoryType::AcpiReclaim),
+            descriptor(0x6000, 0x8000, MemoryType::Conventional),
+        ]);
+
+        f.reclaim(MemoryType::BootServices);
+
+        assert_eq!(free_pages(&f), 7);
 
-        f.free(PhysAddr::new(0x3000));
+        f.reclaim(MemoryType::AcpiReclaim);
 
-        assert_eq!(f, manager!(A 0 => 0x10000))
+        assert_eq!(free_pages(&f), 8);
+        assert_eq!(f.alloc(NumOfPages::new(8)), Some(PhysAddr::zero()));
     }
 
     #[test]
-    fn mergable_two_frmaes() {
-        let f1 = frames!(A 0x2000 => 0xc000);
-        let f2 = frames!(A 0xc000 => 0x10000);
+    #[should_panic(expected = "is not reclaimable")]
+    fn reclaim_loader_memory() {
+        let mut f = FrameManager::new();
+        f.init(&[descriptor(0x1000, 0x3000, MemoryType::Loader)]);
 
-        assert!(f1.is_mergeable(&f2));
+        f.reclaim(MemoryType::Loader);
     }
 }
@@ -1,8 +1,9 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 use {
-    os_units::NumOfPages,
-    x86_64::{structures::paging::Size4KiB, VirtAddr},
+    os_units::{Bytes, NumOfPages},
+    syscalls::DmaConstraints,
+    x86_64::{structures::paging::Size4KiB, PhysAddr, VirtAddr},
 };
 
 #[cfg(not(test))]
@@ -24,7 +25,7 @@ pub(super) fn allocate_pages(n: NumOfPages<Size4KiB>) -> VirtAddr {
 
 #[cfg(not(test))]
 pub(super) fn deallocate_pages(v: VirtAddr, n: NumOfPages<Size4KiB>) {
-    syscalls::deallocate_pages(v, n);
+    syscalls::deallocate_pages(v, n).expect("Failed to deallocate pages.");
 }
 
 #[cfg(test)]
@@ -35,6 +36,51 @@ pub(super) fn deallocate_pages(v: VirtAddr, n: NumOfPages<Size4KiB>) {
     unsafe { std::alloc::dealloc(p, l) }
 }
 
+#[cfg(not(test))]
+pub(super) fn allocate_dma(
+    n: NumOfPages<Size4KiB>,
+    constraints: DmaConstraints,
+) -> (VirtAddr, PhysAddr) {
+    let r = syscalls::allocate_dma(n.as_bytes(), constraints);
+
+    r.expect("Failed to allocate memory for DMA.")
+}
+
+#[cfg(test)]
+pub(super) fn allocate_dma(
+    n: NumOfPages<Size4KiB>,
+    constraints: DmaConstraints,
+) -> (VirtAddr, PhysAddr) {
+    let alignment = Bytes::new(constraints.alignment.try_into().unwrap());
+    let l = dma_layout(n, alignment);
+    let p = unsafe { std::alloc::alloc(l) };
+
+    // The tests run on the host, so the physical address is the same as the virtual one.
+    (VirtAddr::from_ptr(p), PhysAddr::new(p as u64))
+}
+
+#[cfg(not(test))]
+pub(super) fn deallocate_dma(v: VirtAddr, n: NumOfPages<Size4KiB>, _alignment: Bytes) {
+    syscalls::deallocate_pages(v, n).expect("Failed to deallocate memory for DMA.");
+}
+
+#[cfg(test)]
+pub(super) fn deallocate_dma(v: VirtAddr, n: NumOfPages<Size4KiB>, alignment: Bytes) {
+    let l = dma_layout(n, alignment);
+    let p = v.as_mut_ptr();
+
+    unsafe { std::alloc::dealloc(p, l) }
+}
+
+#[cfg(test)]
+fn dma_layout(n: NumOfPages<Size4KiB>, alignment: Bytes) -> std::alloc::Layout {
+    let sz = n.as_bytes().as_usize();
+    let align = sz.next_power_of_two().max(alignment.as_usize());
+    let l = std::alloc::Layout::from_size_align(sz, align);
+
+    l.expect("Invalid layout.")
+}
+
 #[cfg(test)]
 fn num_of_pages_to_layout(n: NumOfPages<Size4KiB>) -> std::alloc::Layout {
     let sz = n.as_bytes().as_usize();
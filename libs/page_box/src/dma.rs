@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    super::{alloc, assert_alignment},
+    core::{
+        convert::TryFrom,
+        fmt,
+        marker::PhantomData,
+        mem,
+        ops::{Deref, DerefMut},
+        ptr, slice,
+    },
+    os_units::Bytes,
+    syscalls::DmaConstraints,
+    x86_64::{structures::paging::Size4KiB, PhysAddr, VirtAddr},
+};
+
+/// A box located in physically contiguous memory which devices can access.
+///
+/// Unlike [`PageBox`](super::PageBox), the physical address is fixed when the box is created.
+pub struct DmaBox<T: ?Sized> {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    bytes: Bytes,
+    alignment: Bytes,
+    _marker: PhantomData<T>,
+}
+impl<T> DmaBox<T> {
+    /// # Panics
+    ///
+    /// This method panics if there is no memory satisfying `constraints`.
+    pub fn new(x: T, constraints: DmaConstraints) -> Self {
+        assert_alignment(&x);
+        let bytes = Bytes::new(mem::size_of::<T>());
+        let mut dma_box = Self::from_bytes(bytes, constraints);
+        dma_box.write_initial_value(x);
+        dma_box
+    }
+
+    fn write_initial_value(&mut self, x: T) {
+        // SAFETY: This operation is safe because the memory `self.virt.as_mut_ptr` points is
+        // allocated, and is page-aligned.
+        unsafe {
+            ptr::write(self.virt.as_mut_ptr(), x);
+        }
+    }
+}
+impl<T> Deref for DmaBox<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: This operation is safe because the memory region `virt` points is allocated and
+        // is not used by the others.
+        unsafe { &*self.virt.as_ptr() }
+    }
+}
+impl<T> DerefMut for DmaBox<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: This operation is safe because the memory region `virt` points is allocated and
+        // is not used by the others.
+        unsafe { &mut *self.virt.as_mut_ptr() }
+    }
+}
+impl<T> fmt::Debug for DmaBox<T>
+where
+    T: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+impl<T> Default for DmaBox<T>
+where
+    T: Default,
+{
+    fn default() -> Self {
+        Self::from(T::default())
+    }
+}
+impl<T> From<T> for DmaBox<T> {
+    fn from(x: T) -> Self {
+        Self::new(x, DmaConstraints::default())
+    }
+}
+
+impl<T> DmaBox<[T]>
+where
+    T: Clone,
+{
+    pub fn new_slice(x: T, num_of_elements: usize) -> Self {
+        Self::new_slice_with_constraints(x, num_of_elements, DmaConstraints::default())
+    }
+
+    /// # Panics
+    ///
+    /// This method panics if there is no memory satisfying `constraints`.
+    pub fn new_slice_with_constraints(
+        x: T,
+        num_of_elements: usize,
+        constraints: DmaConstraints,
+    ) -> Self {
+        assert_alignment(&x);
+        let bytes = Bytes::new(mem::size_of::<T>() * num_of_elements);
+        let mut dma_box = Self::from_bytes(bytes, constraints);
+        dma_box.write_all_elements_with_same_value(x);
+        dma_box
+    }
+
+    fn write_all_elements_with_same_value(&mut self, x: T) {
+        for i in 0..self.len() {
+            let ptr: usize = usize::try_from(self.virt.as_u64()).unwrap() + mem::size_of::<T>() * i;
+
+            // SAFETY: This operation is safe. The memory ptr points is allocated and is aligned
+            // because the first elements is page-aligned.
+            unsafe { ptr::write(ptr as *mut T, x.clone()) }
+        }
+    }
+
+    fn num_of_elements(&self) -> usize {
+        self.bytes.as_usize() / mem::size_of::<T>()
+    }
+}
+impl<T> Deref for DmaBox<[T]>
+where
+    T: Clone,
+{
+    type Target = [T];
+    fn deref(&self) -> &Self::Target {
+        unsafe { slice::from_raw_parts(self.virt.as_ptr(), self.num_of_elements()) }
+    }
+}
+impl<T> DerefMut for DmaBox<[T]>
+where
+    T: Clone,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.num_of_elements()) }
+    }
+}
+impl<T> fmt::Debug for DmaBox<[T]>
+where
+    T: Clone,
+    [T]: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized> DmaBox<T> {
+    #[must_use]
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    #[must_use]
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    #[must_use]
+    pub fn bytes(&self) -> Bytes {
+        self.bytes
+    }
+
+    fn from_bytes(bytes: Bytes, constraints: DmaConstraints) -> Self {
+        let (virt, phys) = alloc::allocate_dma(bytes.as_num_of_pages(), constraints);
+
+        Self {
+            virt,
+            phys,
+            bytes,
+            alignment: Bytes::new(usize::try_from(constraints.alignment).unwrap()),
+            _marker: PhantomData,
+        }
+    }
+}
+impl<T: ?Sized> Drop for DmaBox<T> {
+    fn drop(&mut self) {
+        let num_of_pages = self.bytes.as_num_of_pages::<Size4KiB>();
+        alloc::deallocate_dma(self.virt, num_of_pages, self.alignment);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::DmaBox,
+        syscalls::{CacheType, DmaConstraints},
+    };
+
+    #[test]
+    fn phys_addr_is_aligned() {
+        let constraints = DmaConstraints {
+            alignment: 0x10000,
+            below_4gib: true,
+            cache: CacheType::Uncacheable,
+        };
+
+        let b = DmaBox::new_slice_with_constraints(0_u8, 0x3000, constraints);
+
+        assert_eq!(b.phys_addr().as_u64() % 0x10000, 0);
+        assert!(b.iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn default_constraints() {
+        let b = DmaBox::from([3_u32; 4]);
+
+        assert_eq!(*b, [3; 4]);
+        assert_eq!(b.phys_addr().as_u64() % 0x1000, 0);
+    }
+}
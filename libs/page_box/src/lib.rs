@@ -18,7 +18,13 @@ use {
     },
 };
 
+pub use {
+    dma::DmaBox,
+    syscalls::{CacheType, DmaConstraints},
+};
+
 mod alloc;
+mod dma;
 
 pub struct PageBox<T: ?Sized> {
     virt: VirtAddr,
@@ -8,7 +8,10 @@ use {
 
 pub const KERNEL_ADDR: VirtAddr = VirtAddr::new_truncate(0xffff_ffff_8000_0000);
 pub const INITRD_ADDR: VirtAddr = VirtAddr::new_truncate(0xffff_ffff_8800_0000);
-pub const VRAM_ADDR: VirtAddr = VirtAddr::new_truncate(0xffff_ffff_a000_1000);
+/// Aligned to a huge page boundary so that the framebuffer can be mapped with huge pages.
+pub const VRAM_ADDR: VirtAddr = VirtAddr::new_truncate(0xffff_ffff_a000_0000);
+/// The kernel heap maps more memory from this address when the initial region runs out.
+pub const HEAP_EXTENSION_ADDR: VirtAddr = VirtAddr::new_truncate(0xffff_ff80_0000_0000);
 pub const STACK_BASE: VirtAddr = VirtAddr::new_truncate(0xffff_ffff_c000_0000);
 pub static STACK_LOWER: Lazy<VirtAddr> = Lazy::new(|| {
     VirtAddr::new_truncate(STACK_BASE.as_u64() - NUM_OF_PAGES_STACK.as_bytes().as_usize() as u64)
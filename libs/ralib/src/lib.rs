@@ -2,13 +2,15 @@
 
 #![no_std]
 #![allow(clippy::too_many_arguments)] // A workaround for the clippy's wrong warning.
-#![feature(alloc_error_handler)]
+#![feature(alloc_error_handler, allow_internal_unstable)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
 use core::alloc::Layout;
 
 pub mod io;
 pub mod mem;
+pub mod time;
+pub mod tls;
 
 extern crate alloc;
 
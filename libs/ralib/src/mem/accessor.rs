@@ -4,6 +4,7 @@ use {
     accessor::single::ReadWrite,
     core::{convert::TryInto, num::NonZeroUsize},
     os_units::Bytes,
+    syscalls::CacheType,
     x86_64::{PhysAddr, VirtAddr},
 };
 
@@ -33,7 +34,7 @@ impl accessor::Mapper for Mapper {
         let phys_start = PhysAddr::new(phys_start.try_into().unwrap());
         let bytes = Bytes::new(bytes);
 
-        let a = syscalls::map_pages(phys_start, bytes);
+        let a = syscalls::map_pages(phys_start, bytes, CacheType::Uncacheable);
 
         NonZeroUsize::new(a.as_u64().try_into().unwrap()).expect("Failed to map pages.")
     }
@@ -42,6 +43,6 @@ impl accessor::Mapper for Mapper {
         let virt_start = VirtAddr::new(virt_start.try_into().unwrap());
         let bytes = Bytes::new(bytes);
 
-        syscalls::unmap_pages(virt_start, bytes);
+        syscalls::unmap_pages(virt_start, bytes).expect("Failed to unmap pages.");
     }
 }
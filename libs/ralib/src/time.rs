@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Measuring and waiting for time with the monotonic clock of the kernel.
+//!
+//! A driver waiting for a register to change should sleep between the reads instead of spinning:
+//!
+//! ```ignore
+//! use ralib::time::{self, Duration, Instant};
+//!
+//! let deadline = Instant::now() + Duration::from_millis(100);
+//!
+//! while !ready() {
+//!     assert!(Instant::now() < deadline, "Timed out.");
+//!
+//!     time::sleep(Duration::from_millis(1));
+//! }
+//! ```
+
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+pub use core::time::Duration;
+
+/// A point of the monotonic clock, which never goes backward.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(Duration);
+impl Instant {
+    #[must_use]
+    pub fn now() -> Self {
+        Self(syscalls::monotonic_time())
+    }
+
+    /// Returns the time elapsed from `earlier` to `self`, or zero if `earlier` is later.
+    #[must_use]
+    pub fn duration_since(&self, earlier: Self) -> Duration {
+        self.0.saturating_sub(earlier.0)
+    }
+
+    #[must_use]
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+
+    #[must_use]
+    pub fn checked_add(&self, duration: Duration) -> Option<Self> {
+        self.0.checked_add(duration).map(Self)
+    }
+
+    #[must_use]
+    pub fn checked_sub(&self, duration: Duration) -> Option<Self> {
+        self.0.checked_sub(duration).map(Self)
+    }
+}
+impl Add<Duration> for Instant {
+    type Output = Self;
+
+    fn add(self, rhs: Duration) -> Self {
+        self.checked_add(rhs)
+            .expect("Overflow when adding a duration to an instant.")
+    }
+}
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+impl Sub<Duration> for Instant {
+    type Output = Self;
+
+    fn sub(self, rhs: Duration) -> Self {
+        self.checked_sub(rhs)
+            .expect("Overflow when subtracting a duration from an instant.")
+    }
+}
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Blocks the calling process for at least `duration`.
+///
+/// The kernel wakes sleeping processes on its timer ticks, so the process may sleep up to a tick
+/// longer.
+pub fn sleep(duration: Duration) {
+    syscalls::sleep(duration);
+}
+
+/// Blocks the calling process until `deadline`.
+pub fn sleep_until(deadline: Instant) {
+    syscalls::sleep_until(deadline.0);
+}
@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! The thread-local storage.
+//!
+//! The kernel allocates the thread-local storage of each process from the `PT_TLS` segment of the
+//! binary and sets the FS base to its thread pointer, so `#[thread_local]` statics work.
+
+use core::arch::asm;
+
+/// Declares a thread-local static.
+///
+/// The static is placed in the thread-local storage. Unlike the one of `std`, the value is
+/// accessed directly.
+///
+/// ```ignore
+/// ralib::thread_local! {
+///     static COUNTER: core::cell::Cell<u32> = core::cell::Cell::new(0);
+/// }
+///
+/// COUNTER.set(COUNTER.get() + 1);
+/// ```
+#[macro_export]
+#[allow_internal_unstable(thread_local)]
+macro_rules! thread_local {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr;) => {
+        $(#[$attr])*
+        #[thread_local]
+        $vis static $name: $t = $init;
+    };
+}
+
+/// Returns the thread pointer of the current thread.
+///
+/// The thread pointer points to the end of the thread-local storage, where the first word of the
+/// thread control block holds the thread pointer itself.
+#[must_use]
+pub fn thread_pointer() -> *mut u8 {
+    let p: *mut u8;
+
+    // SAFETY: The kernel always allocates the thread control block, even if the binary does not
+    // have the thread-local storage.
+    unsafe {
+        asm!("mov {}, fs:0", out(reg) p, options(nostack, readonly, preserves_flags));
+    }
+
+    p
+}
@@ -0,0 +1,435 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+use {
+    alloc::collections::{BTreeMap, BTreeSet},
+    core::convert::TryFrom,
+    os_units::NumOfPages,
+    x86_64::{
+        structures::paging::{PageSize, Size4KiB},
+        VirtAddr,
+    },
+};
+
+/// An allocator of page-aligned virtual address ranges.
+///
+/// The free ranges are indexed both by their start addresses and by their sizes, so allocation
+/// and deallocation take `O(log n)` time where `n` is the number of free ranges.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct RangeAllocator {
+    by_start: BTreeMap<VirtAddr, NumOfPages<Size4KiB>>,
+    by_size: BTreeSet<(NumOfPages<Size4KiB>, VirtAddr)>,
+}
+impl RangeAllocator {
+    /// Creates an allocator without any free ranges.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            by_start: BTreeMap::new(),
+            by_size: BTreeSet::new(),
+        }
+    }
+
+    /// Creates an allocator whose free range is `num_of_pages` pages from `start`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `start` is not page-aligned.
+    #[must_use]
+    pub fn with_range(start: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) -> Self {
+        let mut a = Self::new();
+
+        let r = a.deallocate(start, num_of_pages);
+        r.expect("The range is not page-aligned.");
+
+        a
+    }
+
+    /// Allocates `num_of_pages` pages, and returns the start address.
+    ///
+    /// This method chooses the smallest free range which is large enough, and returns the lowest
+    /// part of it. It returns [`None`] if `num_of_pages` is 0 or there is no such range.
+    pub fn allocate(&mut self, num_of_pages: NumOfPages<Size4KiB>) -> Option<VirtAddr> {
+        if num_of_pages.as_usize() == 0 {
+            return None;
+        }
+
+        let (_, start) = *self
+            .by_size
+            .range((num_of_pages, VirtAddr::zero())..)
+            .next()?;
+
+        self.allocate_at(start, num_of_pages)
+            .unwrap_or_else(|_| unreachable!("The range is free."));
+
+        Some(start)
+    }
+
+    /// Allocates `num_of_pages` pages whose start address is aligned to `alignment` bytes.
+    ///
+    /// This method chooses the smallest free range which can hold the aligned pages. It returns
+    /// [`None`] if `num_of_pages` is 0, `alignment` is not a power of two, or there is no such
+    /// range.
+    pub fn allocate_aligned(
+        &mut self,
+        num_of_pages: NumOfPages<Size4KiB>,
+        alignment: u64,
+    ) -> Option<VirtAddr> {
+        if num_of_pages.as_usize() == 0 || !alignment.is_power_of_two() {
+            return None;
+        }
+
+        let bytes = u64::try_from(num_of_pages.as_bytes().as_usize()).ok()?;
+
+        let start = self
+            .by_size
+            .range((num_of_pages, VirtAddr::zero())..)
+            .find_map(|(pages, start)| {
+                let aligned = checked_align_up(*start, alignment)?;
+                let end = aligned.as_u64().checked_add(bytes)?;
+
+                (end <= end_of(*start, *pages).as_u64()).then(|| aligned)
+            })?;
+
+        self.allocate_at(start, num_of_pages)
+            .unwrap_or_else(|_| unreachable!("The range is free."));
+
+        Some(start)
+    }
+
+    /// Allocates `num_of_pages` pages at one of the page-aligned positions where they fit, and
+    /// returns the start address.
+    ///
+    /// `random` chooses the position, so each position is chosen with almost the same probability
+    /// if `random` is uniformly distributed. This method returns [`None`] if `num_of_pages` is 0 or
+    /// there is no such position.
+    pub fn allocate_randomly(
+        &mut self,
+        num_of_pages: NumOfPages<Size4KiB>,
+        random: u64,
+    ) -> Option<VirtAddr> {
+        if num_of_pages.as_usize() == 0 {
+            return None;
+        }
+
+        let positions = |pages: NumOfPages<Size4KiB>| {
+            u64::try_from(pages.as_usize().checked_sub(num_of_pages.as_usize())? + 1).ok()
+        };
+
+        let total: u64 = self.by_start.values().filter_map(|p| positions(*p)).sum();
+
+        let mut n = random.checked_rem(total)?;
+
+        let start = self.by_start.iter().find_map(|(start, pages)| {
+            let p = positions(*pages)?;
+
+            if n < p {
+                Some(*start + n * Size4KiB::SIZE)
+            } else {
+                n -= p;
+                None
+            }
+        })?;
+
+        self.allocate_at(start, num_of_pages)
+            .unwrap_or_else(|_| unreachable!("The range is free."));
+
+        Some(start)
+    }
+
+    /// Allocates `num_of_pages` pages from `start`.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if `start` is not page-aligned or any of the pages are not
+    /// free.
+    pub fn allocate_at(
+        &mut self,
+        start: VirtAddr,
+        num_of_pages: NumOfPages<Size4KiB>,
+    ) -> Result<(), Error> {
+        if !start.is_aligned(Size4KiB::SIZE) {
+            return Err(Error::NotAligned(start));
+        }
+
+        let (free_start, free_pages) = self.free_range_containing(start).ok_or(Error::NotFree)?;
+
+        let end = end_of(start, num_of_pages);
+        let free_end = end_of(free_start, free_pages);
+
+        if end > free_end {
+            return Err(Error::NotFree);
+        }
+
+        self.remove(free_start, free_pages);
+
+        if free_start < start {
+            self.insert(free_start, pages_between(free_start, start));
+        }
+
+        if end < free_end {
+            self.insert(end, pages_between(end, free_end));
+        }
+
+        Ok(())
+    }
+
+    /// Returns `num_of_pages` pages from `start` to the free ranges.
+    ///
+    /// The range is merged with the adjacent free ranges.
+    ///
+    /// # Errors
+    ///
+    /// This method returns an error if `start` is not page-aligned or any of the pages are
+    /// already free.
+    pub fn deallocate(
+        &mut self,
+        start: VirtAddr,
+        num_of_pages: NumOfPages<Size4KiB>,
+    ) -> Result<(), Error> {
+        if !start.is_aligned(Size4KiB::SIZE) {
+            return Err(Error::NotAligned(start));
+        }
+
+        if num_of_pages.as_usize() == 0 {
+            return Ok(());
+        }
+
+        let end = end_of(start, num_of_pages);
+
+        if self.overlaps(start, end) {
+            return Err(Error::DoubleFree(start));
+        }
+
+        self.merge_and_insert(start, end);
+
+        Ok(())
+    }
+
+    /// Returns the total number of free pages.
+    #[must_use]
+    pub fn free_pages(&self) -> NumOfPages<Size4KiB> {
+        self.by_start
+            .values()
+            .fold(NumOfPages::new(0), |acc, n| acc + *n)
+    }
+
+    fn merge_and_insert(&mut self, mut start: VirtAddr, mut end: VirtAddr) {
+        if let Some((prev_start, prev_pages)) = self.free_range_before(start) {
+            if end_of(prev_start, prev_pages) == start {
+                self.remove(prev_start, prev_pages);
+                start = prev_start;
+            }
+        }
+
+        if let Some(next_pages) = self.by_start.get(&end).copied() {
+            self.remove(end, next_pages);
+            end = end_of(end, next_pages);
+        }
+
+        self.insert(start, pages_between(start, end));
+    }
+
+    fn free_range_containing(&self, addr: VirtAddr) -> Option<(VirtAddr, NumOfPages<Size4KiB>)> {
+        let (start, pages) = self.by_start.range(..=addr).next_back()?;
+
+        (addr < end_of(*start, *pages)).then(|| (*start, *pages))
+    }
+
+    fn free_range_before(&self, addr: VirtAddr) -> Option<(VirtAddr, NumOfPages<Size4KiB>)> {
+        self.by_start
+            .range(..addr)
+            .next_back()
+            .map(|(start, pages)| (*start, *pages))
+    }
+
+    fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        let overlaps_previous = self
+            .free_range_before(end)
+            .map_or(false, |(s, n)| end_of(s, n) > start);
+
+        overlaps_previous || self.by_start.contains_key(&start)
+    }
+
+    fn insert(&mut self, start: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
+        self.by_start.insert(start, num_of_pages);
+        self.by_size.insert((num_of_pages, start));
+    }
+
+    fn remove(&mut self, start: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) {
+        self.by_start.remove(&start);
+        self.by_size.remove(&(num_of_pages, start));
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    NotAligned(VirtAddr),
+    /// Some of the pages are not free.
+    NotFree,
+    /// Some of the pages are already free.
+    DoubleFree(VirtAddr),
+}
+
+fn end_of(start: VirtAddr, num_of_pages: NumOfPages<Size4KiB>) -> VirtAddr {
+    start + num_of_pages.as_bytes().as_usize()
+}
+
+/// Returns `addr` aligned upwards to `alignment`, or [`None`] if the result overflows or is not
+/// canonical.
+fn checked_align_up(addr: VirtAddr, alignment: u64) -> Option<VirtAddr> {
+    let mask = alignment - 1;
+    let aligned = addr.as_u64().checked_add(mask)? & !mask;
+
+    VirtAddr::try_new(aligned).ok()
+}
+
+fn pages_between(start: VirtAddr, end: VirtAddr) -> NumOfPages<Size4KiB> {
+    let bytes = usize::try_from(end - start).unwrap();
+
+    NumOfPages::new(bytes / usize::try_from(Size4KiB::SIZE).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::{Error, RangeAllocator},
+        os_units::NumOfPages,
+        x86_64::VirtAddr,
+    };
+
+    fn addr(a: u64) -> VirtAddr {
+        VirtAddr::new(a)
+    }
+
+    fn pages(n: usize) -> NumOfPages<x86_64::structures::paging::Size4KiB> {
+        NumOfPages::new(n)
+    }
+
+    #[test]
+    fn allocate_from_lowest_address() {
+        let mut a = RangeAllocator::with_range(addr(0x1000), pages(16));
+
+        assert_eq!(a.allocate(pages(2)), Some(addr(0x1000)));
+        assert_eq!(a.allocate(pages(2)), Some(addr(0x3000)));
+        assert_eq!(a.free_pages(), pages(12));
+    }
+
+    #[test]
+    fn fail_to_allocate() {
+        let mut a = RangeAllocator::with_range(addr(0x1000), pages(4));
+
+        assert_eq!(a.allocate(pages(5)), None);
+        assert_eq!(a.allocate(pages(0)), None);
+        assert_eq!(a.free_pages(), pages(4));
+    }
+
+    #[test]
+    fn allocate_from_smallest_fitting_range() {
+        let mut a = RangeAllocator::with_range(addr(0x1000), pages(8));
+        a.deallocate(addr(0x10000), pages(2)).unwrap();
+
+        assert_eq!(a.allocate(pages(2)), Some(addr(0x10000)));
+        assert_eq!(a.allocate(pages(2)), Some(addr(0x1000)));
+    }
+
+    #[test]
+    fn allocate_aligned() {
+        let mut a = RangeAllocator::with_range(addr(0x1000), pages(0x3ff));
+
+        assert_eq!(
+            a.allocate_aligned(pages(2), 0x20_0000),
+            Some(addr(0x20_0000))
+        );
+        assert_eq!(a.allocate_aligned(pages(1), 0x20_0000), None);
+        assert_eq!(a.allocate_aligned(pages(1), 0x3000), None);
+        assert_eq!(a.allocate(pages(0x1ff)), Some(addr(0x1000)));
+        assert_eq!(a.free_pages(), pages(0x1fe));
+    }
+
+    #[test]
+    fn skip_range_whose_aligned_start_overflows() {
+        let mut a = RangeAllocator::with_range(addr(0xffff_ffff_fffe_0000), pages(0x1f));
+
+        assert_eq!(a.allocate_aligned(pages(1), 0x20_0000), None);
+
+        a.deallocate(addr(0x20_0000), pages(0x200)).unwrap();
+
+        assert_eq!(
+            a.allocate_aligned(pages(1), 0x20_0000),
+            Some(addr(0x20_0000))
+        );
+    }
+
+    #[test]
+    fn allocate_randomly() {
+        let mut a = RangeAllocator::with_range(addr(0x1000), pages(4));
+        a.deallocate(addr(0x10000), pages(2)).unwrap();
+
+        assert_eq!(a.clone().allocate_randomly(pages(2), 0), Some(addr(0x1000)));
+        assert_eq!(a.clone().allocate_randomly(pages(2), 2), Some(addr(0x3000)));
+        assert_eq!(
+            a.clone().allocate_randomly(pages(2), 3),
+            Some(addr(0x10000))
+        );
+        assert_eq!(a.clone().allocate_randomly(pages(2), 5), Some(addr(0x2000)));
+        assert_eq!(a.allocate_randomly(pages(5), 0), None);
+        assert_eq!(a.allocate_randomly(pages(0), 0), None);
+
+        assert_eq!(a.allocate_randomly(pages(2), 1), Some(addr(0x2000)));
+        assert_eq!(a.free_pages(), pages(4));
+        assert_eq!(a.allocate_randomly(pages(2), 0), Some(addr(0x10000)));
+        assert_eq!(a.allocate_randomly(pages(2), 0), None);
+    }
+
+    #[test]
+    fn allocate_at() {
+        let mut a = RangeAllocator::with_range(addr(0x1000), pages(8));
+
+        assert_eq!(a.allocate_at(addr(0x3000), pages(2)), Ok(()));
+        assert_eq!(a.allocate_at(addr(0x4000), pages(1)), Err(Error::NotFree));
+        assert_eq!(a.allocate_at(addr(0x8000), pages(2)), Err(Error::NotFree));
+        assert_eq!(
+            a.allocate_at(addr(0x5800), pages(1)),
+            Err(Error::NotAligned(addr(0x5800)))
+        );
+
+        assert_eq!(a.allocate(pages(3)), Some(addr(0x5000)));
+        assert_eq!(a.allocate(pages(2)), Some(addr(0x1000)));
+        assert_eq!(a.allocate(pages(1)), Some(addr(0x8000)));
+        assert_eq!(a.allocate(pages(1)), None);
+    }
+
+    #[test]
+    fn merge_freed_ranges() {
+        let mut a = RangeAllocator::with_range(addr(0x1000), pages(3));
+
+        let x = a.allocate(pages(1)).unwrap();
+        let y = a.allocate(pages(1)).unwrap();
+        let z = a.allocate(pages(1)).unwrap();
+
+        a.deallocate(x, pages(1)).unwrap();
+        a.deallocate(z, pages(1)).unwrap();
+        a.deallocate(y, pages(1)).unwrap();
+
+        assert_eq!(a, RangeAllocator::with_range(addr(0x1000), pages(3)));
+    }
+
+    #[test]
+    fn detect_double_free() {
+        let mut a = RangeAllocator::with_range(addr(0x1000), pages(4));
+
+        let x = a.allocate(pages(2)).unwrap();
+        a.deallocate(x, pages(2)).unwrap();
+
+        assert_eq!(a.deallocate(x, pages(2)), Err(Error::DoubleFree(x)));
+        assert_eq!(
+            a.deallocate(addr(0x4000), pages(2)),
+            Err(Error::DoubleFree(addr(0x4000)))
+        );
+        assert_eq!(a.free_pages(), pages(4));
+    }
+}
@@ -6,9 +6,16 @@
 #![feature(naked_functions)]
 
 use {
-    core::{arch::asm, convert::TryInto, ffi::c_void, panic::PanicInfo},
+    core::{
+        arch::asm,
+        convert::{TryFrom, TryInto},
+        ffi::c_void,
+        panic::PanicInfo,
+        time::Duration,
+    },
     message::Message,
     num_derive::FromPrimitive,
+    num_traits::FromPrimitive,
     os_units::{Bytes, NumOfPages},
     x86_64::{structures::paging::Size4KiB, PhysAddr, VirtAddr},
 };
@@ -88,9 +95,13 @@ pub fn allocate_pages(pages: NumOfPages<Size4KiB>) -> VirtAddr {
     ))
 }
 
-pub fn deallocate_pages(virt: VirtAddr, pages: NumOfPages<Size4KiB>) {
+/// # Errors
+///
+/// This function returns an error if `virt` and `pages` do not match the memory allocated by
+/// [`allocate_pages`] or [`allocate_dma`].
+pub fn deallocate_pages(virt: VirtAddr, pages: NumOfPages<Size4KiB>) -> Result<(), MemoryError> {
     // SAFETY: This operation is safe as the all arguments are propertly passed.
-    general_syscall(
+    let status = general_syscall(
         Ty::DeallocatePages,
         virt.as_u64(),
         pages
@@ -99,10 +110,14 @@ pub fn deallocate_pages(virt: VirtAddr, pages: NumOfPages<Size4KiB>) {
             .unwrap_or_else(|_| unreachable!("On x86_64 architecture, `u64` == `usize`.")),
         0,
     );
+
+    status_to_result(status)
 }
 
+/// Maps the physical memory `start..start+bytes` to the address space of the calling process
+/// with the memory type `cache`.
 #[must_use]
-pub fn map_pages(start: PhysAddr, bytes: Bytes) -> VirtAddr {
+pub fn map_pages(start: PhysAddr, bytes: Bytes, cache: CacheType) -> VirtAddr {
     // SAFETY: This operation is safe as the all arguments are propertly passed.
     VirtAddr::new(general_syscall(
         Ty::MapPages,
@@ -111,12 +126,16 @@ pub fn map_pages(start: PhysAddr, bytes: Bytes) -> VirtAddr {
             .as_usize()
             .try_into()
             .unwrap_or_else(|_| unreachable!("On x86_64 architecture, `u64` == `usize`.")),
-        0,
+        cache as u64,
     ))
 }
 
-pub fn unmap_pages(start: VirtAddr, bytes: Bytes) {
-    general_syscall(
+/// # Errors
+///
+/// This function returns an error if `start` and `bytes` do not match the memory mapped by
+/// [`map_pages`] or [`map_framebuffer`].
+pub fn unmap_pages(start: VirtAddr, bytes: Bytes) -> Result<(), MemoryError> {
+    let status = general_syscall(
         Ty::UnmapPages,
         start.as_u64(),
         bytes
@@ -125,6 +144,72 @@ pub fn unmap_pages(start: VirtAddr, bytes: Bytes) {
             .unwrap_or_else(|_| unreachable!("On x86_64 architecture, `usize` == `u64`.")),
         0,
     );
+
+    status_to_result(status)
+}
+
+/// Allocates physically contiguous memory for DMA.
+///
+/// This function returns the virtual and physical addresses of the allocated memory, or [`None`]
+/// if there is no memory satisfying `constraints`.
+#[must_use]
+pub fn allocate_dma(bytes: Bytes, constraints: DmaConstraints) -> Option<(VirtAddr, PhysAddr)> {
+    let mut phys = 0_u64;
+
+    let constraints: *const DmaConstraints = &constraints;
+    let phys_ptr: *mut u64 = &mut phys;
+
+    let virt = general_syscall(
+        Ty::AllocateDma,
+        bytes
+            .as_usize()
+            .try_into()
+            .unwrap_or_else(|_| unreachable!("On x86_64 architecture, `u64` == `usize`.")),
+        constraints as _,
+        phys_ptr as _,
+    );
+
+    if virt == 0 {
+        None
+    } else {
+        Some((VirtAddr::new(virt), PhysAddr::new(phys)))
+    }
+}
+
+/// Maps the framebuffer to the address space of the calling process.
+///
+/// The framebuffer is not accessible from user processes unless they call this function.
+#[must_use]
+pub fn map_framebuffer() -> VirtAddr {
+    VirtAddr::new(general_syscall(Ty::MapFramebuffer, 0, 0, 0))
+}
+
+/// Creates a copy of the calling process.
+///
+/// The memory is shared between both processes until either of them writes to it. This function
+/// returns the PID of the child process to the parent, and 0 to the child.
+#[must_use]
+pub fn fork() -> i32 {
+    general_syscall(Ty::Fork, 0, 0, 0).try_into().unwrap()
+}
+
+/// Returns the time elapsed since the kernel started its monotonic clock.
+#[must_use]
+pub fn monotonic_time() -> Duration {
+    Duration::from_nanos(general_syscall(Ty::MonotonicTime, 0, 0, 0))
+}
+
+/// Blocks the calling process for at least `duration`.
+///
+/// The process wakes on a timer tick of the kernel, so it may sleep a tick longer than
+/// `duration`.
+pub fn sleep(duration: Duration) {
+    general_syscall(Ty::Sleep, duration_to_nanoseconds(duration), 0, 0);
+}
+
+/// Blocks the calling process until [`monotonic_time`] reaches `deadline`.
+pub fn sleep_until(deadline: Duration) {
+    general_syscall(Ty::SleepUntil, duration_to_nanoseconds(deadline), 0, 0);
 }
 
 #[must_use]
@@ -208,6 +293,19 @@ pub fn panic(info: &PanicInfo<'_>) -> ! {
     unreachable!("The `panic` system call should not return.");
 }
 
+fn status_to_result(status: u64) -> Result<(), MemoryError> {
+    if status == 0 {
+        Ok(())
+    } else {
+        let e = FromPrimitive::from_u64(status);
+        Err(e.expect("Unknown error code."))
+    }
+}
+
+fn duration_to_nanoseconds(d: Duration) -> u64 {
+    u64::try_from(d.as_nanos()).unwrap_or(u64::MAX)
+}
+
 fn receive_ack(from: i32) {
     let _ = receive_from(from);
 }
@@ -230,6 +328,56 @@ pub enum Ty {
     ReceiveFromAny,
     ReceiveFrom,
     Panic,
+    MapFramebuffer,
+    AllocateDma,
+    Fork,
+    Sleep,
+    SleepUntil,
+    MonotonicTime,
+}
+
+/// Errors of the system calls which free or unmap memory.
+#[derive(Copy, Clone, FromPrimitive, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum MemoryError {
+    /// The calling process does not have memory starting from the address.
+    NotOwned = 1,
+    /// The size or the kind of the memory does not match the request.
+    Mismatch,
+}
+
+/// Constraints on the memory allocated by [`allocate_dma`].
+///
+/// The allocated memory is always physically contiguous.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DmaConstraints {
+    /// The alignment of the physical address in bytes. This must be a power of two.
+    pub alignment: u64,
+    /// If this is `true`, the whole memory is located below 4 GiB so that devices without 64-bit
+    /// addressing can access it.
+    pub below_4gib: bool,
+    pub cache: CacheType,
+}
+impl Default for DmaConstraints {
+    fn default() -> Self {
+        Self {
+            alignment: 0x1000,
+            below_4gib: false,
+            cache: CacheType::WriteBack,
+        }
+    }
+}
+
+/// The memory type of mapped pages.
+#[repr(u8)]
+#[derive(Copy, Clone, FromPrimitive, Debug, PartialEq, Eq)]
+pub enum CacheType {
+    WriteBack,
+    /// For device registers. Every access reaches the device in the program order.
+    Uncacheable,
+    /// For framebuffers. Writes are buffered and combined, and reads are not cached.
+    WriteCombining,
 }
 
 #[naked]
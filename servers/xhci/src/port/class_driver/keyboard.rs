@@ -7,7 +7,7 @@ use {
     },
     alloc::{string::String, vec::Vec},
     log::info,
-    page_box::PageBox,
+    page_box::DmaBox,
     spinning_top::Spinlock,
     xhci::context::EndpointType,
 };
@@ -32,7 +32,7 @@ pub(in crate::port) async fn task(eps: FullyOperational) {
 
 pub(crate) struct Keyboard {
     ep: FullyOperational,
-    buf: PageBox<[u8; 8]>,
+    buf: DmaBox<[u8; 8]>,
 }
 impl Keyboard {
     pub(in crate::port) fn new(ep: FullyOperational) -> Self {
@@ -9,7 +9,7 @@ use {
     },
     alloc::vec::Vec,
     log::info,
-    page_box::PageBox,
+    page_box::DmaBox,
     scsi::{
         command_data_block,
         response::{Inquiry, Read10, ReadCapacity10},
@@ -75,9 +75,9 @@ impl MassStorage {
             .build()
             .expect("Failed to build an inquiry command block wrapper.");
         let data = command_data_block::Inquiry::new(LEN);
-        let mut wrapper = PageBox::from(CommandBlockWrapper::new(header, data.into()));
+        let mut wrapper = DmaBox::from(CommandBlockWrapper::new(header, data.into()));
 
-        let (response, status): (PageBox<Inquiry>, _) = self.send_scsi_command(&mut wrapper).await;
+        let (response, status): (DmaBox<Inquiry>, _) = self.send_scsi_command(&mut wrapper).await;
 
         status.check_corruption();
         *response
@@ -92,16 +92,16 @@ impl MassStorage {
             .build()
             .expect("Failed to build a read capacity command block wrapper");
         let data = command_data_block::ReadCapacity::default();
-        let mut wrapper = PageBox::from(CommandBlockWrapper::new(header, data.into()));
+        let mut wrapper = DmaBox::from(CommandBlockWrapper::new(header, data.into()));
 
-        let (response, status): (PageBox<ReadCapacity10>, _) =
+        let (response, status): (DmaBox<ReadCapacity10>, _) =
             self.send_scsi_command(&mut wrapper).await;
 
         status.check_corruption();
         *response
     }
 
-    async fn read10(&mut self) -> PageBox<Read10> {
+    async fn read10(&mut self) -> DmaBox<Read10> {
         let header = CommandBlockWrapperHeaderBuilder::default()
             .transfer_length(0x8000)
             .flags(scsi::Flags::In)
@@ -110,9 +110,9 @@ impl MassStorage {
             .build()
             .expect("Failed to build a read 10 command block wrapper.");
         let data = command_data_block::Read10::new(0, 64);
-        let mut wrapper = PageBox::from(CommandBlockWrapper::new(header, data.into()));
+        let mut wrapper = DmaBox::from(CommandBlockWrapper::new(header, data.into()));
 
-        let (response, status): (PageBox<Read10>, _) = self.send_scsi_command(&mut wrapper).await;
+        let (response, status): (DmaBox<Read10>, _) = self.send_scsi_command(&mut wrapper).await;
 
         status.check_corruption();
         response
@@ -127,9 +127,9 @@ impl MassStorage {
             .build()
             .expect("Failed to build a write 10 command block wrapper.");
         let data = command_data_block::Write10::new(0, 64);
-        let mut wrapper = PageBox::from(CommandBlockWrapper::new(header, data.into()));
+        let mut wrapper = DmaBox::from(CommandBlockWrapper::new(header, data.into()));
 
-        let content = PageBox::from(0x334_usize);
+        let content = DmaBox::from(0x334_usize);
 
         let status = self.send_scsi_command_for_out(&mut wrapper, &content).await;
         status.check_corruption();
@@ -137,8 +137,8 @@ impl MassStorage {
 
     async fn send_scsi_command<T>(
         &mut self,
-        c: &mut PageBox<CommandBlockWrapper>,
-    ) -> (PageBox<T>, PageBox<CommandStatusWrapper>)
+        c: &mut DmaBox<CommandBlockWrapper>,
+    ) -> (DmaBox<T>, DmaBox<CommandStatusWrapper>)
     where
         T: Default,
     {
@@ -150,26 +150,26 @@ impl MassStorage {
 
     async fn send_scsi_command_for_out(
         &mut self,
-        c: &mut PageBox<CommandBlockWrapper>,
-        d: &PageBox<impl ?Sized>,
-    ) -> PageBox<CommandStatusWrapper> {
+        c: &mut DmaBox<CommandBlockWrapper>,
+        d: &DmaBox<impl ?Sized>,
+    ) -> DmaBox<CommandStatusWrapper> {
         self.send_command_block_wrapper(c).await;
         self.send_additional_data(d).await;
         self.receive_command_status().await
     }
 
-    async fn send_command_block_wrapper(&mut self, c: &mut PageBox<CommandBlockWrapper>) {
+    async fn send_command_block_wrapper(&mut self, c: &mut DmaBox<CommandBlockWrapper>) {
         self.ep
             .issue_normal_trb(c, EndpointType::BulkOut)
             .await
             .expect("Failed to send a SCSI command.");
     }
 
-    async fn receive_command_response<T>(&mut self) -> PageBox<T>
+    async fn receive_command_response<T>(&mut self) -> DmaBox<T>
     where
         T: Default,
     {
-        let c = PageBox::default();
+        let c = DmaBox::default();
         self.ep
             .issue_normal_trb(&c, EndpointType::BulkIn)
             .await
@@ -177,15 +177,15 @@ impl MassStorage {
         c
     }
 
-    async fn send_additional_data(&mut self, d: &PageBox<impl ?Sized>) {
+    async fn send_additional_data(&mut self, d: &DmaBox<impl ?Sized>) {
         self.ep
             .issue_normal_trb(d, EndpointType::BulkOut)
             .await
             .expect("Failed to send a data.");
     }
 
-    async fn receive_command_status(&mut self) -> PageBox<CommandStatusWrapper> {
-        let b = PageBox::default();
+    async fn receive_command_status(&mut self) -> DmaBox<CommandStatusWrapper> {
+        let b = DmaBox::default();
         self.ep
             .issue_normal_trb(&b, EndpointType::BulkIn)
             .await
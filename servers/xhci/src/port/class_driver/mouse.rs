@@ -7,7 +7,7 @@ use {
     },
     alloc::vec::Vec,
     log::info,
-    page_box::PageBox,
+    page_box::DmaBox,
     xhci::context::EndpointType,
 };
 
@@ -31,7 +31,7 @@ pub(in super::super) async fn task(eps: FullyOperational) {
 
 pub(crate) struct Mouse {
     ep: FullyOperational,
-    buf: PageBox<[i8; 4]>,
+    buf: DmaBox<[i8; 4]>,
 }
 impl Mouse {
     pub(super) fn new(ep: FullyOperational) -> Self {
@@ -12,7 +12,7 @@ use {
     alloc::vec::Vec,
     core::slice,
     log::debug,
-    page_box::PageBox,
+    page_box::DmaBox,
     xhci::context::EndpointType,
 };
 
@@ -47,7 +47,7 @@ impl FullyOperational {
 
     pub(in super::super) async fn issue_normal_trb(
         &mut self,
-        b: &PageBox<impl ?Sized>,
+        b: &DmaBox<impl ?Sized>,
         ty: EndpointType,
     ) -> Result<(), Error> {
         for ep in &mut self.eps {
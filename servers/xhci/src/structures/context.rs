@@ -2,7 +2,7 @@
 
 use {
     super::registers,
-    page_box::PageBox,
+    page_box::DmaBox,
     x86_64::PhysAddr,
     xhci::context::{
         Device32Byte, Device64Byte, DeviceHandler, Input32Byte, Input64Byte, InputControlHandler,
@@ -12,7 +12,7 @@ use {
 
 pub(crate) struct Context {
     pub(crate) input: Input,
-    pub(crate) output: PageBox<Device>,
+    pub(crate) output: DmaBox<Device>,
 }
 impl Default for Context {
     fn default() -> Self {
@@ -24,8 +24,8 @@ impl Default for Context {
 }
 
 pub(crate) enum Input {
-    Byte64(PageBox<Input64Byte>),
-    Byte32(PageBox<Input32Byte>),
+    Byte64(DmaBox<Input64Byte>),
+    Byte32(DmaBox<Input32Byte>),
 }
 impl Input {
     pub(crate) fn control_mut(&mut self) -> &mut dyn InputControlHandler {
@@ -60,8 +60,8 @@ impl Default for Input {
 }
 
 pub(crate) enum Device {
-    Byte64(PageBox<Device64Byte>),
-    Byte32(PageBox<Device32Byte>),
+    Byte64(DmaBox<Device64Byte>),
+    Byte32(DmaBox<Device32Byte>),
 }
 impl Default for Device {
     fn default() -> Self {
@@ -4,7 +4,7 @@ use {
     super::registers,
     conquer_once::spin::Lazy,
     core::ops::{Index, IndexMut},
-    page_box::PageBox,
+    page_box::DmaBox,
     spinning_top::Spinlock,
     x86_64::PhysAddr,
 };
@@ -21,11 +21,11 @@ pub(crate) fn register(port_id: usize, a: PhysAddr) {
 }
 
 pub(crate) struct DeviceContextBaseAddressArray {
-    arr: PageBox<[PhysAddr]>,
+    arr: DmaBox<[PhysAddr]>,
 }
 impl DeviceContextBaseAddressArray {
     fn new() -> Self {
-        let arr = PageBox::new_slice(PhysAddr::zero(), Self::num_of_slots());
+        let arr = DmaBox::new_slice(PhysAddr::zero(), Self::num_of_slots());
         Self { arr }
     }
 
@@ -12,7 +12,7 @@ use {
     },
     futures_util::{stream::Stream, StreamExt},
     log::{debug, info, warn},
-    page_box::PageBox,
+    page_box::DmaBox,
     segment_table::SegmentTable,
     x86_64::{
         structures::paging::{PageSize, Size4KiB},
@@ -101,7 +101,7 @@ impl Stream for Ring {
 }
 
 struct Raw {
-    rings: Vec<PageBox<[[u32; 4]]>>,
+    rings: Vec<DmaBox<[[u32; 4]]>>,
     c: CycleBit,
     deq_p_seg: usize,
     deq_p_trb: usize,
@@ -117,10 +117,10 @@ impl Raw {
         }
     }
 
-    fn new_rings() -> Vec<PageBox<[[u32; 4]]>> {
+    fn new_rings() -> Vec<DmaBox<[[u32; 4]]>> {
         let mut v = Vec::new();
         for _ in 0..Self::max_num_of_erst() {
-            v.push(PageBox::new_slice([0; 4], MAX_NUM_OF_TRB_IN_QUEUE.into()));
+            v.push(DmaBox::new_slice([0; 4], MAX_NUM_OF_TRB_IN_QUEUE.into()));
         }
 
         v
@@ -201,7 +201,7 @@ impl Raw {
     }
 
     fn head_addrs(&self) -> Vec<PhysAddr> {
-        self.rings.iter().map(PageBox::phys_addr).collect()
+        self.rings.iter().map(DmaBox::phys_addr).collect()
     }
 }
 
@@ -5,15 +5,15 @@ use {
         ops::{Index, IndexMut},
         slice,
     },
-    page_box::PageBox,
+    page_box::DmaBox,
     x86_64::PhysAddr,
 };
 
 #[derive(Debug)]
-pub struct SegmentTable(PageBox<[Entry]>);
+pub struct SegmentTable(DmaBox<[Entry]>);
 impl SegmentTable {
     pub fn new(len: usize) -> Self {
-        Self(PageBox::new_slice(Entry::null(), len))
+        Self(DmaBox::new_slice(Entry::null(), len))
     }
 
     pub fn phys_addr(&self) -> PhysAddr {
@@ -3,7 +3,7 @@
 use {
     super::CycleBit,
     alloc::vec::Vec,
-    page_box::PageBox,
+    page_box::DmaBox,
     trb::Link,
     x86_64::PhysAddr,
     xhci::ring::{trb, trb::transfer},
@@ -29,14 +29,14 @@ impl Ring {
 }
 
 struct Raw {
-    ring: PageBox<[[u32; 4]]>,
+    ring: DmaBox<[[u32; 4]]>,
     enq_p: usize,
     c: CycleBit,
 }
 impl Raw {
     fn new() -> Self {
         Self {
-            ring: PageBox::new_slice([0; 4], SIZE_OF_RING),
+            ring: DmaBox::new_slice([0; 4], SIZE_OF_RING),
             enq_p: 0,
             c: CycleBit::new(true),
         }
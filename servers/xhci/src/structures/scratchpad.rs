@@ -2,7 +2,7 @@
 
 use {
     super::dcbaa, crate::registers, alloc::vec::Vec, conquer_once::spin::OnceCell,
-    core::convert::TryInto, os_units::Bytes, page_box::PageBox, x86_64::PhysAddr,
+    core::convert::TryInto, os_units::Bytes, page_box::DmaBox, x86_64::PhysAddr,
 };
 
 static SCRATCHPAD: OnceCell<Scratchpad> = OnceCell::uninit();
@@ -22,15 +22,15 @@ fn init_static() {
 }
 
 struct Scratchpad {
-    arr: PageBox<[PhysAddr]>,
-    bufs: Vec<PageBox<[u8]>>,
+    arr: DmaBox<[PhysAddr]>,
+    bufs: Vec<DmaBox<[u8]>>,
 }
 impl Scratchpad {
     fn new() -> Self {
         let len: usize = Self::num_of_buffers().try_into().unwrap();
 
         Self {
-            arr: PageBox::new_slice(PhysAddr::zero(), len),
+            arr: DmaBox::new_slice(PhysAddr::zero(), len),
             bufs: Vec::new(),
         }
     }
@@ -52,7 +52,7 @@ impl Scratchpad {
         for _ in 0..Self::num_of_buffers() {
             // Allocate the double size of memory, then register the aligned address with the
             // array.
-            let b = PageBox::new_slice(0, Self::page_size().as_usize() * 2);
+            let b = DmaBox::new_slice(0, Self::page_size().as_usize() * 2);
             self.bufs.push(b);
         }
     }
@@ -2,9 +2,13 @@
 
 use {
     super::structures::{extended_capabilities, registers},
+    ralib::time::{self, Duration},
     xhci::extended_capabilities::ExtendedCapability,
 };
 
+/// The interval between the reads of a register while waiting for it to change.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
 pub(super) fn exists() -> bool {
     super::iter_xhc().next().is_some()
 }
@@ -70,7 +74,9 @@ fn stop() {
 }
 
 fn wait_until_halt() {
-    registers::handle(|r| while !r.operational.usbsts.read_volatile().hc_halted() {});
+    while !registers::handle(|r| r.operational.usbsts.read_volatile().hc_halted()) {
+        time::sleep(POLL_INTERVAL);
+    }
 }
 
 fn reset() {
@@ -88,19 +94,15 @@ fn start_resetting() {
 }
 
 fn wait_until_reset_completed() {
-    registers::handle(
-        |r| {
-            while r.operational.usbcmd.read_volatile().host_controller_reset() {}
-        },
-    );
+    while registers::handle(|r| r.operational.usbcmd.read_volatile().host_controller_reset()) {
+        time::sleep(POLL_INTERVAL);
+    }
 }
 
 fn wait_until_ready() {
-    registers::handle(
-        |r| {
-            while r.operational.usbsts.read_volatile().controller_not_ready() {}
-        },
-    );
+    while registers::handle(|r| r.operational.usbsts.read_volatile().controller_not_ready()) {
+        time::sleep(POLL_INTERVAL);
+    }
 }
 
 fn set_num_of_enabled_slots() {